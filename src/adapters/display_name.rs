@@ -1,5 +1,5 @@
 use crate::comms::{CommsMessage, CommsVerifier};
-use crate::manager::AccountStatus;
+use crate::manager::{AccountStatus, InvalidReason};
 use crate::primitives::{Account, AccountType, ChallengeStatus, NetAccount, Result};
 use crate::Database2;
 use strsim::jaro_winkler;
@@ -94,7 +94,7 @@ impl DisplayNameHandler {
                 .set_account_status(
                     &net_account,
                     &AccountType::DisplayName,
-                    &AccountStatus::Invalid,
+                    &AccountStatus::Invalid(Some(InvalidReason::DisplayNameViolation)),
                 )
                 .await?;
 
@@ -1,10 +1,15 @@
 use crate::comms::{CommsMessage, CommsVerifier};
 use crate::db::Database2;
-use crate::primitives::{Account, AccountType, NetAccount, Result};
+use crate::manager::{AccountStatus, InvalidReason};
+use crate::message::FormattedMessage;
+use crate::primitives::{Account, AccountType, Challenge, ChallengeStatus, NetAccount, Result};
+use crate::templates::{RenderedMessage, TemplateKind, Templates};
 use crate::verifier::{invalid_accounts_message, verification_handler, Verifier2};
 use lettre::smtp::authentication::Credentials;
+use lettre::smtp::error::Error as SmtpError;
+use lettre::smtp::response::Response;
 use lettre::smtp::SmtpClient;
-use lettre::Transport;
+use lettre::{SendableEmail, Transport};
 use lettre_email::EmailBuilder;
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use std::result::Result as StdResult;
@@ -77,6 +82,107 @@ pub struct ReceivedMessageContext {
     pub id: EmailId,
     pub sender: Account,
     pub body: String,
+    pub message_id: Option<String>,
+    /// The `References` header, or the `In-Reply-To` header if the former is
+    /// missing.
+    pub references: Option<String>,
+    pub subject: Option<String>,
+    /// Addresses reported as undeliverable if the message is a delivery
+    /// status notification (bounce).
+    pub failed_recipients: Vec<Account>,
+    /// The `Message-ID`s quoted in the bounce, used to check whether the
+    /// bounced message was actually sent by us.
+    pub bounced_message_ids: Vec<String>,
+}
+
+/// How long processed email IDs are kept track of, in seconds. Must exceed
+/// the time span of the IMAP search query in `request_messages`.
+const EMAIL_ID_RETENTION: u64 = 7 * 24 * 3600;
+/// How often old email IDs are pruned, in seconds.
+const PRUNE_INTERVAL: u64 = 3600;
+
+/// How long one-time verification codes are valid, in seconds. Identities
+/// time out after the same period.
+pub(crate) const EMAIL_CODE_EXPIRY: u64 = 3600;
+
+/// Subject used if the template does not specify one.
+const DEFAULT_SUBJECT: &str = "W3F Registrar Verification Service";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutgoingMessage {
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+    /// The `Message-ID` of the message this is a reply to.
+    pub in_reply_to: Option<String>,
+    /// The `Message-ID`s of the thread, ending with `in_reply_to`.
+    pub references: Option<String>,
+}
+
+impl OutgoingMessage {
+    /// Marks the message as a reply, so email clients display it in the same
+    /// thread as the received message.
+    pub fn reply_to(mut self, received: &ReceivedMessageContext) -> Self {
+        if let Some(subject) = &received.subject {
+            self.subject = if subject.to_lowercase().starts_with("re:") {
+                subject.clone()
+            } else {
+                format!("Re: {}", subject)
+            };
+        }
+
+        // The thread of the received message is continued (RFC 5322,
+        // section 3.6.4).
+        self.references =
+            received
+                .message_id
+                .as_ref()
+                .map(|message_id| match &received.references {
+                    Some(references) => format!("{} {}", references, message_id),
+                    None => message_id.clone(),
+                });
+
+        self.in_reply_to = received.message_id.clone();
+        self
+    }
+}
+
+impl From<String> for OutgoingMessage {
+    fn from(text: String) -> Self {
+        OutgoingMessage {
+            subject: DEFAULT_SUBJECT.to_string(),
+            text: text,
+            html: None,
+            in_reply_to: None,
+            references: None,
+        }
+    }
+}
+
+impl From<FormattedMessage> for OutgoingMessage {
+    fn from(message: FormattedMessage) -> Self {
+        OutgoingMessage {
+            subject: DEFAULT_SUBJECT.to_string(),
+            text: message.plain,
+            html: message.html,
+            in_reply_to: None,
+            references: None,
+        }
+    }
+}
+
+impl From<RenderedMessage> for OutgoingMessage {
+    fn from(rendered: RenderedMessage) -> Self {
+        OutgoingMessage {
+            subject: rendered
+                .subject
+                .unwrap_or_else(|| DEFAULT_SUBJECT.to_string()),
+            text: rendered.text,
+            html: rendered.html,
+            in_reply_to: None,
+            references: None,
+        }
+    }
 }
 
 #[derive(Debug, Fail)]
@@ -88,6 +194,8 @@ pub enum ClientError {
     #[fail(display = "No Email account found for user: {}", 0)]
     // TODO: Should be `NetAccount`
     NoEmailAccount(String),
+    #[fail(display = "the recipient {} was rejected: {}", _0, _1)]
+    RecipientRejected(Account, String),
 }
 
 pub struct SmtpImapClientBuilder {
@@ -96,6 +204,7 @@ pub struct SmtpImapClientBuilder {
     inbox: Option<String>,
     user: Option<String>,
     password: Option<String>,
+    message_id_domain: Option<String>,
 }
 
 impl SmtpImapClientBuilder {
@@ -106,6 +215,7 @@ impl SmtpImapClientBuilder {
             inbox: None,
             user: None,
             password: None,
+            message_id_domain: None,
         }
     }
     pub fn email_server(mut self, server: String) -> Self {
@@ -128,21 +238,40 @@ impl SmtpImapClientBuilder {
         self.password = Some(password);
         self
     }
+    /// Optional, defaults to the domain of the email user.
+    pub fn message_id_domain(mut self, domain: String) -> Self {
+        self.message_id_domain = Some(domain);
+        self
+    }
     pub fn build(self) -> Result<SmtpImapClient> {
+        let user_domain = self
+            .user
+            .as_ref()
+            .and_then(|user| user.split('@').nth(1))
+            .map(|domain| domain.to_string());
+
+        let message_id_domain = self
+            .message_id_domain
+            .or(user_domain)
+            .ok_or(ClientError::IncompleteBuilder)?;
+
         Ok(SmtpImapClient {
             smtp_server: self.server.ok_or(ClientError::IncompleteBuilder)?,
             imap_server: self.imap_server.ok_or(ClientError::IncompleteBuilder)?,
             inbox: self.inbox.ok_or(ClientError::IncompleteBuilder)?,
             user: self.user.ok_or(ClientError::IncompleteBuilder)?,
             password: self.password.ok_or(ClientError::IncompleteBuilder)?,
+            message_id_domain: message_id_domain,
         })
     }
 }
 
 #[async_trait]
 pub trait EmailTransport: 'static + Send + Sync {
-    async fn request_messages(&self) -> Result<Vec<ReceivedMessageContext>>;
-    async fn send_message(&self, account: &Account, msg: String) -> Result<()>;
+    /// Returns the recent messages and the UIDVALIDITY of the inbox.
+    async fn request_messages(&self) -> Result<(Vec<ReceivedMessageContext>, u32)>;
+    /// Returns the `Message-ID` of the sent message, without angle brackets.
+    async fn send_message(&self, account: &Account, msg: OutgoingMessage) -> Result<String>;
 }
 
 #[derive(Clone)]
@@ -152,11 +281,12 @@ pub struct SmtpImapClient {
     inbox: String,
     user: String,
     password: String,
+    message_id_domain: String,
 }
 
 #[async_trait]
 impl EmailTransport for SmtpImapClient {
-    async fn request_messages(&self) -> Result<Vec<ReceivedMessageContext>> {
+    async fn request_messages(&self) -> Result<(Vec<ReceivedMessageContext>, u32)> {
         let tls = native_tls::TlsConnector::builder().build()?;
         let client = imap::connect((self.imap_server.as_str(), 993), &self.imap_server, &tls)?;
 
@@ -164,7 +294,10 @@ impl EmailTransport for SmtpImapClient {
             .login(&self.user, &self.password)
             .map_err(|(err, _)| err)?;
 
-        imap.select(&self.inbox)?;
+        // The UIDs of messages are only unique in combination with the
+        // UIDVALIDITY of the mailbox. Servers which do not report it are
+        // treated as if it never changes.
+        let uid_validity = imap.select(&self.inbox)?.uid_validity.unwrap_or(0);
 
         // Fetch the messages of the last day. The database keeps track of which messages
         // have been processed.
@@ -174,7 +307,7 @@ impl EmailTransport for SmtpImapClient {
         let recent_seq = imap.search("X-GM-RAW \"newer_than:2d\"")?;
 
         if recent_seq.is_empty() {
-            return Ok(vec![]);
+            return Ok((vec![], uid_validity));
         }
 
         let min = recent_seq.iter().min().unwrap();
@@ -192,6 +325,9 @@ impl EmailTransport for SmtpImapClient {
             email_id: EmailId,
             sender: Account,
             body: String,
+            message_id: Option<String>,
+            references: Option<String>,
+            subject: Option<String>,
         ) -> ReceivedMessageContext {
             // The very first line must be the signature. If the message cannot
             // be parsed correctly, then just use empty strings which will
@@ -213,6 +349,11 @@ impl EmailTransport for SmtpImapClient {
                         .trim()
                 )
                 .replace("\"", ""),
+                message_id: message_id,
+                references: references,
+                subject: subject,
+                failed_recipients: vec![],
+                bounced_message_ids: vec![],
             }
         }
 
@@ -230,8 +371,43 @@ impl EmailTransport for SmtpImapClient {
                     .get_value()
                     .convert_into()?;
 
+                let message_id = find_header(&mail, "Message-ID");
+                let references = find_header(&mail, "References")
+                    .or_else(|| find_header(&mail, "In-Reply-To"))
+                    .map(|references| {
+                        references
+                            .split_whitespace()
+                            .collect::<Vec<&str>>()
+                            .join(" ")
+                    });
+                let subject = find_header(&mail, "Subject");
+
+                // Bounces are not processed as regular messages.
+                let failed_recipients = find_failed_recipients(&mail);
+                if !failed_recipients.is_empty() {
+                    parsed_messages.push(ReceivedMessageContext {
+                        id: email_id,
+                        sender: sender,
+                        body: String::new(),
+                        message_id: message_id,
+                        references: references,
+                        subject: subject,
+                        failed_recipients: failed_recipients,
+                        bounced_message_ids: find_quoted_message_ids(&mail),
+                    });
+
+                    continue;
+                }
+
                 if let Ok(body) = mail.get_body() {
-                    parsed_messages.push(create_message_context(email_id, sender.clone(), body));
+                    parsed_messages.push(create_message_context(
+                        email_id,
+                        sender.clone(),
+                        body,
+                        message_id.clone(),
+                        references.clone(),
+                        subject.clone(),
+                    ));
                 } else {
                     warn!("No body found in message from {}", sender);
                 }
@@ -242,6 +418,9 @@ impl EmailTransport for SmtpImapClient {
                             email_id,
                             sender.clone(),
                             body,
+                            message_id.clone(),
+                            references.clone(),
+                            subject.clone(),
                         ));
                     } else {
                         warn!("No body found in subpart message from {}", sender);
@@ -252,9 +431,9 @@ impl EmailTransport for SmtpImapClient {
             }
         }
 
-        Ok(parsed_messages)
+        Ok((parsed_messages, uid_validity))
     }
-    async fn send_message(&self, account: &Account, msg: String) -> Result<()> {
+    async fn send_message(&self, account: &Account, msg: OutgoingMessage) -> Result<String> {
         // SMTP transport
         let mut smtp = SmtpClient::new_simple(&self.smtp_server)?
             .credentials(Credentials::new(
@@ -263,38 +442,128 @@ impl EmailTransport for SmtpImapClient {
             ))
             .transport();
 
-        let email = EmailBuilder::new()
+        let builder = EmailBuilder::new()
             // Addresses can be specified by the tuple (email, alias)
             .to(account.as_str())
             .from(self.user.as_str())
-            .subject("W3F Registrar Verification Service")
-            .text(msg)
-            .build()
-            .unwrap();
+            .subject(msg.subject);
 
-        let _ = smtp.send(email.into())?;
+        let builder = if let Some(in_reply_to) = msg.in_reply_to {
+            builder.in_reply_to(in_reply_to)
+        } else {
+            builder
+        };
 
-        Ok(())
+        let builder = if let Some(references) = msg.references {
+            builder.references(references)
+        } else {
+            builder
+        };
+
+        // Send both the HTML and the plain text version if available, the
+        // email client decides which one to display.
+        let email = if let Some(html) = msg.html {
+            builder.alternative(html, msg.text)
+        } else {
+            builder.text(msg.text)
+        }
+        .build()?;
+
+        // `lettre_email` always generates a Message-ID on the `localhost`
+        // domain, which gets flagged by spam filters. Replace it with an ID on
+        // the configured domain.
+        let email: SendableEmail = email.into();
+        let envelope = email.envelope().clone();
+        let message_id = format!(
+            "{}@{}",
+            Challenge::gen_random().as_str(),
+            self.message_id_domain
+        );
+        let content = replace_message_id(&email.message_to_string()?, &message_id);
+
+        let _ = smtp
+            .send(SendableEmail::new(
+                envelope,
+                message_id.clone(),
+                content.into_bytes(),
+            ))
+            .map_err(|err| match err {
+                SmtpError::Permanent(ref response) if is_recipient_rejection(response) => {
+                    ClientError::RecipientRejected(account.clone(), err.to_string()).into()
+                }
+                _ => failure::Error::from(err),
+            })?;
+
+        Ok(message_id)
     }
 }
 
+/// How the owner of an email address proves control over the mailbox.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EmailVerificationMode {
+    /// The challenge must be signed with the on-chain key.
+    Signature,
+    /// The owner replies with a one-time code or opens the included link. The
+    /// on-chain key must then be proven through another channel, identities
+    /// without a Matrix or Twitter account must sign the challenge instead.
+    Code {
+        /// Public base URL of the HTTP service, e.g.
+        /// "https://registrar.example.com". No link is sent if not specified.
+        base_url: Option<String>,
+    },
+}
+
 #[derive(Clone)]
 pub struct EmailHandler {
     db: Database2,
     comms: CommsVerifier,
+    mode: EmailVerificationMode,
+    templates: Templates,
 }
 
 impl EmailHandler {
-    pub fn new(db: Database2, comms: CommsVerifier) -> Self {
+    pub fn new(
+        db: Database2,
+        comms: CommsVerifier,
+        mode: EmailVerificationMode,
+        templates: Templates,
+    ) -> Self {
         EmailHandler {
             db: db,
             comms: comms,
+            mode: mode,
+            templates: templates,
         }
     }
 }
 
 impl EmailHandler {
     pub async fn start<T: Clone + EmailTransport>(self, transport: T) {
+        // Start cleanup handler of processed email IDs.
+        let l_db = self.db.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(PRUNE_INTERVAL));
+            loop {
+                interval.tick().await;
+
+                let _ = l_db
+                    .prune_email_ids(EMAIL_ID_RETENTION)
+                    .await
+                    .map_err(|err| {
+                        error!("{}", err);
+                        err
+                    });
+
+                let _ = l_db
+                    .prune_sent_emails(EMAIL_ID_RETENTION)
+                    .await
+                    .map_err(|err| {
+                        error!("{}", err);
+                        err
+                    });
+            }
+        });
+
         // Start incoming messages handler.
         let l_self = self.clone();
         let l_transport = transport.clone();
@@ -324,10 +593,11 @@ impl EmailHandler {
 
         match self.comms.recv().await {
             AccountToVerify {
-                net_account: _,
+                net_account,
                 account,
             } => {
-                self.handle_account_verification(transport, account).await?;
+                self.handle_account_verification(transport, net_account, account)
+                    .await?;
             }
             NotifyInvalidAccount {
                 net_account,
@@ -344,8 +614,29 @@ impl EmailHandler {
     async fn handle_account_verification<T: EmailTransport>(
         &self,
         transport: &T,
+        net_account: NetAccount,
         account: Account,
     ) -> Result<()> {
+        // Codes only prove control over the mailbox, so the on-chain key must
+        // be proven through another channel. Otherwise, the challenge must be
+        // signed.
+        let signed_channel = self.has_signed_channel(&net_account).await?;
+
+        match &self.mode {
+            EmailVerificationMode::Code { base_url } if signed_channel => {
+                return self
+                    .send_code(transport, &net_account, &account, base_url.as_deref())
+                    .await;
+            }
+            EmailVerificationMode::Code { .. } => {
+                debug!(
+                    "No signature verified channel for {}, requesting a signature instead",
+                    net_account.as_str()
+                );
+            }
+            EmailVerificationMode::Signature => {}
+        }
+
         let challenge_data = self
             .db
             .select_challenge_data(&account, &AccountType::Email)
@@ -353,16 +644,90 @@ impl EmailHandler {
 
         debug!("Sending initial message to {}", account.as_str());
 
-        // Only require the verifier to send the initial message
-        let verifier = Verifier2::new(&challenge_data);
-        transport
-            .send_message(&account, verifier.init_message_builder(true))
-            .await?;
+        // Prefer the configured template, if any. Only require the verifier to
+        // send the initial message otherwise.
+        let message = self
+            .templates
+            .render(
+                &AccountType::Email,
+                TemplateKind::Instructions,
+                &challenge_data,
+            )
+            .map(|rendered| rendered.into())
+            .unwrap_or_else(|| {
+                Verifier2::new(&challenge_data)
+                    .init_message_builder(true)
+                    .into()
+            });
 
-        Ok(())
+        self.send_initial_message(transport, &account, message)
+            .await
+    }
+    /// Whether the identity contains an account which is verified by signing
+    /// the challenge with the on-chain key.
+    async fn has_signed_channel(&self, net_account: &NetAccount) -> Result<bool> {
+        Ok(self
+            .db
+            .select_account_statuses(net_account)
+            .await?
+            .iter()
+            .any(|(account_ty, _, _)| {
+                account_ty == &AccountType::Matrix || account_ty == &AccountType::Twitter
+            }))
+    }
+    async fn send_code<T: EmailTransport>(
+        &self,
+        transport: &T,
+        net_account: &NetAccount,
+        account: &Account,
+        base_url: Option<&str>,
+    ) -> Result<()> {
+        let code = Challenge::gen_random();
+        self.db.insert_email_code(net_account, &code).await?;
+
+        debug!("Sending verification code to {}", account.as_str());
+
+        // Prefer the configured template, if any.
+        let link = code_link(&code, base_url);
+        let message = self
+            .templates
+            .render_code(&AccountType::Email, &code, link.as_deref())
+            .map(|rendered| rendered.into())
+            .unwrap_or_else(|| code_message_builder(&code, link.as_deref()).into());
+
+        self.send_initial_message(transport, account, message).await
+    }
+    async fn send_initial_message<T: EmailTransport>(
+        &self,
+        transport: &T,
+        account: &Account,
+        message: OutgoingMessage,
+    ) -> Result<()> {
+        match self.send_message(transport, account, message).await {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                // The address does not exist (anymore), there is no point in
+                // retrying.
+                if let Some(ClientError::RecipientRejected(_, reason)) =
+                    err.downcast_ref::<ClientError>()
+                {
+                    self.handle_undeliverable(account, reason).await
+                } else {
+                    Err(err)
+                }
+            }
+        }
     }
     async fn handle_incoming_messages<T: EmailTransport>(&self, transport: &T) -> Result<()> {
-        let messages = transport.request_messages().await?;
+        // Codes confirmed via the HTTP service are picked up here.
+        if let EmailVerificationMode::Code { .. } = self.mode {
+            self.handle_confirmed_codes().await?;
+        }
+
+        let (messages, uid_validity) = transport.request_messages().await?;
+
+        // Forget about previously processed email IDs if the mailbox changed.
+        self.db.invalidate_email_ids(uid_validity).await?;
 
         if messages.is_empty() {
             trace!("No new messages found");
@@ -374,7 +739,10 @@ impl EmailHandler {
         email_ids.sort();
         email_ids.dedup();
 
-        let unknown_email_ids = self.db.find_untracked_email_ids(&email_ids).await?;
+        let unknown_email_ids = self
+            .db
+            .find_untracked_email_ids(&email_ids, uid_validity)
+            .await?;
 
         for email_id in unknown_email_ids {
             // Filter messages based on EmailId.
@@ -383,9 +751,35 @@ impl EmailHandler {
                 .filter(|msg| &msg.id == email_id)
                 .collect::<Vec<&ReceivedMessageContext>>();
 
-            let sender = &user_messages.first().unwrap().sender;
+            let received = user_messages.first().unwrap();
+            let sender = &received.sender;
             debug!("New message from {}", sender.as_str());
 
+            if !received.failed_recipients.is_empty() {
+                for account in &received.failed_recipients {
+                    // Only trust bounces of messages which were actually sent
+                    // to that address, anyone could send us a fake report.
+                    if !self
+                        .db
+                        .is_sent_email(&received.bounced_message_ids, account)
+                        .await?
+                    {
+                        warn!(
+                            "Ignoring bounce for {} which does not refer to a sent message",
+                            account.as_str()
+                        );
+
+                        continue;
+                    }
+
+                    self.handle_undeliverable(account, "delivery status notification received")
+                        .await?;
+                }
+
+                self.db.track_email_id(email_id, uid_validity).await?;
+                continue;
+            }
+
             debug!("Fetching challenge data");
             let challenge_data = self
                 .db
@@ -395,10 +789,50 @@ impl EmailHandler {
             if challenge_data.is_empty() {
                 warn!("No challenge data found for {}. Ignoring", sender.as_str());
 
-                self.db.track_email_id(email_id).await?;
+                self.db.track_email_id(email_id, uid_validity).await?;
                 continue;
             }
 
+            if let EmailVerificationMode::Code { .. } = self.mode {
+                let mut confirmed = false;
+                for message in &user_messages {
+                    debug!("Verifying code: {}", message.body);
+                    confirmed |= self
+                        .db
+                        .confirm_email_code(&message.body, EMAIL_CODE_EXPIRY, Some(sender))
+                        .await?;
+                }
+
+                self.handle_confirmed_codes().await?;
+
+                // Identities without a signed channel were asked to sign the
+                // challenge instead.
+                let mut signature_required = false;
+                for (network_address, _) in &challenge_data {
+                    signature_required |=
+                        !self.has_signed_channel(network_address.address()).await?;
+                }
+
+                if confirmed || !signature_required {
+                    let response = if confirmed {
+                        "The email address has been verified."
+                    } else {
+                        "The code is invalid or has expired. Please reply with the code from \
+                        the previous message."
+                    };
+
+                    self.send_message(
+                        transport,
+                        sender,
+                        OutgoingMessage::from(response.to_string()).reply_to(received),
+                    )
+                    .await?;
+
+                    self.db.track_email_id(email_id, uid_validity).await?;
+                    continue;
+                }
+            }
+
             let mut verifier = Verifier2::new(&challenge_data);
 
             for message in &user_messages {
@@ -410,11 +844,84 @@ impl EmailHandler {
             verification_handler(&verifier, &self.db, &self.comms, &AccountType::Email).await?;
 
             // Inform user about the current state of the verification
-            transport
-                .send_message(sender, verifier.response_message_builder())
+            self.send_message(
+                transport,
+                sender,
+                OutgoingMessage::from(verifier.response_message_builder()).reply_to(received),
+            )
+            .await?;
+
+            self.db.track_email_id(email_id, uid_validity).await?;
+        }
+
+        Ok(())
+    }
+    /// Sends the message and keeps track of its `Message-ID`, so bounces can
+    /// be matched to it.
+    async fn send_message<T: EmailTransport>(
+        &self,
+        transport: &T,
+        account: &Account,
+        message: OutgoingMessage,
+    ) -> Result<()> {
+        let message_id = transport.send_message(account, message).await?;
+        self.db.track_sent_email(&message_id, account).await?;
+
+        Ok(())
+    }
+    /// Marks the email account of all pending identities using the given
+    /// address as invalid, so the user can be informed via another channel.
+    async fn handle_undeliverable(&self, account: &Account, reason: &str) -> Result<()> {
+        warn!(
+            "Email address {} is undeliverable: {}",
+            account.as_str(),
+            reason
+        );
+
+        let challenge_data = self
+            .db
+            .select_challenge_data(account, &AccountType::Email)
+            .await?;
+
+        for (network_address, _) in challenge_data {
+            let net_account = network_address.address();
+
+            self.db
+                .set_account_status(
+                    net_account,
+                    &AccountType::Email,
+                    &AccountStatus::Invalid(Some(InvalidReason::MailBounced)),
+                )
                 .await?;
 
-            self.db.track_email_id(email_id).await?;
+            self.comms.notify_status_change(net_account.clone());
+        }
+
+        Ok(())
+    }
+    async fn handle_confirmed_codes(&self) -> Result<()> {
+        for net_account in self.db.select_confirmed_email_codes().await? {
+            debug!(
+                "Verification code confirmed for address: {}",
+                net_account.as_str()
+            );
+
+            self.db
+                .set_account_status(&net_account, &AccountType::Email, &AccountStatus::Valid)
+                .await?;
+
+            self.db
+                .set_challenge_status(
+                    &net_account,
+                    &AccountType::Email,
+                    &ChallengeStatus::Accepted,
+                )
+                .await?;
+
+            // Codes can only be used once.
+            self.db.remove_email_code(&net_account).await?;
+
+            self.comms.notify_status_change(net_account);
         }
 
         Ok(())
@@ -422,7 +929,7 @@ impl EmailHandler {
     async fn handle_invalid_account_notification<T: EmailTransport>(
         &self,
         net_account: NetAccount,
-        accounts: Vec<(AccountType, Account)>,
+        accounts: Vec<(AccountType, Account, Option<InvalidReason>)>,
         transport: &T,
     ) -> Result<()> {
         let account = self
@@ -436,10 +943,637 @@ impl EmailHandler {
         // Check for any display name violations (optional).
         let violations = self.db.select_display_name_violations(&net_account).await?;
 
-        transport
-            .send_message(&account, invalid_accounts_message(&accounts, violations))
-            .await?;
+        self.send_message(
+            transport,
+            &account,
+            invalid_accounts_message(&accounts, violations).into(),
+        )
+        .await?;
 
         Ok(())
     }
 }
+
+fn find_header(mail: &mailparse::ParsedMail, key: &str) -> Option<String> {
+    mail.headers
+        .iter()
+        .find(|header| header.get_key_ref().eq_ignore_ascii_case(key))
+        .map(|header| header.get_value())
+}
+
+/// Whether the mail was sent by a mail server. Delivery status notifications
+/// have a null envelope sender (RFC 5321, section 4.5.5), which the receiving
+/// server records as the `Return-Path`. Some servers use "MAILER-DAEMON" as
+/// the sender instead.
+fn is_from_mail_system(mail: &mailparse::ParsedMail) -> bool {
+    let null_sender = find_header(mail, "Return-Path")
+        .map(|path| path.trim() == "<>")
+        .unwrap_or(false);
+
+    let mailer_daemon = find_header(mail, "From")
+        .map(|from| from.to_lowercase().contains("mailer-daemon@"))
+        .unwrap_or(false);
+
+    null_sender || mailer_daemon
+}
+
+/// Returns the addresses reported as undeliverable if the mail is a bounce.
+/// Only top-level reports sent by a mail server are considered, so forwarded
+/// bounces are ignored.
+fn find_failed_recipients(mail: &mailparse::ParsedMail) -> Vec<Account> {
+    if !is_from_mail_system(mail) {
+        return vec![];
+    }
+
+    if mail.ctype.mimetype.eq_ignore_ascii_case("multipart/report") {
+        let failed = mail
+            .subparts
+            .iter()
+            .filter(|subpart| {
+                subpart
+                    .ctype
+                    .mimetype
+                    .eq_ignore_ascii_case("message/delivery-status")
+            })
+            .filter_map(|subpart| subpart.get_body().ok())
+            .flat_map(|body| parse_delivery_status(&body))
+            .collect::<Vec<Account>>();
+
+        if !failed.is_empty() {
+            return failed;
+        }
+    }
+
+    // Non-standard header set by some mail servers (e.g. Exim, Gmail).
+    find_header(mail, "X-Failed-Recipients")
+        .map(|header| {
+            header
+                .split(',')
+                .map(|addr| addr.trim())
+                .filter(|addr| !addr.is_empty())
+                .filter_map(|addr| addr.convert_into().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the `Message-ID`s quoted in the bounce, without angle brackets.
+/// Bounces either attach the original message or its headers, or quote them
+/// as text. Some servers also reference the original message in the
+/// `In-Reply-To` header.
+fn find_quoted_message_ids(mail: &mailparse::ParsedMail) -> Vec<String> {
+    fn collect(part: &mailparse::ParsedMail, ids: &mut Vec<String>) {
+        if let Ok(body) = part.get_body() {
+            for line in body.lines() {
+                let mut parts = line.trim().splitn(2, ':');
+                if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+                    if key.eq_ignore_ascii_case("Message-ID") {
+                        ids.push(value.to_string());
+                    }
+                }
+            }
+        }
+
+        for subpart in &part.subparts {
+            collect(subpart, ids);
+        }
+    }
+
+    let mut ids = vec![];
+    collect(mail, &mut ids);
+
+    if let Some(in_reply_to) = find_header(mail, "In-Reply-To") {
+        ids.push(in_reply_to);
+    }
+
+    let mut ids = ids
+        .iter()
+        .map(|id| id.trim().trim_matches(|c| c == '<' || c == '>').to_string())
+        .filter(|id| !id.is_empty())
+        .collect::<Vec<String>>();
+
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+/// Parses the body of a `message/delivery-status` part (RFC 3464) and returns
+/// the recipients whose delivery failed permanently.
+fn parse_delivery_status(body: &str) -> Vec<Account> {
+    let body = body.replace("\r\n", "\n");
+    let mut failed = vec![];
+
+    // The first block contains per-message fields, followed by a block for
+    // each recipient.
+    for block in body.split("\n\n") {
+        let mut recipient = None;
+        let mut original_recipient = None;
+        let mut action_failed = false;
+
+        for line in block.lines() {
+            let mut parts = line.splitn(2, ':');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => continue,
+            };
+
+            // Addresses are prefixed with their type, e.g. "rfc822; alice@example.com".
+            let address = || {
+                value
+                    .splitn(2, ';')
+                    .nth(1)
+                    .map(|addr| addr.trim().trim_matches(|c| c == '<' || c == '>'))
+                    .filter(|addr| !addr.is_empty())
+                    .map(Account::from)
+            };
+
+            if key.eq_ignore_ascii_case("Final-Recipient") {
+                recipient = address();
+            } else if key.eq_ignore_ascii_case("Original-Recipient") {
+                original_recipient = address();
+            } else if key.eq_ignore_ascii_case("Action") {
+                action_failed = value.eq_ignore_ascii_case("failed");
+            }
+        }
+
+        if action_failed {
+            if let Some(recipient) = original_recipient.or(recipient) {
+                failed.push(recipient);
+            }
+        }
+    }
+
+    failed
+}
+
+/// Whether the permanent SMTP error indicates that the recipient address does
+/// not exist, as opposed to other failures like authentication or policy
+/// errors.
+fn is_recipient_rejection(response: &Response) -> bool {
+    // Prefer the enhanced status code (RFC 3463) if available, where "5.1.x"
+    // indicates an addressing problem and "5.2.1" a disabled mailbox.
+    if let Some(status) = response
+        .first_word()
+        .filter(|word| word.starts_with("5.") && word.split('.').count() == 3)
+    {
+        return status.starts_with("5.1.") || status == "5.2.1";
+    }
+
+    // 550: mailbox unavailable, 551: user not local, 553: mailbox name not
+    // allowed.
+    [550, 551, 553].iter().any(|code| response.has_code(*code))
+}
+
+/// Replaces the value of the `Message-ID` header of the raw email.
+fn replace_message_id(content: &str, message_id: &str) -> String {
+    // Only look at the headers, which are separated from the body by an empty
+    // line.
+    let headers_end = content.find("\r\n\r\n").unwrap_or(content.len());
+
+    content[..headers_end]
+        .split("\r\n")
+        .map(|line| {
+            if line.to_lowercase().starts_with("message-id:") {
+                format!("Message-ID: <{}>", message_id)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\r\n")
+        + &content[headers_end..]
+}
+
+/// The link to confirm the code, if the public base URL is configured.
+fn code_link(code: &Challenge, base_url: Option<&str>) -> Option<String> {
+    base_url.map(|base_url| {
+        format!(
+            "{}/verify/email/{}",
+            base_url.trim_end_matches('/'),
+            code.as_str()
+        )
+    })
+}
+
+fn code_message_builder(code: &Challenge, link: Option<&str>) -> String {
+    let mut message = String::new();
+
+    message.push_str(
+        "\
+        This contact address was discovered in the Polkadot on-chain naming system and \
+        the issuer has requested the Web3 Registrar service to judge this account. \
+        If you did not issue this request then just ignore this message.\n\n\
+        Please reply to this message with the following code:\n\n\
+    ",
+    );
+
+    message.push_str(code.as_str());
+
+    if let Some(link) = link {
+        message.push_str("\n\nAlternatively, open the following link:\n\n");
+        message.push_str(link);
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::OnChainIdentity;
+    use crate::tests::mocks::{EmailEvent, EmailMocker, Event, EventManager2};
+    use tokio::runtime::Runtime;
+
+    // Generate a random db path
+    fn db_path() -> String {
+        format!("/tmp/sqlite_{}", Challenge::gen_random().as_str())
+    }
+
+    async fn is_valid(db: &Database2, net_account: &NetAccount) -> bool {
+        db.select_account_statuses(net_account)
+            .await
+            .unwrap()
+            .iter()
+            .any(|(_, _, status)| status == &AccountStatus::Valid)
+    }
+
+    #[test]
+    fn verify_code() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+            let manager = EventManager2::new();
+            let (sender, child) = manager.child();
+            let transport = EmailMocker::new(child);
+
+            let handler = EmailHandler::new(
+                db.clone(),
+                CommsVerifier::new(),
+                EmailVerificationMode::Code {
+                    base_url: Some(String::from("https://registrar.example.com")),
+                },
+                Templates::default(),
+            );
+
+            let alice = NetAccount::alice();
+            let bob = NetAccount::bob();
+            let alice_email = Account::from("alice@example.com");
+            let bob_email = Account::from("bob@example.com");
+
+            // The on-chain keys are proven through Matrix.
+            let mut alice_ident = OnChainIdentity::new(alice.clone()).unwrap();
+            alice_ident
+                .push_account(AccountType::Email, alice_email.clone())
+                .unwrap();
+            alice_ident
+                .push_account(AccountType::Matrix, Account::from("@alice:matrix.org"))
+                .unwrap();
+
+            let mut bob_ident = OnChainIdentity::new(bob.clone()).unwrap();
+            bob_ident
+                .push_account(AccountType::Email, bob_email.clone())
+                .unwrap();
+            bob_ident
+                .push_account(AccountType::Matrix, Account::from("@bob:matrix.org"))
+                .unwrap();
+
+            db.insert_identity_batch(&[&alice_ident, &bob_ident])
+                .await
+                .unwrap();
+
+            handler
+                .handle_account_verification(&transport, alice.clone(), alice_email.clone())
+                .await
+                .unwrap();
+
+            // Extract the code from the link of the sent message.
+            let events = manager.events().await;
+            let code = match events.last().unwrap() {
+                Event::Email(EmailEvent::SendMessage { account, message }) => {
+                    assert_eq!(account, &alice_email);
+                    message
+                        .split("https://registrar.example.com/verify/email/")
+                        .nth(1)
+                        .unwrap()
+                        .trim()
+                        .to_string()
+                }
+                _ => panic!("expected the code to be sent"),
+            };
+
+            let reply = |id: u64, from: &Account| ReceivedMessageContext {
+                id: EmailId::from(id),
+                sender: from.clone(),
+                body: code.clone(),
+                message_id: None,
+                references: None,
+                subject: None,
+                failed_recipients: vec![],
+                bounced_message_ids: vec![],
+            };
+
+            // The code of Alice is sent by Bob.
+            sender.send_message(reply(1, &bob_email)).await;
+            handler.handle_incoming_messages(&transport).await.unwrap();
+
+            assert!(!is_valid(&db, &alice).await);
+
+            let events = manager.events().await;
+            match events.last().unwrap() {
+                Event::Email(EmailEvent::SendMessage { account, message }) => {
+                    assert_eq!(account, &bob_email);
+                    assert!(message.starts_with("The code is invalid"));
+                }
+                _ => panic!("expected a response to Bob"),
+            }
+
+            // The code is sent by Alice.
+            sender.send_message(reply(2, &alice_email)).await;
+            handler.handle_incoming_messages(&transport).await.unwrap();
+
+            assert!(is_valid(&db, &alice).await);
+
+            let events = manager.events().await;
+            match events.last().unwrap() {
+                Event::Email(EmailEvent::SendMessage { account, message }) => {
+                    assert_eq!(account, &alice_email);
+                    assert_eq!(message, "The email address has been verified.");
+                }
+                _ => panic!("expected a response to Alice"),
+            }
+
+            // Codes can only be used once.
+            let res = db
+                .confirm_email_code(&code, EMAIL_CODE_EXPIRY, None)
+                .await
+                .unwrap();
+            assert!(!res);
+        });
+    }
+
+    #[test]
+    fn email_only_identity_requires_signature() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+            let manager = EventManager2::new();
+            let (sender, child) = manager.child();
+            let transport = EmailMocker::new(child);
+
+            let handler = EmailHandler::new(
+                db.clone(),
+                CommsVerifier::new(),
+                EmailVerificationMode::Code {
+                    base_url: Some(String::from("https://registrar.example.com")),
+                },
+                Templates::default(),
+            );
+
+            let alice = NetAccount::alice();
+            let alice_email = Account::from("alice@example.com");
+
+            // No other channel proves the on-chain key.
+            let mut ident = OnChainIdentity::new(alice.clone()).unwrap();
+            ident
+                .push_account(AccountType::Email, alice_email.clone())
+                .unwrap();
+
+            db.insert_identity(&ident).await.unwrap();
+
+            handler
+                .handle_account_verification(&transport, alice.clone(), alice_email.clone())
+                .await
+                .unwrap();
+
+            // The challenge must be signed instead of replying with a code.
+            let challenge_data = db
+                .select_challenge_data(&alice_email, &AccountType::Email)
+                .await
+                .unwrap();
+
+            let events = manager.events().await;
+            match events.last().unwrap() {
+                Event::Email(EmailEvent::SendMessage { account, message }) => {
+                    assert_eq!(account, &alice_email);
+                    assert!(!message.contains("/verify/email/"));
+                    assert!(message.contains(challenge_data[0].1.as_str()));
+                }
+                _ => panic!("expected the challenge to be sent"),
+            }
+
+            // Replies are checked for a signature, not for a code.
+            sender
+                .send_message(ReceivedMessageContext {
+                    id: EmailId::from(1),
+                    sender: alice_email.clone(),
+                    body: String::from("unsigned"),
+                    message_id: None,
+                    references: None,
+                    subject: None,
+                    failed_recipients: vec![],
+                    bounced_message_ids: vec![],
+                })
+                .await;
+            handler.handle_incoming_messages(&transport).await.unwrap();
+
+            let events = manager.events().await;
+            match events.last().unwrap() {
+                Event::Email(EmailEvent::SendMessage { account, message }) => {
+                    assert_eq!(account, &alice_email);
+                    assert!(!message.starts_with("The code is invalid"));
+                }
+                _ => panic!("expected a response to Alice"),
+            }
+
+            assert!(db.select_confirmed_email_codes().await.unwrap().is_empty());
+            assert!(!db.is_fully_verified(&alice).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn replace_message_id_header() {
+        let content = "To: alice@example.com\r\n\
+            Message-ID: <1234.lettre@localhost>\r\n\
+            Subject: Hello\r\n\
+            \r\n\
+            Message-ID: <body@localhost>\r\n";
+
+        let res = replace_message_id(content, "abcd@example.com");
+        assert_eq!(
+            res,
+            "To: alice@example.com\r\n\
+            Message-ID: <abcd@example.com>\r\n\
+            Subject: Hello\r\n\
+            \r\n\
+            Message-ID: <body@localhost>\r\n"
+        );
+    }
+
+    #[test]
+    fn reply_to_received_message() {
+        let mut received = ReceivedMessageContext {
+            id: EmailId::from(1u64),
+            sender: Account::from("alice@example.com"),
+            body: String::from("body"),
+            message_id: Some(String::from("<1234@example.com>")),
+            references: None,
+            subject: Some(String::from("W3F Registrar Verification Service")),
+            failed_recipients: vec![],
+            bounced_message_ids: vec![],
+        };
+
+        let res = OutgoingMessage::from(String::from("response")).reply_to(&received);
+        assert_eq!(res.subject, "Re: W3F Registrar Verification Service");
+        assert_eq!(res.in_reply_to.unwrap(), "<1234@example.com>");
+        assert_eq!(res.references.unwrap(), "<1234@example.com>");
+
+        // The thread is continued.
+        received.references = Some(String::from("<1000@example.com> <1200@example.com>"));
+
+        let res = OutgoingMessage::from(String::from("response")).reply_to(&received);
+        assert_eq!(
+            res.references.unwrap(),
+            "<1000@example.com> <1200@example.com> <1234@example.com>"
+        );
+
+        // Do not stack prefixes.
+        received.subject = Some(String::from("RE: W3F Registrar Verification Service"));
+
+        let res = OutgoingMessage::from(String::from("response")).reply_to(&received);
+        assert_eq!(res.subject, "RE: W3F Registrar Verification Service");
+
+        // Missing headers.
+        received.message_id = None;
+        received.subject = None;
+
+        let res = OutgoingMessage::from(String::from("response")).reply_to(&received);
+        assert_eq!(res.subject, DEFAULT_SUBJECT);
+        assert!(res.in_reply_to.is_none());
+        assert!(res.references.is_none());
+    }
+
+    #[test]
+    fn parse_delivery_status_notification() {
+        let body = "Reporting-MTA: dns; googlemail.com\r\n\
+            Arrival-Date: Mon, 12 Oct 2020 10:00:00 -0700\r\n\
+            \r\n\
+            Final-Recipient: rfc822; alice@example.com\r\n\
+            Action: failed\r\n\
+            Status: 5.1.1\r\n\
+            \r\n\
+            Final-Recipient: rfc822; bob@example.com\r\n\
+            Action: delayed\r\n\
+            Status: 4.4.1\r\n\
+            \r\n\
+            Original-Recipient: rfc822;<Eve@example.com>\r\n\
+            Final-Recipient: rfc822; eve@example.com\r\n\
+            Action: Failed\r\n\
+            Status: 5.2.1\r\n";
+
+        let res = parse_delivery_status(body);
+        assert_eq!(
+            res,
+            vec![
+                Account::from("alice@example.com"),
+                Account::from("Eve@example.com")
+            ]
+        );
+
+        assert!(parse_delivery_status("Reporting-MTA: dns; googlemail.com").is_empty());
+    }
+
+    #[test]
+    fn authenticate_bounces() {
+        let bounce = "Return-Path: <>\r\n\
+            From: Mail Delivery Subsystem <mailer-daemon@googlemail.com>\r\n\
+            Message-ID: <bounce@googlemail.com>\r\n\
+            Content-Type: multipart/report; report-type=delivery-status; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            The email account that you tried to reach does not exist.\r\n\
+            --b\r\n\
+            Content-Type: message/delivery-status\r\n\
+            \r\n\
+            Reporting-MTA: dns; googlemail.com\r\n\
+            \r\n\
+            Final-Recipient: rfc822; alice@example.com\r\n\
+            Action: failed\r\n\
+            Status: 5.1.1\r\n\
+            --b\r\n\
+            Content-Type: text/rfc822-headers\r\n\
+            \r\n\
+            To: alice@example.com\r\n\
+            Message-ID: <1234@example.com>\r\n\
+            --b--\r\n";
+
+        let mail = mailparse::parse_mail(bounce.as_bytes()).unwrap();
+        assert_eq!(
+            find_failed_recipients(&mail),
+            vec![Account::from("alice@example.com")]
+        );
+        assert_eq!(find_quoted_message_ids(&mail), vec!["1234@example.com"]);
+
+        // Same report, but sent by a regular user.
+        let spoofed = bounce
+            .replace("Return-Path: <>", "Return-Path: <eve@example.com>")
+            .replace("mailer-daemon@googlemail.com", "eve@example.com");
+
+        let mail = mailparse::parse_mail(spoofed.as_bytes()).unwrap();
+        assert!(find_failed_recipients(&mail).is_empty());
+
+        // Non-standard header.
+        let spoofed = "From: Eve <eve@example.com>\r\n\
+            X-Failed-Recipients: alice@example.com\r\n\
+            \r\n\
+            Hello\r\n";
+
+        let mail = mailparse::parse_mail(spoofed.as_bytes()).unwrap();
+        assert!(find_failed_recipients(&mail).is_empty());
+
+        let bounce = spoofed.replace("Eve <eve@example.com>", "MAILER-DAEMON@example.com");
+
+        let mail = mailparse::parse_mail(bounce.as_bytes()).unwrap();
+        assert_eq!(
+            find_failed_recipients(&mail),
+            vec![Account::from("alice@example.com")]
+        );
+        assert!(find_quoted_message_ids(&mail).is_empty());
+    }
+
+    #[test]
+    fn recipient_rejection_codes() {
+        use lettre::smtp::response::{Category, Code, Detail, Severity};
+
+        let response = |category, detail, message: &str| {
+            Response::new(
+                Code::new(Severity::PermanentNegativeCompletion, category, detail),
+                vec![message.to_string()],
+            )
+        };
+
+        assert!(is_recipient_rejection(&response(
+            Category::MailSystem,
+            Detail::Zero,
+            "5.1.1 The email account that you tried to reach does not exist."
+        )));
+        assert!(is_recipient_rejection(&response(
+            Category::MailSystem,
+            Detail::Zero,
+            "Mailbox unavailable"
+        )));
+        // Policy rejections are not related to the recipient.
+        assert!(!is_recipient_rejection(&response(
+            Category::MailSystem,
+            Detail::Zero,
+            "5.7.1 Message rejected due to spam"
+        )));
+        // Authentication failure.
+        assert!(!is_recipient_rejection(&response(
+            Category::Unspecified3,
+            Detail::Five,
+            "5.7.8 Username and Password not accepted"
+        )));
+    }
+}
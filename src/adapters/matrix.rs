@@ -1,40 +1,81 @@
 use crate::comms::{CommsMessage, CommsVerifier};
 use crate::db::Database2;
-use crate::manager::AccountStatus;
-use crate::primitives::{Account, AccountType, NetAccount, Result};
-use crate::verifier::{invalid_accounts_message, verification_handler, Verifier2};
+use crate::manager::{AccountStatus, InvalidReason};
+use crate::message::{FormattedMessage, MessageBuilder};
+use crate::primitives::{Account, AccountType, Challenge, NetAccount, NetworkAddress, Result};
+use crate::templates::{TemplateKind, Templates};
+use crate::verifier::{
+    invalid_accounts_message, invalid_reason_explanation, verification_handler, Verifier2,
+};
 use matrix_sdk::{
     self,
+    api::r0::account::whoami,
+    api::r0::config::{get_global_account_data, set_global_account_data},
+    api::r0::membership::joined_members,
+    api::r0::profile::get_profile,
     api::r0::room::create_room::{Request, Response},
     api::r0::room::Visibility,
+    api::r0::typing::create_typing_event::Typing,
     events::{
+        room::encryption::EncryptionEventContent,
+        room::member::{MemberEventContent, MembershipState},
         room::message::{MessageEventContent, TextMessageEventContent},
-        AnyMessageEventContent, SyncMessageEvent,
+        AnyInitialStateEvent, AnyMessageEventContent, InitialStateEvent, SyncMessageEvent,
+        SyncStateEvent,
     },
-    identifiers::{RoomId, UserId},
-    Client, ClientConfig, EventEmitter, JsonStore, SyncRoom, SyncSettings,
+    identifiers::{EventEncryptionAlgorithm, EventId, RoomId, UserId},
+    Client, ClientConfig, EventEmitter, JsonStore, Session, SyncRoom, SyncSettings,
 };
-use std::convert::TryInto;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::future::Future;
+use std::path::PathBuf;
 use std::result::Result as StdResult;
 use tokio::time::{self, Duration};
 use url::Url;
 
+/// How often the access token is checked for validity, in seconds.
+const SESSION_CHECK_INTERVAL: u64 = 300;
+/// How often the queue of rooms to leave is processed, in seconds.
+const REAPER_INTERVAL: u64 = 10;
+/// Initial delay before retrying to leave a room, doubled on each attempt.
+const LEAVE_BACKOFF_BASE: u64 = 30;
+const LEAVE_BACKOFF_MAX: u64 = 3600;
+/// Rooms are no longer retried to be left after that many failures
+/// (excluding rate limits).
+const MAX_LEAVE_ATTEMPTS: u32 = 10;
+/// How long the typing notification is displayed at most, in seconds.
+const TYPING_TIMEOUT: u64 = 30;
+/// If processing a message takes longer than that (e.g. on a slow database),
+/// the user is informed that the message is being processed, in seconds.
+const PROCESSING_NOTE_DELAY: u64 = 5;
+/// Timeout of the profile lookup checking whether a user exists, in seconds.
+const LOOKUP_TIMEOUT: u64 = 10;
+/// How often users of unreachable homeservers are looked up again, in seconds.
+const LOOKUP_RETRY_INTERVAL: u64 = 300;
+/// Users are marked invalid if their homeserver could not be reached after
+/// that many lookups.
+const MAX_LOOKUP_ATTEMPTS: u32 = 12;
+
 #[derive(Debug, Fail)]
 pub enum MatrixError {
     #[fail(display = "failed to open state store: {}", 0)]
     StateStore(failure::Error),
+    #[fail(display = "failed to open crypto store: {}", _0)]
+    CryptoStore(failure::Error),
     #[fail(display = "failed to create client with the given config: {}", 0)]
     ClientCreation(failure::Error),
     #[fail(display = "failed to login into the homeserver: {}", 0)]
     Login(failure::Error),
+    #[fail(display = "failed to persist session: {}", _0)]
+    Session(failure::Error),
     #[fail(display = "failed to sync: {}", 0)]
     Sync(failure::Error),
     #[fail(display = "the specified UserId is invalid: {}", 0)]
     InvalidUserId(failure::Error),
     #[fail(display = "failed to join room: {}", 0)]
     JoinRoom(failure::Error),
-    #[fail(display = "timeout while trying to join room with: {}", 0)]
-    JoinRoomTimeout(Account),
     #[fail(display = "the remote UserId was not found when trying to respond")]
     RemoteUserIdNotFound,
     #[fail(display = "failed to send message: {}", 0)]
@@ -52,18 +93,41 @@ pub enum MatrixError {
     NoMatrixAccount(String),
 }
 
+/// Result of looking up a Matrix user before creating a room.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum UserLookup {
+    Exists,
+    /// The homeserver is reachable, but does not know the user.
+    NotFound,
+    /// The homeserver could not be reached, the lookup should be retried.
+    Unreachable,
+}
+
 #[async_trait]
 pub trait MatrixTransport: 'static + Send + Sync {
-    async fn send_message(&self, room_id: &RoomId, message: String) -> Result<()>;
+    async fn send_message(&self, room_id: &RoomId, message: FormattedMessage) -> Result<()>;
     async fn create_room<'a>(&'a self, request: Request<'a>) -> Result<Response>;
     async fn leave_room(&self, room_id: &RoomId) -> Result<()>;
+    async fn joined_members(&self, room_id: &RoomId) -> Result<Vec<UserId>>;
+    async fn invite_user(&self, room_id: &RoomId, user_id: &UserId) -> Result<()>;
+    /// Returns the rooms of the `m.direct` account data.
+    async fn direct_rooms(&self) -> Result<Vec<(Account, RoomId)>>;
+    /// Replaces the `m.direct` account data, which lists the direct message
+    /// rooms per user.
+    async fn set_direct_rooms(&self, rooms: &[(Account, RoomId)]) -> Result<()>;
+    async fn read_receipt(&self, room_id: &RoomId, event_id: &EventId) -> Result<()>;
+    async fn typing(&self, room_id: &RoomId, typing: bool) -> Result<()>;
+    async fn lookup_user(&self, user_id: &UserId) -> Result<UserLookup>;
     async fn user_id(&self) -> Result<UserId>;
-    async fn run_emitter(&mut self, db: Database2, comms: CommsVerifier);
+    async fn run_emitter(&mut self, db: Database2, comms: CommsVerifier, templates: Templates);
 }
 
 #[derive(Clone)]
 pub struct MatrixClient {
     client: Client, // `Client` from matrix_sdk
+    username: String,
+    password: String,
+    session_path: PathBuf,
 }
 
 impl MatrixClient {
@@ -77,17 +141,44 @@ impl MatrixClient {
         info!("Setting up Matrix client");
         // Setup client
         let store = JsonStore::open(db_path).map_err(|err| MatrixError::StateStore(err.into()))?;
-        let client_config = ClientConfig::new().state_store(Box::new(store));
+
+        // The E2EE device keys are kept next to the state store. The keys are
+        // bound to the device ID of the session, so the same ID must be
+        // reused on every login.
+        let crypto_path = PathBuf::from(format!("{}.crypto", db_path));
+        fs::create_dir_all(&crypto_path).map_err(|err| MatrixError::CryptoStore(err.into()))?;
+
+        let client_config = ClientConfig::new()
+            .state_store(Box::new(store))
+            .store_path(&crypto_path);
 
         let homeserver = Url::parse(homeserver).expect("Couldn't parse the homeserver URL");
         let client = Client::new_with_config(homeserver, client_config)
             .map_err(|err| MatrixError::ClientCreation(err.into()))?;
 
-        // Login with credentials
-        client
-            .login(username, password, None, Some("w3f-registrar-bot"))
-            .await
-            .map_err(|err| MatrixError::Login(err.into()))?;
+        let matrix = MatrixClient {
+            client: client.clone(),
+            username: username.to_string(),
+            password: password.to_string(),
+            session_path: crypto_path.join("session.json"),
+        };
+
+        // Restore the previous session, if available. Only login with
+        // credentials if the access token is no longer valid.
+        let l_matrix = &matrix;
+        restore_session(
+            matrix.load_session(),
+            |session| async move {
+                l_matrix
+                    .client
+                    .restore_login(session)
+                    .await
+                    .map_err(|err| failure::Error::from(MatrixError::Login(err.into())))
+            },
+            || l_matrix.is_session_valid(),
+            |device_id| async move { l_matrix.login(device_id.as_deref()).await },
+        )
+        .await?;
 
         // Sync up, avoid responding to old messages.
         info!("Syncing Matrix client");
@@ -99,14 +190,15 @@ impl MatrixClient {
         // Request a list of open/pending room ids. Used to detect dead rooms.
         let pending_room_ids = db.select_room_ids().await?;
 
-        // Leave dead rooms.
+        // Leave dead rooms. The rooms are left in the background by the
+        // `MatrixHandler`.
         info!("Detecting dead Matrix rooms");
         let rooms = client.joined_rooms();
         let rooms = rooms.read().await;
         for (room_id, _) in rooms.iter() {
             if pending_room_ids.iter().find(|&id| id == room_id).is_none() {
-                warn!("Leaving dead room: {}", room_id.as_str());
-                let _ = client.leave_room(room_id).await;
+                debug!("Found dead room: {}", room_id.as_str());
+                db.insert_room_to_leave(room_id).await?;
             }
         }
 
@@ -117,22 +209,147 @@ impl MatrixClient {
                 .await;
         });
 
-        let matrix = MatrixClient { client: client };
+        // Login again if the access token expires or gets invalidated. The
+        // sync loop picks up the new session.
+        let session_matrix = matrix.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(SESSION_CHECK_INTERVAL));
+            loop {
+                interval.tick().await;
+
+                // Errors other than an invalidated access token, e.g. if the
+                // homeserver is unreachable, are not solved by logging in.
+                match session_matrix.is_session_valid().await {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(err) => {
+                        debug!("Matrix session check failed: {}", err);
+                        continue;
+                    }
+                }
+
+                warn!("Matrix session is no longer valid, logging in again");
+
+                let device_id = session_matrix
+                    .load_session()
+                    .map(|session| session.device_id.to_string());
+
+                let _ = session_matrix
+                    .login(device_id.as_deref())
+                    .await
+                    .map_err(|err| {
+                        error!("{}", err);
+                    });
+            }
+        });
 
         Ok(matrix)
     }
+    /// Logs in with the credentials and persists the session.
+    async fn login(&self, device_id: Option<&str>) -> Result<()> {
+        let resp = self
+            .client
+            .login(
+                &self.username,
+                &self.password,
+                device_id,
+                Some("w3f-registrar-bot"),
+            )
+            .await
+            .map_err(|err| MatrixError::Login(err.into()))?;
+
+        let session = Session {
+            access_token: resp.access_token,
+            user_id: resp.user_id,
+            device_id: resp.device_id,
+        };
+
+        fs::write(
+            &self.session_path,
+            serde_json::to_string(&session).map_err(|err| MatrixError::Session(err.into()))?,
+        )
+        .map_err(|err| MatrixError::Session(err.into()))?;
+
+        Ok(())
+    }
+    fn load_session(&self) -> Option<Session> {
+        fs::read_to_string(&self.session_path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+    }
+    /// Returns `false` if the homeserver no longer accepts the access token.
+    async fn is_session_valid(&self) -> Result<bool> {
+        use matrix_sdk::api::error::ErrorKind;
+
+        match self.client.send(whoami::Request::new()).await {
+            Ok(_) => Ok(true),
+            Err(err) => {
+                let err = failure::Error::from(err);
+                match error_kind(&err) {
+                    Some(ErrorKind::UnknownToken { .. }) => Ok(false),
+                    _ => Err(err),
+                }
+            }
+        }
+    }
+}
+
+/// Restores the persisted session and checks whether the access token is
+/// still valid. Logs in with the credentials if there is no session, if it
+/// cannot be restored or if the access token was invalidated. The device ID
+/// of the session is kept, since the E2EE keys are bound to it.
+async fn restore_session<R, RF, C, CF, L, LF>(
+    session: Option<Session>,
+    restore: R,
+    is_valid: C,
+    login: L,
+) -> Result<()>
+where
+    R: FnOnce(Session) -> RF,
+    RF: Future<Output = Result<()>>,
+    C: FnOnce() -> CF,
+    CF: Future<Output = Result<bool>>,
+    L: FnOnce(Option<String>) -> LF,
+    LF: Future<Output = Result<()>>,
+{
+    let session = match session {
+        Some(session) => session,
+        None => return login(None).await,
+    };
+
+    let device_id = session.device_id.to_string();
+
+    if let Err(err) = restore(session).await {
+        warn!(
+            "Failed to restore Matrix session, logging in again: {}",
+            err
+        );
+        return login(Some(device_id)).await;
+    }
+
+    if !is_valid().await? {
+        info!("Matrix session expired, logging in again");
+        login(Some(device_id)).await?;
+    }
+
+    Ok(())
 }
 
 #[async_trait]
 impl MatrixTransport for MatrixClient {
-    async fn send_message(&self, room_id: &RoomId, message: String) -> Result<()> {
+    async fn send_message(&self, room_id: &RoomId, message: FormattedMessage) -> Result<()> {
+        // The plain text body is the fallback for clients which do not
+        // support formatted messages.
+        let content = if let Some(html) = message.html {
+            TextMessageEventContent::html(message.plain, html)
+        } else {
+            TextMessageEventContent::plain(message.plain)
+        };
+
         self.client
             .room_send(
                 room_id,
-                AnyMessageEventContent::RoomMessage(MessageEventContent::Text(
-                    // TODO: Make a proper Message Creator for this
-                    TextMessageEventContent::plain(message),
-                )),
+                AnyMessageEventContent::RoomMessage(MessageEventContent::Text(content)),
                 None,
             )
             .await
@@ -149,24 +366,234 @@ impl MatrixTransport for MatrixClient {
         self.client
             .leave_room(room_id)
             .await
+            .map_err(|err| failure::Error::from(err))?;
+
+        // Remove the room from the room list of the account. Not critical.
+        let _ = self.client.forget_room_by_id(room_id).await.map_err(|err| {
+            debug!("Failed to forget room {}: {}", room_id.as_str(), err);
+        });
+
+        Ok(())
+    }
+    async fn joined_members(&self, room_id: &RoomId) -> Result<Vec<UserId>> {
+        self.client
+            .send(joined_members::Request::new(room_id))
+            .await
+            .map_err(|err| err.into())
+            .map(|resp| resp.joined.keys().cloned().collect())
+    }
+    async fn invite_user(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+        self.client
+            .invite_user_by_id(room_id, user_id)
+            .await
             .map_err(|err| err.into())
             .map(|_| ())
     }
+    async fn direct_rooms(&self) -> Result<Vec<(Account, RoomId)>> {
+        let user_id = self.user_id().await?;
+        let request = get_global_account_data::Request::new(&user_id, "m.direct");
+
+        let resp = match self.client.send(request).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                use matrix_sdk::api::error::ErrorKind;
+
+                let err = failure::Error::from(err);
+                match error_kind(&err) {
+                    // The account data does not exist yet.
+                    Some(ErrorKind::NotFound) => return Ok(vec![]),
+                    _ => return Err(err),
+                }
+            }
+        };
+
+        let content: BTreeMap<String, Vec<String>> =
+            serde_json::from_str(resp.account_data.json().get())?;
+
+        let mut rooms = vec![];
+        for (account, room_ids) in content {
+            for room_id in room_ids {
+                match RoomId::try_from(room_id.as_str()) {
+                    Ok(room_id) => rooms.push((Account::from(account.as_str()), room_id)),
+                    Err(_) => warn!("Invalid room ID in direct rooms: {}", room_id),
+                }
+            }
+        }
+
+        Ok(rooms)
+    }
+    async fn set_direct_rooms(&self, rooms: &[(Account, RoomId)]) -> Result<()> {
+        let mut content: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (account, room_id) in rooms {
+            content
+                .entry(account.as_str())
+                .or_default()
+                .push(room_id.as_str());
+        }
+
+        let user_id = self.user_id().await?;
+        let request = set_global_account_data::Request {
+            data: serde_json::value::to_raw_value(&content)?,
+            event_type: "m.direct",
+            user_id: &user_id,
+        };
+
+        self.client
+            .send(request)
+            .await
+            .map_err(|err| err.into())
+            .map(|_| ())
+    }
+    async fn read_receipt(&self, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+        self.client
+            .read_receipt(room_id, event_id)
+            .await
+            .map_err(|err| err.into())
+            .map(|_| ())
+    }
+    async fn typing(&self, room_id: &RoomId, typing: bool) -> Result<()> {
+        let typing = if typing {
+            Typing::Yes(Duration::from_secs(TYPING_TIMEOUT))
+        } else {
+            Typing::No
+        };
+
+        let user_id = self.user_id().await?;
+        self.client
+            .typing_notice(room_id, &user_id, typing)
+            .await
+            .map_err(|err| err.into())
+            .map(|_| ())
+    }
+    async fn lookup_user(&self, user_id: &UserId) -> Result<UserLookup> {
+        // The profile is fetched by the own homeserver via federation, which
+        // takes care of resolving the remote homeserver.
+        let request = get_profile::Request::new(user_id);
+        match time::timeout(
+            Duration::from_secs(LOOKUP_TIMEOUT),
+            self.client.send(request),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(UserLookup::Exists),
+            Ok(Err(err)) => {
+                use matrix_sdk::api::error::ErrorKind;
+
+                let err = failure::Error::from(err);
+                match (error_kind(&err), error_status(&err)) {
+                    (Some(ErrorKind::NotFound), _) => Ok(UserLookup::NotFound),
+                    // Some homeservers restrict profile lookups, the user
+                    // might exist anyway.
+                    (Some(ErrorKind::Forbidden), _) => Ok(UserLookup::Exists),
+                    // The own homeserver failed to reach the remote one.
+                    (Some(_), Some(status)) if status >= 500 => {
+                        debug!("Failed to fetch profile of {}: {}", user_id.as_str(), err);
+                        Ok(UserLookup::Unreachable)
+                    }
+                    // Any other response of the remote homeserver, the invite
+                    // decides whether the user can be contacted.
+                    (Some(_), _) => {
+                        debug!("Failed to fetch profile of {}: {}", user_id.as_str(), err);
+                        Ok(UserLookup::Exists)
+                    }
+                    // The own homeserver could not be reached, which is not
+                    // the fault of the user.
+                    (None, _) => Err(err),
+                }
+            }
+            Err(_) => Ok(UserLookup::Unreachable),
+        }
+    }
     async fn user_id(&self) -> Result<UserId> {
-        //self.client.user_id().await.ok_or(failure::Error::from(Err(MatrixError::RemoteUserIdNotFound)))
-        // TODO
-        Ok(self.client.user_id().await.unwrap())
+        self.client
+            .user_id()
+            .await
+            .ok_or_else(|| MatrixError::RemoteUserIdNotFound.into())
     }
-    async fn run_emitter(&mut self, db: Database2, comms: CommsVerifier) {
+    async fn run_emitter(&mut self, db: Database2, comms: CommsVerifier, templates: Templates) {
         // Add event emitter
         self.client
-            .add_event_emitter(Box::new(MatrixHandler::new(db, comms, self.clone())))
+            .add_event_emitter(Box::new(MatrixHandler::new(
+                db,
+                comms,
+                self.clone(),
+                templates,
+            )))
             .await;
     }
 }
 
+/// Commands users can send instead of a signature.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Command {
+    Status,
+    Resend,
+    Help,
+    Cancel,
+}
+
+impl Command {
+    fn parse(message: &str) -> Option<Self> {
+        match message
+            .trim()
+            .trim_start_matches('!')
+            .to_lowercase()
+            .as_str()
+        {
+            "status" => Some(Command::Status),
+            "resend" => Some(Command::Resend),
+            "help" => Some(Command::Help),
+            "cancel" => Some(Command::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// Canonicalizes a Matrix user ID as specified in an on-chain identity. IDs
+/// without the leading `@`, matrix.to links and mixed case server names are
+/// accepted. The localpart is kept as is, since historical user IDs might
+/// contain upper case characters. Returns `None` if the ID cannot be parsed.
+pub fn normalize_matrix_id(account: &Account) -> Option<Account> {
+    let mut id = account.as_str().trim();
+
+    for prefix in &[
+        "https://matrix.to/#/",
+        "http://matrix.to/#/",
+        "matrix.to/#/",
+    ] {
+        if let Some(stripped) = id.strip_prefix(prefix) {
+            id = stripped;
+            break;
+        }
+    }
+
+    // Links might contain query parameters, e.g. `?via=matrix.org`.
+    let id = id.split('?').next().unwrap_or(id);
+    let id = urlencoding::decode(id).ok()?.trim().to_string();
+
+    if id.is_empty() || id.contains(char::is_whitespace) {
+        return None;
+    }
+
+    // Server names are case-insensitive.
+    let id = match id.find(':') {
+        Some(pos) => format!("{}{}", &id[..pos], id[pos..].to_lowercase()),
+        None => id,
+    };
+
+    let id = if id.starts_with('@') {
+        id
+    } else {
+        format!("@{}", id)
+    };
+
+    let user_id: UserId = id.as_str().try_into().ok()?;
+    Some(Account::from(user_id.as_str()))
+}
+
 pub trait EventExtract {
     fn sender(&self) -> &UserId;
+    fn event_id(&self) -> &EventId;
     fn message(&self) -> Result<String>;
 }
 
@@ -174,6 +601,9 @@ impl EventExtract for SyncMessageEvent<MessageEventContent> {
     fn sender(&self) -> &UserId {
         &self.sender
     }
+    fn event_id(&self) -> &EventId {
+        &self.event_id
+    }
     fn message(&self) -> Result<String> {
         match self {
             SyncMessageEvent {
@@ -189,6 +619,7 @@ pub struct MatrixHandler {
     db: Database2,
     comms: CommsVerifier,
     transport: Box<dyn MatrixTransport>,
+    templates: Templates,
 }
 
 impl MatrixHandler {
@@ -196,20 +627,104 @@ impl MatrixHandler {
         db: Database2,
         comms: CommsVerifier,
         transport: T,
+        templates: Templates,
     ) -> Self {
         MatrixHandler {
             db: db,
             comms: comms,
             transport: Box::new(transport),
+            templates: templates,
         }
     }
     pub async fn start(self) {
-        loop {
-            let _ = self.local().await.map_err(|err| {
-                error!("{}", err);
-                err
-            });
+        // Not critical, rooms are created as needed.
+        let _ = self.import_direct_rooms().await.map_err(|err| {
+            warn!("Failed to import direct rooms: {}", err);
+        });
+
+        let reaper = async {
+            let mut backlog = 0;
+            loop {
+                match self.reap_rooms().await {
+                    Ok(count) => {
+                        if count != backlog {
+                            info!("Matrix rooms waiting to be left: {}", count);
+                            backlog = count;
+                        }
+                    }
+                    Err(err) => error!("{}", err),
+                }
+
+                time::delay_for(Duration::from_secs(REAPER_INTERVAL)).await;
+            }
+        };
+
+        let lookups = async {
+            loop {
+                let _ = self
+                    .retry_user_lookups()
+                    .await
+                    .map_err(|err| error!("{}", err));
+
+                time::delay_for(Duration::from_secs(REAPER_INTERVAL)).await;
+            }
+        };
+
+        let handler = async {
+            loop {
+                let _ = self.local().await.map_err(|err| {
+                    error!("{}", err);
+                    err
+                });
+            }
+        };
+
+        futures::join!(reaper, lookups, handler);
+    }
+    /// Leaves the queued rooms and returns the number of remaining rooms.
+    async fn reap_rooms(&self) -> Result<usize> {
+        for (room_id, attempts) in self.db.select_rooms_to_leave().await? {
+            let err = match self.transport.leave_room(&room_id).await {
+                Ok(()) => {
+                    debug!("Left room: {}", room_id.as_str());
+                    self.db.remove_room_to_leave(&room_id).await?;
+                    self.db.remove_room_id(&room_id).await?;
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            if let Some(delay) = rate_limit_delay(&err) {
+                debug!(
+                    "Rate limited while leaving rooms, retrying in {} seconds",
+                    delay.as_secs()
+                );
+
+                self.db
+                    .postpone_room_to_leave(&room_id, delay.as_secs().max(1))
+                    .await?;
+
+                // Any further request would be rate limited, too.
+                break;
+            }
+
+            if attempts + 1 >= MAX_LEAVE_ATTEMPTS {
+                warn!("Giving up on leaving room {}: {}", room_id.as_str(), err);
+                self.db.remove_room_to_leave(&room_id).await?;
+            } else {
+                let delay = (LEAVE_BACKOFF_BASE << attempts.min(16)).min(LEAVE_BACKOFF_MAX);
+                warn!(
+                    "Failed to leave room {}, retrying in {} seconds: {}",
+                    room_id.as_str(),
+                    delay,
+                    err
+                );
+
+                self.db.postpone_room_to_leave(&room_id, delay).await?;
+            }
         }
+
+        self.db.count_rooms_to_leave().await
     }
     async fn local(&self) -> Result<()> {
         use CommsMessage::*;
@@ -225,10 +740,22 @@ impl MatrixHandler {
             LeaveRoom { net_account } => {
                 if let Some(room_id) = self.db.select_room_id(&net_account).await? {
                     self.transport
-                        .send_message(&room_id, "Bye bye!".to_string())
+                        .send_message(&room_id, "Bye bye!".into())
                         .await?;
-                    debug!("Leaving room: {}", room_id.as_str());
-                    let _ = self.transport.leave_room(&room_id).await;
+
+                    // Direct message rooms are kept open for future
+                    // verifications of the same user.
+                    let is_direct = self
+                        .db
+                        .select_direct_rooms()
+                        .await?
+                        .iter()
+                        .any(|(_, direct_room_id)| direct_room_id == &room_id);
+
+                    if !is_direct {
+                        debug!("Queuing room to leave: {}", room_id.as_str());
+                        self.db.insert_room_to_leave(&room_id).await?;
+                    }
                 } else {
                     debug!(
                         "No active Matrix room found for address {}",
@@ -261,14 +788,27 @@ impl MatrixHandler {
 
         Ok(())
     }
-    async fn init_room_id(&self, net_account: &NetAccount, account: &Account) -> Result<RoomId> {
+    /// Returns the room with the user, if the user can be contacted. If the
+    /// homeserver of the user is unreachable, the verification is retried
+    /// later.
+    async fn init_room_id(
+        &self,
+        net_account: &NetAccount,
+        account: &Account,
+    ) -> Result<Option<RoomId>> {
         // If a room already exists, don't create a new one.
         let room_id = if let Some(room_id) = self.db.select_room_id(&net_account).await? {
             room_id
+        } else if let Some(room_id) = self.reuse_direct_room(net_account, account).await? {
+            debug!("Reusing direct room with {}", account.as_str());
+            room_id
         } else {
-            // When the UserId is invalid, even though it can be successfully
-            // converted, creating a room seems to block forever here. So we
-            // just set a timeout and abort if exceeded.
+            if !self.lookup_user(net_account, account).await? {
+                return Ok(None);
+            }
+
+            // Creating a room might still block if the homeserver becomes
+            // unreachable in the meantime, so the lookup is retried later.
             if let Ok(room_id) = time::timeout(Duration::from_secs(20), async {
                 debug!("Connecting to {}", account.as_str());
 
@@ -278,8 +818,17 @@ impl MatrixHandler {
                     .try_into()
                     .map_err(|err| MatrixError::InvalidUserId(failure::Error::from(err)))?];
 
+                // Users' clients commonly expect DMs to be encrypted. Messages
+                // are encrypted/decrypted transparently by the client.
+                let initial_state = [AnyInitialStateEvent::RoomEncryption(InitialStateEvent {
+                    content: EncryptionEventContent::new(EventEncryptionAlgorithm::MegolmV1AesSha2),
+                    state_key: String::new(),
+                })];
+
                 let mut request = Request::default();
                 request.invite = &to_invite;
+                request.initial_state = &initial_state;
+                request.is_direct = true;
                 request.name = Some("W3F Registrar Verification");
                 request.visibility = Visibility::Private;
 
@@ -297,36 +846,218 @@ impl MatrixHandler {
                     .await
                     .map_err(|err| MatrixError::Database(err.into()))?;
 
+                self.db
+                    .insert_direct_room(account, &resp.room_id)
+                    .await
+                    .map_err(|err| MatrixError::Database(err.into()))?;
+
                 StdResult::<_, MatrixError>::Ok(resp.room_id)
             })
             .await
             {
-                // Mark the account as valid.
-                self.db
-                    .set_account_status(&net_account, &AccountType::Matrix, &AccountStatus::Valid)
-                    .await?;
+                let room_id = room_id?;
 
-                room_id?
+                // Not critical, the rooms are tracked in the database.
+                let _ = self.update_direct_rooms().await.map_err(|err| {
+                    warn!("Failed to update direct rooms: {}", err);
+                });
+
+                // The account is marked as valid once the user joins the room.
+                room_id
             } else {
                 debug!("Failed to connect to account: {}", account.as_str());
 
-                // Mark the account as invalid.
-                self.db
-                    .set_account_status(&net_account, &AccountType::Matrix, &AccountStatus::Invalid)
+                self.postpone_user_lookup(net_account, account).await?;
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(room_id))
+    }
+    /// Checks whether the user exists before creating a room. Returns `false`
+    /// if the user cannot be contacted (yet).
+    async fn lookup_user(&self, net_account: &NetAccount, account: &Account) -> Result<bool> {
+        let user_id: UserId = account
+            .as_str()
+            .try_into()
+            .map_err(|err| MatrixError::InvalidUserId(failure::Error::from(err)))?;
+
+        match self.transport.lookup_user(&user_id).await? {
+            UserLookup::Exists => {
+                self.db.remove_user_lookup(net_account, account).await?;
+                Ok(true)
+            }
+            UserLookup::NotFound => {
+                debug!("User {} does not exist", account.as_str());
+
+                self.db.remove_user_lookup(net_account, account).await?;
+                self.set_invalid(net_account, &InvalidReason::NotFound)
                     .await?;
+                Ok(false)
+            }
+            UserLookup::Unreachable => {
+                self.postpone_user_lookup(net_account, account).await?;
+                Ok(false)
+            }
+        }
+    }
+    async fn postpone_user_lookup(
+        &self,
+        net_account: &NetAccount,
+        account: &Account,
+    ) -> Result<()> {
+        let attempts = self
+            .db
+            .postpone_user_lookup(net_account, account, LOOKUP_RETRY_INTERVAL)
+            .await?;
 
-                return Err(MatrixError::JoinRoomTimeout(account.clone()))?;
+        if attempts >= MAX_LOOKUP_ATTEMPTS {
+            warn!(
+                "Homeserver of {} is unreachable, marking account as invalid",
+                account.as_str()
+            );
+
+            self.db.remove_user_lookup(net_account, account).await?;
+            self.set_invalid(net_account, &InvalidReason::HomeserverUnreachable)
+                .await?;
+        } else {
+            info!(
+                "Homeserver of {} is unreachable, retrying later ({}/{})",
+                account.as_str(),
+                attempts,
+                MAX_LOOKUP_ATTEMPTS
+            );
+        }
+
+        Ok(())
+    }
+    async fn set_invalid(&self, net_account: &NetAccount, reason: &InvalidReason) -> Result<()> {
+        self.db
+            .set_account_status(
+                net_account,
+                &AccountType::Matrix,
+                &AccountStatus::Invalid(Some(reason.clone())),
+            )
+            .await?;
+
+        self.comms.notify_status_change(net_account.clone());
+        Ok(())
+    }
+    /// Retries the verification of users whose homeserver was unreachable.
+    async fn retry_user_lookups(&self) -> Result<()> {
+        for (net_account, account) in self.db.select_user_lookups().await? {
+            // The identity might have been updated or removed in the meantime.
+            if !self
+                .db
+                .select_addresses(&net_account)
+                .await?
+                .contains(&account)
+            {
+                self.db.remove_user_lookup(&net_account, &account).await?;
+                continue;
             }
+
+            debug!("Retrying to contact {}", account.as_str());
+            let _ = self
+                .handle_account_verification(net_account, account)
+                .await
+                .map_err(|err| error!("{}", err));
+        }
+
+        Ok(())
+    }
+    /// Returns the existing direct message room with the user, if the room
+    /// can still be used. The user is invited again if they left the room.
+    async fn reuse_direct_room(
+        &self,
+        net_account: &NetAccount,
+        account: &Account,
+    ) -> Result<Option<RoomId>> {
+        let room_id = if let Some(room_id) = self.db.select_direct_room(account).await? {
+            room_id
+        } else {
+            return Ok(None);
         };
 
-        Ok(room_id)
+        let user_id: UserId = account
+            .as_str()
+            .try_into()
+            .map_err(|err| MatrixError::InvalidUserId(failure::Error::from(err)))?;
+
+        let my_user_id = self.transport.user_id().await?;
+
+        let members = self
+            .transport
+            .joined_members(&room_id)
+            .await
+            .unwrap_or_else(|err| {
+                debug!("Failed to fetch members of {}: {}", room_id.as_str(), err);
+                vec![]
+            });
+
+        // Nobody can be invited if the bot itself is no longer in the room.
+        if !members.contains(&my_user_id) {
+            debug!("Direct room {} is no longer available", room_id.as_str());
+            self.db.remove_direct_room(account).await?;
+            return Ok(None);
+        }
+
+        self.db.insert_room_id(net_account, &room_id).await?;
+
+        if members.contains(&user_id) {
+            // Mark the account as valid.
+            self.db
+                .set_account_status(net_account, &AccountType::Matrix, &AccountStatus::Valid)
+                .await?;
+
+            self.comms.notify_status_change(net_account.clone());
+        } else {
+            debug!("Inviting {} to {}", account.as_str(), room_id.as_str());
+
+            // The invite fails if the user is still invited.
+            let _ = self
+                .transport
+                .invite_user(&room_id, &user_id)
+                .await
+                .map_err(|err| {
+                    debug!("Failed to invite {}: {}", account.as_str(), err);
+                });
+        }
+
+        Ok(Some(room_id))
+    }
+    async fn update_direct_rooms(&self) -> Result<()> {
+        let rooms = self.db.select_direct_rooms().await?;
+        self.transport.set_direct_rooms(&rooms).await
+    }
+    /// Adds the direct rooms of the `m.direct` account data which are not
+    /// tracked in the database yet, e.g. rooms created by a previous
+    /// deployment or another client.
+    async fn import_direct_rooms(&self) -> Result<()> {
+        let mut imported = 0;
+        for (account, room_id) in self.transport.direct_rooms().await? {
+            if self.db.select_direct_room(&account).await?.is_none() {
+                self.db.insert_direct_room(&account, &room_id).await?;
+                imported += 1;
+            }
+        }
+
+        if imported > 0 {
+            info!("Imported {} direct room(-s)", imported);
+        }
+
+        Ok(())
     }
     async fn handle_account_verification(
         &self,
         net_account: NetAccount,
         account: Account,
     ) -> Result<()> {
-        let room_id = self.init_room_id(&net_account, &account).await?;
+        let room_id = if let Some(room_id) = self.init_room_id(&net_account, &account).await? {
+            room_id
+        } else {
+            return Ok(());
+        };
 
         let challenge_data = self
             .db
@@ -334,9 +1065,8 @@ impl MatrixHandler {
             .await?;
 
         debug!("Sending instructions to user");
-        let verifier = Verifier2::new(&challenge_data);
         self.transport
-            .send_message(&room_id, verifier.init_message_builder(true))
+            .send_message(&room_id, self.instructions(&challenge_data, true))
             .await
             .map_err(|err| MatrixError::SendMessage(err.into()).into())
             .map(|_| ())
@@ -360,65 +1090,299 @@ impl MatrixHandler {
         debug!("Reacting to received message");
 
         if let SyncRoom::Joined(room) = room {
-            debug!("Search for address based on RoomId");
+            let room_id = room.read().await.room_id.clone();
 
-            let room_id = &room.read().await.room_id;
-            let account = Account::from(event.sender().as_str());
+            // Not critical, the message gets processed anyway.
+            let _ = self
+                .transport
+                .read_receipt(&room_id, event.event_id())
+                .await
+                .map_err(|err| debug!("Failed to send read receipt: {}", err));
 
-            debug!("Fetching challenge data");
-            let challenge_data = self
-                .db
-                .select_challenge_data(&account, &AccountType::Matrix)
+            let _ = self
+                .transport
+                .typing(&room_id, true)
+                .await
+                .map_err(|err| debug!("Failed to send typing notification: {}", err));
+
+            let processing = self.process_message(&room_id, event);
+            futures::pin_mut!(processing);
+
+            // Answer within a bounded time, even if the processing itself
+            // takes longer.
+            let delay = Duration::from_secs(PROCESSING_NOTE_DELAY);
+            let res = match time::timeout(delay, &mut processing).await {
+                Ok(res) => res,
+                Err(_) => {
+                    debug!("Processing message takes longer than expected, notifying...");
+
+                    let _ = self
+                        .transport
+                        .send_message(
+                            &room_id,
+                            "Your message is being processed, please wait a moment...".into(),
+                        )
+                        .await
+                        .map_err(|err| {
+                            debug!("Failed to send processing note: {}", err);
+                        });
+
+                    processing.await
+                }
+            };
+
+            let _ = self
+                .transport
+                .typing(&room_id, false)
+                .await
+                .map_err(|err| debug!("Failed to send typing notification: {}", err));
+
+            res?;
+        } else {
+            warn!("Received an message from an un-joined room");
+        }
+
+        Ok(())
+    }
+    async fn process_message<T: EventExtract>(&self, room_id: &RoomId, event: &T) -> Result<()> {
+        let account = Account::from(event.sender().as_str());
+
+        debug!("Fetching challenge data");
+        let challenge_data = self
+            .db
+            .select_challenge_data(&account, &AccountType::Matrix)
+            .await?;
+
+        if challenge_data.is_empty() {
+            warn!("No challenge data found for {}", account.as_str());
+            return Err(MatrixError::ChallengeDataNotFound(account.clone()).into());
+        }
+
+        let mut verifier = Verifier2::new(&challenge_data);
+
+        // Fetch the text message from the event.
+        let msg_body = if let Ok(msg_body) = event.message() {
+            msg_body
+        } else {
+            debug!(
+                "Didn't receive a text message from {}, notifying...",
+                event.sender().as_str()
+            );
+
+            self.transport
+                .send_message(
+                    room_id,
+                    "Please send the signature directly as a text message.".into(),
+                )
+                .await
+                .map_err(|err| MatrixError::SendMessage(err.into()))?;
+
+            return Ok(());
+        };
+
+        if let Some(command) = Command::parse(&msg_body) {
+            debug!(
+                "Received command {:?} from {}",
+                command,
+                event.sender().as_str()
+            );
+
+            let response = self
+                .handle_command(command, room_id, &challenge_data)
                 .await?;
 
-            if challenge_data.is_empty() {
-                warn!("No challenge data found for {}", account.as_str());
-                return Err(MatrixError::ChallengeDataNotFound(account.clone()).into());
+            self.transport
+                .send_message(room_id, response)
+                .await
+                .map_err(|err| MatrixError::SendMessage(err.into()))?;
+
+            return Ok(());
+        }
+
+        debug!("Verifying message: {}", msg_body);
+        verifier.verify(&msg_body);
+
+        // Update challenge statuses and notify manager
+        verification_handler(&verifier, &self.db, &self.comms, &AccountType::Matrix).await?;
+
+        // Inform user about the current state of the verification
+        self.transport
+            .send_message(room_id, verifier.response_message_builder())
+            .await
+            .map_err(|err| MatrixError::SendMessage(err.into()))?;
+
+        Ok(())
+    }
+    async fn handle_command(
+        &self,
+        command: Command,
+        room_id: &RoomId,
+        challenge_data: &[(NetworkAddress, Challenge)],
+    ) -> Result<FormattedMessage> {
+        let response = match command {
+            Command::Status => {
+                let mut statuses = vec![];
+                for (network_address, _) in challenge_data {
+                    statuses.push((
+                        network_address.address().clone(),
+                        self.db
+                            .select_account_statuses(network_address.address())
+                            .await?,
+                    ));
+                }
+
+                status_message(&statuses)
             }
+            Command::Resend => self.instructions(challenge_data, false),
+            Command::Help => help_message().into(),
+            Command::Cancel => {
+                // Only cancel the verification of the addresses which are
+                // verified in this room, the same Matrix ID might be specified
+                // in other identities, too.
+                let room_accounts = self.db.select_net_accounts_by_room_id(room_id).await?;
+                let to_cancel = challenge_data
+                    .iter()
+                    .map(|(network_address, _)| network_address.address())
+                    .filter(|net_account| room_accounts.contains(net_account))
+                    .collect::<Vec<&NetAccount>>();
 
-            let mut verifier = Verifier2::new(&challenge_data);
+                if to_cancel.is_empty() {
+                    return Ok("There is no verification to cancel in this room.".into());
+                }
 
-            // Fetch the text message from the event.
-            let msg_body = if let Ok(msg_body) = event.message() {
-                msg_body
-            } else {
+                let mut message = String::from("The verification has been cancelled for:\n");
+
+                for net_account in to_cancel {
+                    message.push_str("\n- Address:\n");
+                    message.push_str(net_account.as_str());
+
+                    self.comms.notify_cancel(net_account.clone());
+                }
+
+                message.push_str(
+                    "\n\nNo judgement has been given. Please issue a new `requestJudgement` \
+                    extrinsic in order to start the verification again.",
+                );
+
+                message.into()
+            }
+        };
+
+        Ok(response)
+    }
+    /// Prefers the configured template, if any.
+    fn instructions(
+        &self,
+        challenge_data: &[(NetworkAddress, Challenge)],
+        send_context: bool,
+    ) -> FormattedMessage {
+        self.templates
+            .render(
+                &AccountType::Matrix,
+                TemplateKind::Instructions,
+                challenge_data,
+            )
+            .map(|rendered| rendered.into())
+            .unwrap_or_else(|| Verifier2::new(challenge_data).init_message_builder(send_context))
+    }
+    async fn handle_membership_change(
+        &self,
+        room_id: &RoomId,
+        event: &SyncStateEvent<MemberEventContent>,
+    ) -> Result<()> {
+        // Only consider changes made by users to their own membership, which
+        // excludes invites and kicks by the bot.
+        if event.state_key != event.sender.as_str()
+            || event.sender == self.transport.user_id().await?
+        {
+            return Ok(());
+        }
+
+        let account = Account::from(event.sender.as_str());
+        let prev_membership = event
+            .prev_content
+            .as_ref()
+            .map(|content| &content.membership);
+
+        match (prev_membership, &event.content.membership) {
+            // E.g. a display name change.
+            (Some(MembershipState::Join), MembershipState::Join) => {}
+            (_, MembershipState::Join) => {
+                debug!("{} joined room {}", account.as_str(), room_id.as_str());
+
+                self.set_room_account_status(room_id, &AccountStatus::Valid)
+                    .await?;
+            }
+            (Some(MembershipState::Invite), MembershipState::Leave) => {
                 debug!(
-                    "Didn't receive a text message from {}, notifying...",
-                    event.sender().as_str()
+                    "{} rejected the invite to room {}",
+                    account.as_str(),
+                    room_id.as_str()
                 );
 
-                self.transport
-                    .send_message(
-                        room_id,
-                        "Please send the signature directly as a text message.".to_string(),
-                    )
-                    .await
-                    .map_err(|err| MatrixError::SendMessage(err.into()))?;
+                // Other channels inform the user about the invalid account.
+                for net_account in self.db.select_net_accounts_by_room_id(room_id).await? {
+                    self.set_invalid(&net_account, &InvalidReason::InviteRejected)
+                        .await?;
+                }
 
-                return Ok(());
-            };
+                self.cleanup_room(room_id).await?;
+            }
+            (Some(MembershipState::Join), MembershipState::Leave) => {
+                debug!("{} left room {}", account.as_str(), room_id.as_str());
 
-            debug!("Verifying message: {}", msg_body);
-            verifier.verify(&msg_body);
+                let challenge_data = self
+                    .db
+                    .select_challenge_data(&account, &AccountType::Matrix)
+                    .await?;
 
-            // Update challenge statuses and notify manager
-            verification_handler(&verifier, &self.db, &self.comms, &AccountType::Matrix).await?;
+                if challenge_data.is_empty() {
+                    self.cleanup_room(room_id).await?;
+                } else {
+                    // The verification is still pending. Rejecting the invite
+                    // marks the account as invalid.
+                    debug!("Inviting {} to {}", account.as_str(), room_id.as_str());
 
-            // Inform user about the current state of the verification
-            self.transport
-                .send_message(room_id, verifier.response_message_builder())
-                .await
-                .map_err(|err| MatrixError::SendMessage(err.into()))?;
-        } else {
-            warn!("Received an message from an un-joined room");
+                    self.transport.invite_user(room_id, &event.sender).await?;
+                }
+            }
+            _ => {}
         }
 
         Ok(())
     }
+    async fn set_room_account_status(
+        &self,
+        room_id: &RoomId,
+        status: &AccountStatus,
+    ) -> Result<()> {
+        for net_account in self.db.select_net_accounts_by_room_id(room_id).await? {
+            self.db
+                .set_account_status(&net_account, &AccountType::Matrix, status)
+                .await?;
+
+            self.comms.notify_status_change(net_account);
+        }
+
+        Ok(())
+    }
+    async fn cleanup_room(&self, room_id: &RoomId) -> Result<()> {
+        debug!("Queuing room to leave: {}", room_id.as_str());
+
+        self.db.remove_room_id(room_id).await?;
+        self.db.insert_room_to_leave(room_id).await?;
+
+        // Not critical, the rooms are tracked in the database.
+        let _ = self.update_direct_rooms().await.map_err(|err| {
+            warn!("Failed to update direct rooms: {}", err);
+        });
+
+        Ok(())
+    }
     async fn handle_invalid_account_notification(
         &self,
         net_account: NetAccount,
-        accounts: Vec<(AccountType, Account)>,
+        accounts: Vec<(AccountType, Account, Option<InvalidReason>)>,
     ) -> Result<()> {
         let account = self
             .db
@@ -428,7 +1392,12 @@ impl MatrixHandler {
                 net_account.as_str().to_string(),
             ))?;
 
-        let room_id = self.init_room_id(&net_account, &account).await?;
+        let room_id = if let Some(room_id) = self.init_room_id(&net_account, &account).await? {
+            room_id
+        } else {
+            warn!("Cannot inform {} about invalid accounts", account.as_str());
+            return Ok(());
+        };
 
         // Check for any display name violations (optional).
         let violations = self.db.select_display_name_violations(&net_account).await?;
@@ -444,6 +1413,8 @@ impl MatrixHandler {
 
 #[async_trait]
 impl EventEmitter for MatrixHandler {
+    // Messages of encrypted rooms are decrypted by the client before being
+    // passed on, so those are handled the same way as plain messages.
     async fn on_room_message(&self, room: SyncRoom, event: &SyncMessageEvent<MessageEventContent>) {
         let _ = self
             .handle_incoming_messages::<SyncMessageEvent<MessageEventContent>>(room, event)
@@ -452,4 +1423,225 @@ impl EventEmitter for MatrixHandler {
                 error!("{}", err);
             });
     }
+    async fn on_room_member(&self, room: SyncRoom, event: &SyncStateEvent<MemberEventContent>) {
+        if let SyncRoom::Joined(room) = room {
+            let room_id = room.read().await.room_id.clone();
+
+            let _ = self
+                .handle_membership_change(&room_id, event)
+                .await
+                .map_err(|err| {
+                    error!("{}", err);
+                });
+        }
+    }
+}
+
+/// Returns the error kind if the homeserver responded with an error.
+fn error_kind(err: &failure::Error) -> Option<&matrix_sdk::api::error::ErrorKind> {
+    use matrix_sdk::{FromHttpResponseError, ServerError};
+
+    match err.downcast_ref::<matrix_sdk::Error>()? {
+        matrix_sdk::Error::RumaResponse(FromHttpResponseError::Http(ServerError::Known(err))) => {
+            Some(&err.kind)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the HTTP status code if the homeserver responded with an error.
+fn error_status(err: &failure::Error) -> Option<u16> {
+    use matrix_sdk::{FromHttpResponseError, ServerError};
+
+    match err.downcast_ref::<matrix_sdk::Error>()? {
+        matrix_sdk::Error::RumaResponse(FromHttpResponseError::Http(ServerError::Known(err))) => {
+            Some(err.status_code.as_u16())
+        }
+        _ => None,
+    }
+}
+
+/// Returns the delay requested by the homeserver if the request was rate
+/// limited (`M_LIMIT_EXCEEDED`).
+fn rate_limit_delay(err: &failure::Error) -> Option<Duration> {
+    use matrix_sdk::api::error::ErrorKind;
+
+    match error_kind(err)? {
+        ErrorKind::LimitExceeded { retry_after_ms } => {
+            Some(retry_after_ms.unwrap_or(Duration::from_secs(LEAVE_BACKOFF_BASE)))
+        }
+        _ => None,
+    }
+}
+
+fn help_message() -> String {
+    String::from(
+        "\
+        This is the Web3 Registrar verification service. In order to verify \
+        this account, sign the challenge with the private key of the \
+        corresponding address and send the resulting signature as a text \
+        message, without any additional data.\n\n\
+        The following commands are available:\n\n\
+        - status: show the verification progress of each account\n\
+        - resend: send the challenge again\n\
+        - cancel: stop the verification, without giving a judgement\n\
+        - help: show this message\n\n\
+        Refer to the Polkadot Wiki guide https://wiki.polkadot.network/",
+    )
+}
+
+fn status_message(
+    statuses: &[(NetAccount, Vec<(AccountType, Account, AccountStatus)>)],
+) -> FormattedMessage {
+    let mut message = MessageBuilder::new();
+    message.bold("Current verification status:").text("\n");
+
+    for (net_account, account_statuses) in statuses {
+        message
+            .text("\n- Address:\n")
+            .code_block(net_account.as_str())
+            .text("\n");
+
+        for (account_ty, account, status) in account_statuses {
+            message.text(&format!(
+                "  * \"{}\" ({}): ",
+                account.as_str(),
+                account_ty.to_string()
+            ));
+
+            match status {
+                AccountStatus::Unknown => message.bold("pending"),
+                AccountStatus::Valid => message.bold("reachable"),
+                AccountStatus::Invalid(Some(reason)) => message
+                    .bold("invalid")
+                    .text(", ")
+                    .text(invalid_reason_explanation(account_ty, reason)),
+                AccountStatus::Invalid(None) => message.bold("could not be reached"),
+                AccountStatus::Notified => message.bold("notified"),
+            };
+
+            message.text("\n");
+        }
+    }
+
+    message.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("status"), Some(Command::Status));
+        assert_eq!(Command::parse(" Resend\n"), Some(Command::Resend));
+        assert_eq!(Command::parse("!help"), Some(Command::Help));
+        assert_eq!(Command::parse("CANCEL"), Some(Command::Cancel));
+        assert_eq!(Command::parse("0x1234abcd"), None);
+        assert_eq!(Command::parse("status please"), None);
+    }
+
+    #[test]
+    fn normalize_matrix_ids() {
+        let expected = Some(Account::from("@alice:matrix.org"));
+
+        for id in &[
+            "@alice:matrix.org",
+            "alice:matrix.org",
+            " @alice:Matrix.org\n",
+            "https://matrix.to/#/@alice:matrix.org",
+            "https://matrix.to/#/%40alice%3Amatrix.org?via=matrix.org",
+        ] {
+            assert_eq!(normalize_matrix_id(&Account::from(*id)), expected);
+        }
+
+        // The localpart is case-sensitive.
+        assert_eq!(
+            normalize_matrix_id(&Account::from("@Alice:MATRIX.org")),
+            Some(Account::from("@Alice:matrix.org"))
+        );
+
+        for id in &[
+            "",
+            "alice",
+            "@alice",
+            "@ali ce:matrix.org",
+            "alice@example.com",
+        ] {
+            assert_eq!(normalize_matrix_id(&Account::from(*id)), None);
+        }
+    }
+
+    #[test]
+    fn restore_and_relogin() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let session = || -> Option<Session> {
+                serde_json::from_str(
+                    r#"{
+                        "access_token": "token",
+                        "user_id": "@registrar:matrix.org",
+                        "device_id": "DEVICE"
+                    }"#,
+                )
+                .ok()
+            };
+
+            // Returns the logins, with the device ID, that were performed.
+            async fn run(
+                session: Option<Session>,
+                restored: bool,
+                is_valid: Result<bool>,
+            ) -> (Result<()>, Vec<Option<String>>) {
+                let logins = RefCell::new(vec![]);
+                let res = restore_session(
+                    session,
+                    |_| async move {
+                        if restored {
+                            Ok(())
+                        } else {
+                            Err(failure::err_msg("corrupted session"))
+                        }
+                    },
+                    move || async move { is_valid },
+                    |device_id| {
+                        logins.borrow_mut().push(device_id);
+                        async { Ok(()) }
+                    },
+                )
+                .await;
+
+                (res, logins.into_inner())
+            }
+
+            let device = Some(String::from("DEVICE"));
+
+            // No session persisted.
+            let (res, logins) = run(None, true, Ok(true)).await;
+            assert!(res.is_ok());
+            assert_eq!(logins, vec![None]);
+
+            // The session is restored and still valid.
+            let (res, logins) = run(session(), true, Ok(true)).await;
+            assert!(res.is_ok());
+            assert!(logins.is_empty());
+
+            // The access token was invalidated.
+            let (res, logins) = run(session(), true, Ok(false)).await;
+            assert!(res.is_ok());
+            assert_eq!(logins, vec![device.clone()]);
+
+            // The session cannot be restored.
+            let (res, logins) = run(session(), false, Ok(true)).await;
+            assert!(res.is_ok());
+            assert_eq!(logins, vec![device.clone()]);
+
+            // Other errors do not cause a login.
+            let (res, logins) = run(session(), true, Err(failure::err_msg("unreachable"))).await;
+            assert!(res.is_err());
+            assert!(logins.is_empty());
+        });
+    }
 }
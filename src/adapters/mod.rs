@@ -1,9 +1,19 @@
 mod display_name;
 pub(crate) mod email;
 mod matrix;
+mod oauth;
 pub(crate) mod twitter;
+mod twitter_v2;
 
 pub use display_name::{DisplayNameHandler, VIOLATIONS_CAP};
-pub use email::{EmailHandler, EmailId, EmailTransport, SmtpImapClientBuilder};
-pub use matrix::{EventExtract, MatrixClient, MatrixHandler, MatrixTransport};
-pub use twitter::{Twitter, TwitterBuilder, TwitterHandler, TwitterId, TwitterTransport};
+pub use email::{
+    EmailHandler, EmailId, EmailTransport, EmailVerificationMode, SmtpImapClientBuilder,
+};
+pub use matrix::{
+    normalize_matrix_id, EventExtract, MatrixClient, MatrixHandler, MatrixTransport, UserLookup,
+};
+pub use twitter::{
+    normalize_twitter_handle, Twitter, TwitterBuilder, TwitterClient, TwitterHandler, TwitterId,
+    TwitterTransport, TwitterUser,
+};
+pub use twitter_v2::{TwitterToken, TwitterV2, TwitterV2Builder};
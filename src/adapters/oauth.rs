@@ -0,0 +1,271 @@
+use crate::primitives::{unix_time, Challenge};
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+
+/// The only supported signature method.
+pub const SIGNATURE_METHOD: &str = "HMAC-SHA1";
+
+/// Signs requests according to OAuth 1.0a (RFC 5849), using HMAC-SHA1.
+#[derive(Debug, Clone)]
+pub struct OAuth1Signer {
+    consumer_key: String,
+    consumer_secret: String,
+    token: String,
+    token_secret: String,
+    version: String,
+}
+
+impl OAuth1Signer {
+    pub fn new(
+        consumer_key: String,
+        consumer_secret: String,
+        token: String,
+        token_secret: String,
+    ) -> Self {
+        OAuth1Signer {
+            consumer_key: consumer_key,
+            consumer_secret: consumer_secret,
+            token: token,
+            token_secret: token_secret,
+            version: "1.0".to_string(),
+        }
+    }
+    pub fn version(mut self, version: f64) -> Self {
+        self.version = format!("{:.1}", version);
+        self
+    }
+    /// Returns the value of the `Authorization` header. The `url` must not
+    /// contain a query, all query and form parameters must be passed as
+    /// `params`, without encoding. JSON bodies are not signed.
+    pub fn authorization(&self, method: &str, url: &str, params: &[(&str, &str)]) -> String {
+        self.authorization_with(
+            method,
+            url,
+            params,
+            Challenge::gen_random().as_str(),
+            unix_time(),
+        )
+    }
+    fn authorization_with(
+        &self,
+        method: &str,
+        url: &str,
+        params: &[(&str, &str)],
+        nonce: &str,
+        timestamp: u64,
+    ) -> String {
+        let timestamp = timestamp.to_string();
+        let mut oauth_params = vec![
+            ("oauth_consumer_key", self.consumer_key.as_str()),
+            ("oauth_nonce", nonce),
+            ("oauth_signature_method", SIGNATURE_METHOD),
+            ("oauth_timestamp", timestamp.as_str()),
+            ("oauth_token", self.token.as_str()),
+            ("oauth_version", self.version.as_str()),
+        ];
+
+        let mut all_params = oauth_params.clone();
+        all_params.extend_from_slice(params);
+
+        let sig = self.signature(&base_string(method, url, &all_params));
+        oauth_params.push(("oauth_signature", sig.as_str()));
+        oauth_params.sort();
+
+        let fields = oauth_params
+            .iter()
+            .map(|(name, val)| format!("{}=\"{}\"", percent_encode(name), percent_encode(val)))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        format!("OAuth {}", fields)
+    }
+    fn signature(&self, base: &str) -> String {
+        let sign_key = format!(
+            "{}&{}",
+            percent_encode(&self.consumer_secret),
+            percent_encode(&self.token_secret)
+        );
+
+        let mut mac: Hmac<Sha1> = Hmac::new_varkey(sign_key.as_bytes()).unwrap();
+        mac.update(base.as_bytes());
+
+        base64::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// Creates the signature base string, see RFC 5849, section 3.4.1.
+fn base_string(method: &str, url: &str, params: &[(&str, &str)]) -> String {
+    // Parameters are sorted by their encoded names, then by their encoded
+    // values.
+    let mut encoded = params
+        .iter()
+        .map(|(name, val)| (percent_encode(name), percent_encode(val)))
+        .collect::<Vec<(String, String)>>();
+
+    encoded.sort();
+
+    let params = encoded
+        .iter()
+        .map(|(name, val)| format!("{}={}", name, val))
+        .collect::<Vec<String>>()
+        .join("&");
+
+    format!(
+        "{}&{}&{}",
+        method.to_uppercase(),
+        percent_encode(url),
+        percent_encode(&params)
+    )
+}
+
+/// Percent-encodes everything except the unreserved characters of RFC 3986,
+/// as required by RFC 5849, section 3.6.
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// Encodes the parameters as a query string or form body, using the same
+/// encoding as for the signature.
+pub fn encode_params(params: &[(&str, &str)]) -> String {
+    params
+        .iter()
+        .map(|(name, val)| format!("{}={}", percent_encode(name), percent_encode(val)))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_encoding() {
+        assert_eq!(
+            percent_encode("Ladies + Gentlemen"),
+            "Ladies%20%2B%20Gentlemen"
+        );
+        assert_eq!(
+            percent_encode("An encoded string!"),
+            "An%20encoded%20string%21"
+        );
+        assert_eq!(
+            percent_encode("Dogs, Cats & Mice"),
+            "Dogs%2C%20Cats%20%26%20Mice"
+        );
+        assert_eq!(percent_encode("☃"), "%E2%98%83");
+        assert_eq!(percent_encode("a-b.c_d~e"), "a-b.c_d~e");
+    }
+
+    #[test]
+    fn twitter_base_string() {
+        // Example from the Twitter documentation "Creating a signature".
+        let base = base_string(
+            "post",
+            "https://api.twitter.com/1.1/statuses/update.json",
+            &[
+                (
+                    "status",
+                    "Hello Ladies + Gentlemen, a signed OAuth request!",
+                ),
+                ("include_entities", "true"),
+                ("oauth_consumer_key", "xvz1evFS4wEEPTGEFPHBog"),
+                ("oauth_nonce", "kYjzVBB8Y0ZFabxSWbWovY3uYSQ2pTgmZeNu2VS4cg"),
+                ("oauth_signature_method", "HMAC-SHA1"),
+                ("oauth_timestamp", "1318622958"),
+                (
+                    "oauth_token",
+                    "370773112-GmHxMAgYyLbNEtIKZeRNFsMKPR9EyMZeS9weJAEb",
+                ),
+                ("oauth_version", "1.0"),
+            ],
+        );
+
+        assert_eq!(
+            base,
+            "POST&https%3A%2F%2Fapi.twitter.com%2F1.1%2Fstatuses%2Fupdate.json&\
+            include_entities%3Dtrue%26oauth_consumer_key%3Dxvz1evFS4wEEPTGEFPHBog%26\
+            oauth_nonce%3DkYjzVBB8Y0ZFabxSWbWovY3uYSQ2pTgmZeNu2VS4cg%26\
+            oauth_signature_method%3DHMAC-SHA1%26oauth_timestamp%3D1318622958%26\
+            oauth_token%3D370773112-GmHxMAgYyLbNEtIKZeRNFsMKPR9EyMZeS9weJAEb%26\
+            oauth_version%3D1.0%26status%3DHello%2520Ladies%2520%252B%2520Gentlemen\
+            %252C%2520a%2520signed%2520OAuth%2520request%2521"
+        );
+    }
+
+    #[test]
+    fn twitter_signature() {
+        // Example from the Twitter documentation "Creating a signature".
+        let signer = OAuth1Signer::new(
+            "xvz1evFS4wEEPTGEFPHBog".to_string(),
+            "kAcSOqF21Fu85e7zjz7ZN2U4ZRhfV3WpwPAoE3Z7kBw".to_string(),
+            "370773112-GmHxMAgYyLbNEtIKZeRNFsMKPR9EyMZeS9weJAEb".to_string(),
+            "LswwdoUaIvS8ltyTt5jkRh4J50vUPVVHtR2YPi5kE".to_string(),
+        );
+
+        let header = signer.authorization_with(
+            "POST",
+            "https://api.twitter.com/1.1/statuses/update.json",
+            &[
+                (
+                    "status",
+                    "Hello Ladies + Gentlemen, a signed OAuth request!",
+                ),
+                ("include_entities", "true"),
+            ],
+            "kYjzVBB8Y0ZFabxSWbWovY3uYSQ2pTgmZeNu2VS4cg",
+            1318622958,
+        );
+
+        assert_eq!(
+            header,
+            "OAuth oauth_consumer_key=\"xvz1evFS4wEEPTGEFPHBog\", \
+            oauth_nonce=\"kYjzVBB8Y0ZFabxSWbWovY3uYSQ2pTgmZeNu2VS4cg\", \
+            oauth_signature=\"hCtSmYh%2BiHYCEqBWrE7C7hYmtUk%3D\", \
+            oauth_signature_method=\"HMAC-SHA1\", \
+            oauth_timestamp=\"1318622958\", \
+            oauth_token=\"370773112-GmHxMAgYyLbNEtIKZeRNFsMKPR9EyMZeS9weJAEb\", \
+            oauth_version=\"1.0\""
+        );
+    }
+
+    #[test]
+    fn sign_request() {
+        // Example from the OAuth Core 1.0 specification, appendix A.5.
+        let signer = OAuth1Signer::new(
+            "dpf43f3p2l4k3l03".to_string(),
+            "kd94hf93k423kf44".to_string(),
+            "nnch734d00sl2jdk".to_string(),
+            "pfkkdhi9sl3r4s00".to_string(),
+        );
+
+        let header = signer.authorization_with(
+            "GET",
+            "http://photos.example.net/photos",
+            &[("file", "vacation.jpg"), ("size", "original")],
+            "kllo9940pd9333jh",
+            1191242096,
+        );
+
+        assert_eq!(
+            header,
+            "OAuth oauth_consumer_key=\"dpf43f3p2l4k3l03\", \
+            oauth_nonce=\"kllo9940pd9333jh\", \
+            oauth_signature=\"tR3%2BTy81lMeYAr%2FFid0kMTYa%2FWM%3D\", \
+            oauth_signature_method=\"HMAC-SHA1\", \
+            oauth_timestamp=\"1191242096\", \
+            oauth_token=\"nnch734d00sl2jdk\", \
+            oauth_version=\"1.0\""
+        );
+    }
+}
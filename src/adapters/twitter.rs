@@ -1,9 +1,15 @@
+use super::oauth::{encode_params, OAuth1Signer, SIGNATURE_METHOD};
+use super::twitter_v2::TwitterV2;
 use crate::comms::{CommsMessage, CommsVerifier};
 use crate::db::Database2;
-use crate::primitives::{unix_time, Account, AccountType, Challenge, NetAccount, Result};
+use crate::manager::{AccountStatus, InvalidReason};
+use crate::primitives::{
+    unix_time, Account, AccountType, Challenge, NetAccount, NetworkAddress, Result,
+};
+use crate::templates::{TemplateKind, Templates};
 use crate::verifier::{invalid_accounts_message, verification_handler, Verifier2};
-use reqwest::header::{self, HeaderValue};
-use reqwest::{Client, Request};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{Client, Request, StatusCode};
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Value, ValueRef};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
@@ -11,6 +17,32 @@ use std::convert::{TryFrom, TryInto};
 use std::result::Result as StdResult;
 use tokio::time::{self, Duration};
 
+/// Poll interval for new messages if the API does not report rate limits, in
+/// seconds.
+pub(crate) const DEFAULT_POLL_INTERVAL: u64 = 65;
+const MIN_POLL_INTERVAL: u64 = 5;
+/// Number of direct message events fetched per page (max. 50).
+const MESSAGES_PAGE_SIZE: &str = "50";
+/// Number of recent tweets which are checked for signatures.
+const TWEETS_PAGE_SIZE: &str = "20";
+/// Interval for checking the tweets of users with closed direct messages, in
+/// seconds.
+const TWEET_POLL_INTERVAL: u64 = 120;
+/// How often users who could not be contacted are checked, in seconds.
+const CONTACT_CHECK_INTERVAL: u64 = 60;
+/// Delay before retrying to contact a user, in seconds.
+const CONTACT_RETRY_INTERVAL: u64 = 300;
+/// Users are no longer contacted proactively after that many failures.
+const MAX_CONTACT_ATTEMPTS: u32 = 12;
+/// How long processed direct message events are kept track of, in seconds.
+/// The API returns the events of the last 30 days.
+const EVENT_RETENTION: u64 = 31 * 24 * 3600;
+/// How often old direct message events are pruned, in seconds.
+const PRUNE_INTERVAL: u64 = 3600;
+/// Backoff for looking up the registrar account on startup, in seconds.
+const STARTUP_BACKOFF_BASE: u64 = 5;
+const STARTUP_BACKOFF_MAX: u64 = 600;
+
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
 pub struct TwitterId(u64);
 
@@ -56,6 +88,8 @@ impl FromSql for TwitterId {
 pub enum TwitterError {
     #[fail(display = "The builder was not used correctly")]
     IncompleteBuilder,
+    #[fail(display = "Unsupported OAuth signature method: {}", _0)]
+    UnsupportedSignatureMethod(String),
     #[fail(display = "Unrecognized data returned from the Twitter API")]
     UnrecognizedData,
     #[fail(display = "Error from Twitter API: {:?}", 0)]
@@ -68,6 +102,49 @@ pub enum TwitterError {
     RequestBuilder(failure::Error),
     #[fail(display = "No Twitter account found for user: {}", 0)]
     NoTwitterAccount(String),
+    #[fail(display = "Twitter API rate limit exceeded: {:?}", _0)]
+    RateLimited(RateLimit),
+    #[fail(display = "Twitter user does not exist")]
+    UserNotFound,
+    #[fail(display = "The user does not accept direct messages")]
+    DirectMessagesClosed,
+    #[fail(display = "Error from Twitter API: {}", _0)]
+    Api(String),
+    #[fail(display = "Failed to obtain OAuth 2.0 token: {}", _0)]
+    Token(failure::Error),
+}
+
+/// Rate limit state as reported by the `x-rate-limit-*` response headers.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RateLimit {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Unix timestamp of when the limit is reset.
+    pub reset: u64,
+}
+
+impl RateLimit {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let get = |name: &str| -> Option<u64> { headers.get(name)?.to_str().ok()?.parse().ok() };
+
+        Some(RateLimit {
+            limit: get("x-rate-limit-limit")?,
+            remaining: get("x-rate-limit-remaining")?,
+            reset: get("x-rate-limit-reset")?,
+        })
+    }
+    /// Returns the delay until the next poll, spreading the remaining
+    /// requests evenly until the limit is reset.
+    pub fn next_poll(&self) -> Duration {
+        let until_reset = self.reset.saturating_sub(unix_time()) + 1;
+        let secs = if self.remaining == 0 {
+            until_reset
+        } else {
+            until_reset / self.remaining
+        };
+
+        Duration::from_secs(secs.max(MIN_POLL_INTERVAL))
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -81,6 +158,26 @@ pub struct ApiErrorObject {
     message: String,
 }
 
+impl TwitterApiError {
+    fn has_code(&self, code: i64) -> bool {
+        self.errors.iter().any(|err| err.code == code)
+    }
+}
+
+impl TwitterError {
+    /// Whether the user does not accept direct messages from the registrar
+    /// account.
+    fn is_dm_closed(&self) -> bool {
+        match self {
+            // 150: "You cannot send messages to users who are not following you."
+            // 349: "You cannot send messages to this user."
+            TwitterError::ApiCode(api_err) => api_err.has_code(150) || api_err.has_code(349),
+            TwitterError::DirectMessagesClosed => true,
+            _ => false,
+        }
+    }
+}
+
 pub struct TwitterBuilder {
     screen_name: Option<Account>,
     consumer_key: Option<String>,
@@ -132,17 +229,22 @@ impl TwitterBuilder {
         self
     }
     pub fn build(self) -> Result<Twitter> {
+        let sig_method = self.sig_method.ok_or(TwitterError::IncompleteBuilder)?;
+        if sig_method != SIGNATURE_METHOD {
+            return Err(TwitterError::UnsupportedSignatureMethod(sig_method).into());
+        }
+
         Ok(Twitter {
             client: Client::new(),
             screen_name: self.screen_name.ok_or(TwitterError::IncompleteBuilder)?,
-            consumer_key: self.consumer_key.ok_or(TwitterError::IncompleteBuilder)?,
-            consumer_secret: self
-                .consumer_secret
-                .ok_or(TwitterError::IncompleteBuilder)?,
-            sig_method: self.sig_method.ok_or(TwitterError::IncompleteBuilder)?,
-            token: self.token.ok_or(TwitterError::IncompleteBuilder)?,
-            token_secret: self.token_secret.ok_or(TwitterError::IncompleteBuilder)?,
-            version: self.version.ok_or(TwitterError::IncompleteBuilder)?,
+            signer: OAuth1Signer::new(
+                self.consumer_key.ok_or(TwitterError::IncompleteBuilder)?,
+                self.consumer_secret
+                    .ok_or(TwitterError::IncompleteBuilder)?,
+                self.token.ok_or(TwitterError::IncompleteBuilder)?,
+                self.token_secret.ok_or(TwitterError::IncompleteBuilder)?,
+            )
+            .version(self.version.ok_or(TwitterError::IncompleteBuilder)?),
         })
     }
 }
@@ -151,17 +253,9 @@ impl TwitterBuilder {
 pub struct Twitter {
     client: Client,
     screen_name: Account,
-    consumer_key: String,
-    consumer_secret: String,
-    sig_method: String,
-    token: String,
-    token_secret: String,
-    version: f64,
+    signer: OAuth1Signer,
 }
 
-use hmac::{Hmac, Mac, NewMac};
-use sha1::Sha1;
-
 enum HttpMethod {
     POST,
     GET,
@@ -180,55 +274,316 @@ impl HttpMethod {
 
 #[async_trait]
 pub trait TwitterTransport: 'static + Send + Sync {
+    /// Returns the messages received since the watermark, the new watermark
+    /// and the current rate limit of the endpoint, if reported.
     async fn request_messages(
         &self,
         exclude: &TwitterId,
         watermark: u64,
-    ) -> Result<(Vec<ReceivedMessageContext>, u64)>;
+    ) -> Result<(Vec<ReceivedMessageContext>, u64, Option<RateLimit>)>;
     async fn lookup_twitter_id(
         &self,
         twitter_ids: Option<&[&TwitterId]>,
         accounts: Option<&[&Account]>,
     ) -> Result<Vec<(Account, TwitterId)>>;
+    /// Returns the state of the account, as reported by the user object.
+    async fn lookup_user(&self, account: &Account) -> Result<TwitterUser>;
     async fn send_message(&self, id: &TwitterId, message: String) -> StdResult<(), TwitterError>;
+    /// Publicly mentions the account in a tweet.
+    async fn send_mention(&self, account: &Account, message: String)
+        -> StdResult<(), TwitterError>;
+    /// Returns the recent tweets of the user, newer than `since_id` (if
+    /// specified).
+    async fn request_tweets(
+        &self,
+        twitter_id: &TwitterId,
+        since_id: Option<u64>,
+    ) -> Result<Vec<Tweet>>;
     fn my_screen_name(&self) -> &Account;
 }
 
+/// State of a Twitter account.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TwitterUser {
+    Active(TwitterId),
+    /// The tweets of the account are only visible to approved followers.
+    Protected(TwitterId),
+    Suspended,
+    /// The account does not exist or was deleted.
+    NotFound,
+}
+
+/// Twitter client for the API version selected in the config. The API v1.1
+/// is used by default. The API v2 client falls back to the API v1.1, if
+/// configured, while no OAuth 2.0 access token can be obtained.
+#[derive(Clone)]
+pub enum TwitterClient {
+    V1(Twitter),
+    V2(TwitterV2, Option<Twitter>),
+}
+
+impl TwitterClient {
+    /// Uses the API v1.1 client if the API v2 client fails to authenticate.
+    pub fn with_fallback(self, fallback: Twitter) -> Self {
+        match self {
+            TwitterClient::V2(client, _) => TwitterClient::V2(client, Some(fallback)),
+            client => client,
+        }
+    }
+    /// Returns the API v1.1 client if the error is caused by the OAuth 2.0
+    /// token.
+    fn fallback(&self, err: &TwitterError) -> Option<&Twitter> {
+        match (self, err) {
+            (TwitterClient::V2(_, Some(fallback)), TwitterError::Token(_)) => {
+                warn!("Falling back to the Twitter API v1.1: {}", err);
+                Some(fallback)
+            }
+            _ => None,
+        }
+    }
+    fn fallback_for(&self, err: &failure::Error) -> Option<&Twitter> {
+        err.downcast_ref::<TwitterError>()
+            .and_then(|err| self.fallback(err))
+    }
+}
+
+impl From<Twitter> for TwitterClient {
+    fn from(client: Twitter) -> Self {
+        TwitterClient::V1(client)
+    }
+}
+
+impl From<TwitterV2> for TwitterClient {
+    fn from(client: TwitterV2) -> Self {
+        TwitterClient::V2(client, None)
+    }
+}
+
+#[async_trait]
+impl TwitterTransport for TwitterClient {
+    async fn request_messages(
+        &self,
+        exclude: &TwitterId,
+        watermark: u64,
+    ) -> Result<(Vec<ReceivedMessageContext>, u64, Option<RateLimit>)> {
+        match self {
+            TwitterClient::V1(client) => client.request_messages(exclude, watermark).await,
+            TwitterClient::V2(client, _) => match client.request_messages(exclude, watermark).await
+            {
+                Err(err) => match self.fallback_for(&err) {
+                    Some(fallback) => fallback.request_messages(exclude, watermark).await,
+                    None => Err(err),
+                },
+                res => res,
+            },
+        }
+    }
+    async fn lookup_twitter_id(
+        &self,
+        twitter_ids: Option<&[&TwitterId]>,
+        accounts: Option<&[&Account]>,
+    ) -> Result<Vec<(Account, TwitterId)>> {
+        match self {
+            TwitterClient::V1(client) => client.lookup_twitter_id(twitter_ids, accounts).await,
+            TwitterClient::V2(client, _) => {
+                match client.lookup_twitter_id(twitter_ids, accounts).await {
+                    Err(err) => match self.fallback_for(&err) {
+                        Some(fallback) => fallback.lookup_twitter_id(twitter_ids, accounts).await,
+                        None => Err(err),
+                    },
+                    res => res,
+                }
+            }
+        }
+    }
+    async fn lookup_user(&self, account: &Account) -> Result<TwitterUser> {
+        match self {
+            TwitterClient::V1(client) => client.lookup_user(account).await,
+            TwitterClient::V2(client, _) => match client.lookup_user(account).await {
+                Err(err) => match self.fallback_for(&err) {
+                    Some(fallback) => fallback.lookup_user(account).await,
+                    None => Err(err),
+                },
+                res => res,
+            },
+        }
+    }
+    async fn send_message(&self, id: &TwitterId, message: String) -> StdResult<(), TwitterError> {
+        match self {
+            TwitterClient::V1(client) => client.send_message(id, message).await,
+            TwitterClient::V2(client, _) => match client.send_message(id, message.clone()).await {
+                Err(err) => match self.fallback(&err) {
+                    Some(fallback) => fallback.send_message(id, message).await,
+                    None => Err(err),
+                },
+                res => res,
+            },
+        }
+    }
+    async fn send_mention(
+        &self,
+        account: &Account,
+        message: String,
+    ) -> StdResult<(), TwitterError> {
+        match self {
+            TwitterClient::V1(client) => client.send_mention(account, message).await,
+            TwitterClient::V2(client, _) => {
+                match client.send_mention(account, message.clone()).await {
+                    Err(err) => match self.fallback(&err) {
+                        Some(fallback) => fallback.send_mention(account, message).await,
+                        None => Err(err),
+                    },
+                    res => res,
+                }
+            }
+        }
+    }
+    async fn request_tweets(
+        &self,
+        twitter_id: &TwitterId,
+        since_id: Option<u64>,
+    ) -> Result<Vec<Tweet>> {
+        match self {
+            TwitterClient::V1(client) => client.request_tweets(twitter_id, since_id).await,
+            TwitterClient::V2(client, _) => match client.request_tweets(twitter_id, since_id).await
+            {
+                Err(err) => match self.fallback_for(&err) {
+                    Some(fallback) => fallback.request_tweets(twitter_id, since_id).await,
+                    None => Err(err),
+                },
+                res => res,
+            },
+        }
+    }
+    fn my_screen_name(&self) -> &Account {
+        match self {
+            TwitterClient::V1(client) => client.my_screen_name(),
+            TwitterClient::V2(client, _) => client.my_screen_name(),
+        }
+    }
+}
+
+/// Canonicalizes a Twitter handle to the `@handle` notation in lowercase, as
+/// Twitter handles are case-insensitive. Profile links and handles without
+/// the leading `@` are accepted. Returns `None` if the handle is invalid.
+pub fn normalize_twitter_handle(account: &Account) -> Option<Account> {
+    let mut handle = account.as_str().trim();
+
+    for prefix in &[
+        "https://twitter.com/",
+        "http://twitter.com/",
+        "https://www.twitter.com/",
+        "twitter.com/",
+        "https://x.com/",
+        "x.com/",
+    ] {
+        if let Some(stripped) = handle.strip_prefix(prefix) {
+            handle = stripped;
+            break;
+        }
+    }
+
+    let handle = handle.split('?').next().unwrap_or(handle);
+    let handle = handle.trim_end_matches('/');
+    let handle = handle.strip_prefix('@').unwrap_or(handle);
+
+    // Handles consist of up to 15 alphanumeric characters or underscores.
+    if handle.is_empty()
+        || handle.len() > 15
+        || !handle
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return None;
+    }
+
+    Some(Account::from(format!("@{}", handle.to_ascii_lowercase())))
+}
+
 #[derive(Clone)]
 pub struct TwitterHandler {
     db: Database2,
     comms: CommsVerifier,
+    templates: Templates,
 }
 
 impl TwitterHandler {
-    pub fn new(db: Database2, comms: CommsVerifier) -> Self {
+    pub fn new(db: Database2, comms: CommsVerifier, templates: Templates) -> Self {
         TwitterHandler {
             db: db,
             comms: comms,
+            templates: templates,
         }
     }
     pub async fn start<T: Clone + TwitterTransport>(self, transport: T) {
-        // TODO: Improve error case
-        let my_id = transport
-            .lookup_twitter_id(None, Some(&[transport.my_screen_name()]))
-            .await
-            .unwrap()
-            .remove(0)
-            .1;
+        let my_id = self.lookup_my_id(&transport).await;
+
+        // Start cleanup handler of processed direct message events.
+        let l_db = self.db.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(PRUNE_INTERVAL));
+            loop {
+                interval.tick().await;
+
+                let _ = l_db
+                    .prune_twitter_events(EVENT_RETENTION)
+                    .await
+                    .map_err(|err| {
+                        error!("{}", err);
+                        err
+                    });
+            }
+        });
 
         // Start incoming messages handler.
         let l_self = self.clone();
         let l_transport = transport.clone();
+        tokio::spawn(async move {
+            loop {
+                let delay = match l_self.handle_incoming_messages(&l_transport, &my_id).await {
+                    Ok(delay) => delay,
+                    Err(err) => match err.downcast_ref::<TwitterError>() {
+                        Some(TwitterError::RateLimited(rate_limit)) => {
+                            warn!("{}", err);
+                            rate_limit.next_poll()
+                        }
+                        _ => {
+                            error!("{}", err);
+                            Duration::from_secs(DEFAULT_POLL_INTERVAL)
+                        }
+                    },
+                };
+
+                time::delay_for(delay).await;
+            }
+        });
+
+        // Start the handler for retrying to contact users.
+        let l_self = self.clone();
+        let l_transport = transport.clone();
+        tokio::spawn(async move {
+            loop {
+                let _ = l_self.retry_contacts(&l_transport).await.map_err(|err| {
+                    error!("{}", err);
+                });
+
+                time::delay_for(Duration::from_secs(CONTACT_CHECK_INTERVAL)).await;
+            }
+        });
+
+        // Start the handler for verifications via public tweets.
+        let l_self = self.clone();
+        let l_transport = transport.clone();
         tokio::spawn(async move {
             loop {
                 let _ = l_self
-                    .handle_incoming_messages(&l_transport, &my_id)
+                    .handle_tweet_verifications(&l_transport)
                     .await
                     .map_err(|err| {
                         error!("{}", err);
                     });
 
-                time::delay_for(Duration::from_secs(65)).await;
+                time::delay_for(Duration::from_secs(TWEET_POLL_INTERVAL)).await;
             }
         });
 
@@ -238,15 +593,75 @@ impl TwitterHandler {
             });
         }
     }
+    /// Looks up the Twitter ID of the registrar account, retrying with an
+    /// exponential backoff. Twitter is reported as unhealthy until the lookup
+    /// succeeds.
+    async fn lookup_my_id<T: TwitterTransport>(&self, transport: &T) -> TwitterId {
+        let mut attempts: u32 = 0;
+
+        loop {
+            let res = transport
+                .lookup_twitter_id(None, Some(&[transport.my_screen_name()]))
+                .await
+                .and_then(|lookups| {
+                    lookups
+                        .into_iter()
+                        .next()
+                        .map(|(_, twitter_id)| twitter_id)
+                        .ok_or(TwitterError::UserNotFound.into())
+                });
+
+            match res {
+                Ok(my_id) => {
+                    let _ = self
+                        .db
+                        .set_adapter_healthy(&AccountType::Twitter)
+                        .await
+                        .map_err(|err| {
+                            error!("{}", err);
+                        });
+
+                    return my_id;
+                }
+                Err(err) => {
+                    attempts += 1;
+                    let delay = (STARTUP_BACKOFF_BASE << attempts.min(16)).min(STARTUP_BACKOFF_MAX);
+
+                    error!(
+                        "Failed to look up the Twitter account {} (attempt {}), retrying in {} \
+                        seconds: {}",
+                        transport.my_screen_name().as_str(),
+                        attempts,
+                        delay,
+                        err
+                    );
+
+                    let _ = self
+                        .db
+                        .set_adapter_unhealthy(
+                            &AccountType::Twitter,
+                            &format!("failed to look up the registrar account: {}", err),
+                        )
+                        .await
+                        .map_err(|err| {
+                            error!("{}", err);
+                        });
+
+                    time::delay_for(Duration::from_secs(delay)).await;
+                }
+            }
+        }
+    }
     pub async fn local<T: TwitterTransport>(&self, transport: &T) -> Result<()> {
         use CommsMessage::*;
 
         match self.comms.recv().await {
             AccountToVerify {
-                net_account: _,
-                account: _,
+                net_account,
+                account,
             } => {
-                // The Twitter adapter has to wait for messages, no need to initiate contact.
+                self.handle_account_verification(transport, net_account, account)
+                    .await?
             }
             NotifyInvalidAccount {
                 net_account,
@@ -260,11 +675,206 @@ impl TwitterHandler {
 
         Ok(())
     }
+    /// Contacts the user right away instead of waiting for the user to send
+    /// a message first. If the user cannot be contacted, it is retried later.
+    pub async fn handle_account_verification<T: TwitterTransport>(
+        &self,
+        transport: &T,
+        net_account: NetAccount,
+        account: Account,
+    ) -> Result<()> {
+        match self
+            .contact_user(transport, net_account.clone(), &account)
+            .await
+        {
+            Ok(()) => {
+                self.db
+                    .remove_twitter_contact(&net_account, &account)
+                    .await?;
+                Ok(())
+            }
+            Err(err) => {
+                let attempts = self
+                    .db
+                    .postpone_twitter_contact(&net_account, &account, CONTACT_RETRY_INTERVAL)
+                    .await?;
+
+                if attempts >= MAX_CONTACT_ATTEMPTS {
+                    // The user can still contact the registrar.
+                    warn!("Failed to contact {}, giving up: {}", account.as_str(), err);
+
+                    self.db
+                        .remove_twitter_contact(&net_account, &account)
+                        .await?;
+                    Ok(())
+                } else {
+                    info!(
+                        "Failed to contact {}, retrying later ({}/{})",
+                        account.as_str(),
+                        attempts,
+                        MAX_CONTACT_ATTEMPTS
+                    );
+                    Err(err)
+                }
+            }
+        }
+    }
+    /// Retries to contact the users who could not be contacted before.
+    async fn retry_contacts<T: TwitterTransport>(&self, transport: &T) -> Result<()> {
+        for (net_account, account) in self.db.select_twitter_contacts().await? {
+            // The identity might have been updated or removed in the meantime.
+            if !self
+                .db
+                .select_addresses(&net_account)
+                .await?
+                .contains(&account)
+            {
+                self.db
+                    .remove_twitter_contact(&net_account, &account)
+                    .await?;
+                continue;
+            }
+
+            debug!("Retrying to contact {}", account.as_str());
+            let _ = self
+                .handle_account_verification(transport, net_account, account)
+                .await
+                .map_err(|err| error!("{}", err));
+        }
+
+        Ok(())
+    }
+    async fn contact_user<T: TwitterTransport>(
+        &self,
+        transport: &T,
+        net_account: NetAccount,
+        account: &Account,
+    ) -> Result<()> {
+        debug!("Looking up Twitter account {}", account.as_str());
+
+        let (twitter_id, protected) = match transport.lookup_user(account).await? {
+            TwitterUser::Active(twitter_id) => (twitter_id, false),
+            TwitterUser::Protected(twitter_id) => (twitter_id, true),
+            TwitterUser::Suspended => {
+                return self
+                    .set_invalid(net_account, account, InvalidReason::Suspended)
+                    .await;
+            }
+            TwitterUser::NotFound => {
+                return self
+                    .set_invalid(net_account, account, InvalidReason::NotFound)
+                    .await;
+            }
+        };
+
+        self.db.insert_twitter_id(account, &twitter_id).await?;
+
+        let challenge_data = self
+            .db
+            .select_challenge_data(account, &AccountType::Twitter)
+            .await?;
+
+        if challenge_data.is_empty() {
+            warn!(
+                "No challenge data found for account {}. Ignoring.",
+                account.as_str()
+            );
+            return Ok(());
+        }
+
+        match transport
+            .send_message(&twitter_id, self.instructions(&challenge_data))
+            .await
+        {
+            Ok(()) => {
+                self.db.confirm_init_message(account).await?;
+
+                self.db
+                    .set_account_status(&net_account, &AccountType::Twitter, &AccountStatus::Valid)
+                    .await?;
+
+                self.comms.notify_status_change(net_account);
+            }
+            // Tweets of protected accounts are not visible to the registrar,
+            // so there is no way to verify the account.
+            Err(err) if err.is_dm_closed() && protected => {
+                self.set_invalid(net_account, account, InvalidReason::DirectMessagesClosed)
+                    .await?;
+            }
+            Err(err) if err.is_dm_closed() => {
+                debug!(
+                    "Direct messages of {} are closed, mentioning publicly",
+                    account.as_str()
+                );
+
+                // The init message is sent once the user writes first.
+                // Alternatively, the user can tweet the signature publicly.
+                // The challenges are not included, since the tweet would
+                // exceed the length limit with several pending challenges.
+                transport
+                    .send_mention(
+                        account,
+                        format!(
+                            "Please send a direct message to {} in order to verify your \
+                            on-chain identity with the Web3 Registrar.",
+                            transport.my_screen_name().as_str(),
+                        ),
+                    )
+                    .await?;
+
+                self.db
+                    .insert_tweet_verification(account, &twitter_id)
+                    .await?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(())
+    }
+    /// Prefers the configured template, if any.
+    fn instructions(&self, challenge_data: &[(NetworkAddress, Challenge)]) -> String {
+        self.templates
+            .render(
+                &AccountType::Twitter,
+                TemplateKind::Instructions,
+                challenge_data,
+            )
+            .map(|rendered| rendered.text)
+            .unwrap_or_else(|| {
+                Verifier2::new(challenge_data)
+                    .init_message_builder(false)
+                    .into()
+            })
+    }
+    async fn set_invalid(
+        &self,
+        net_account: NetAccount,
+        account: &Account,
+        reason: InvalidReason,
+    ) -> Result<()> {
+        debug!(
+            "Twitter account {} is invalid: {:?}",
+            account.as_str(),
+            reason
+        );
+
+        self.db
+            .set_account_status(
+                &net_account,
+                &AccountType::Twitter,
+                &AccountStatus::Invalid(Some(reason)),
+            )
+            .await?;
+
+        self.comms.notify_status_change(net_account);
+
+        Ok(())
+    }
     pub async fn handle_invalid_account_notification<T: TwitterTransport>(
         &self,
         transport: &T,
         net_account: NetAccount,
-        accounts: Vec<(AccountType, Account)>,
+        accounts: Vec<(AccountType, Account, Option<InvalidReason>)>,
     ) -> Result<()> {
         let account = self
             .db
@@ -286,27 +896,126 @@ impl TwitterHandler {
         let violations = self.db.select_display_name_violations(&net_account).await?;
 
         transport
-            .send_message(&twitter_id, invalid_accounts_message(&accounts, violations))
+            .send_message(
+                &twitter_id,
+                invalid_accounts_message(&accounts, violations).into(),
+            )
             .await?;
 
         Ok(())
     }
+    /// Checks the recent tweets of users with closed direct messages for
+    /// signatures of their challenges. The direct message based verification
+    /// stays available.
+    pub async fn handle_tweet_verifications<T: TwitterTransport>(
+        &self,
+        transport: &T,
+    ) -> Result<()> {
+        for (account, twitter_id, since_id) in self.db.select_tweet_verifications().await? {
+            let challenge_data = self
+                .db
+                .select_challenge_data(&account, &AccountType::Twitter)
+                .await?;
+
+            // Verified (or removed) in the meantime.
+            if challenge_data.is_empty() {
+                debug!(
+                    "No pending challenges for {}, stop checking tweets",
+                    account.as_str()
+                );
+
+                self.db.remove_tweet_verification(&account).await?;
+                continue;
+            }
+
+            // A failure for one user must not hold up the others.
+            let tweets = match transport.request_tweets(&twitter_id, since_id).await {
+                Ok(tweets) => tweets,
+                Err(err) => {
+                    if let Some(TwitterError::UserNotFound) = err.downcast_ref::<TwitterError>() {
+                        warn!(
+                            "Twitter user {} does not exist anymore, stop checking tweets",
+                            account.as_str()
+                        );
+
+                        self.db.remove_tweet_verification(&account).await?;
+                    } else {
+                        error!("Failed to request tweets of {}: {}", account.as_str(), err);
+                    }
+
+                    continue;
+                }
+            };
+
+            if tweets.is_empty() {
+                continue;
+            }
+
+            debug!(
+                "Checking {} new tweet(-s) of {}",
+                tweets.len(),
+                account.as_str()
+            );
+
+            // Tweets might contain additional text besides the signature.
+            let mut verifier = Verifier2::new(&challenge_data);
+            tweets
+                .iter()
+                .flat_map(|tweet| tweet.text.split_whitespace())
+                .for_each(|word| verifier.verify(word));
+
+            verification_handler(&verifier, &self.db, &self.comms, &AccountType::Twitter).await?;
+
+            // The entry is removed on the next run once all challenges are
+            // accepted.
+            if let Some(since_id) = tweets.iter().map(|tweet| tweet.id).max() {
+                self.db
+                    .update_tweet_verification(&account, since_id)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+    /// Processes new messages and returns the delay until the next poll.
     pub async fn handle_incoming_messages<T: TwitterTransport>(
         &self,
         transport: &T,
         my_id: &TwitterId,
-    ) -> Result<()> {
+    ) -> Result<Duration> {
         let watermark = self
             .db
             .select_watermark(&AccountType::Twitter)
             .await?
             .unwrap_or(0);
 
-        let (messages, watermark) = transport.request_messages(my_id, watermark).await?;
+        let (messages, mut new_watermark, rate_limit) =
+            transport.request_messages(my_id, watermark).await?;
+
+        let delay = rate_limit
+            .map(|rate_limit| rate_limit.next_poll())
+            .unwrap_or(Duration::from_secs(DEFAULT_POLL_INTERVAL));
+
+        // Skip messages which were already processed, e.g. if processing a
+        // different conversation failed during a previous poll.
+        let untracked = self
+            .db
+            .find_untracked_twitter_events(&messages.iter().map(|msg| msg.id).collect::<Vec<u64>>())
+            .await?;
+
+        let messages = messages
+            .into_iter()
+            .filter(|msg| untracked.contains(&msg.id))
+            .collect::<Vec<ReceivedMessageContext>>();
 
         if messages.is_empty() {
             trace!("No new messages received");
-            return Ok(());
+
+            self.db
+                .update_watermark(&AccountType::Twitter, new_watermark)
+                .await?;
+
+            return Ok(delay);
         } else {
             debug!("Received {} new messasge(-s)", messages.len());
         }
@@ -316,19 +1025,38 @@ impl TwitterHandler {
         let mut to_lookup = vec![];
         for message in &messages {
             // Avoid duplicates.
-            if let Some(_) = idents
+            if idents
                 .iter()
-                .find(|(_, twitter_id, _)| *twitter_id == &message.sender)
+                .any(|(_, twitter_id, _)| *twitter_id == &message.sender)
+                || to_lookup.contains(&&message.sender)
             {
                 continue;
             }
 
-            // Lookup TwitterId in database.
-            if let Some((account, init_msg)) = self
+            // Lookup TwitterId in database. The cached screen name might be
+            // outdated if the user changed the handle, in which case no
+            // pending challenge is associated with it anymore.
+            let cached = match self
                 .db
                 .select_account_from_twitter_id(&message.sender)
                 .await?
             {
+                Some((account, init_msg)) => {
+                    if self
+                        .db
+                        .select_challenge_data(&account, &AccountType::Twitter)
+                        .await?
+                        .is_empty()
+                    {
+                        None
+                    } else {
+                        Some((account, init_msg))
+                    }
+                }
+                None => None,
+            };
+
+            if let Some((account, init_msg)) = cached {
                 debug!(
                     "Found associated match for {}: {}",
                     message.sender.as_u64(),
@@ -351,143 +1079,165 @@ impl TwitterHandler {
         let lookup_results;
         if !to_lookup.is_empty() {
             debug!("Looking up TwitterIds");
-            lookup_results = transport.lookup_twitter_id(Some(&to_lookup), None).await?;
+            lookup_results = match transport.lookup_twitter_id(Some(&to_lookup), None).await {
+                Ok(results) => results,
+                // None of the senders exist anymore.
+                Err(err) => match err.downcast_ref::<TwitterError>() {
+                    Some(TwitterError::UserNotFound) => vec![],
+                    _ => return Err(err),
+                },
+            };
 
+            // Only senders with a pending challenge are of interest, any other
+            // user can send messages to the registrar, too.
+            let mut known = vec![];
             for (account, twitter_id) in &lookup_results {
-                idents.push((account.clone(), &twitter_id, false));
-            }
+                if self
+                    .db
+                    .select_challenge_data(account, &AccountType::Twitter)
+                    .await?
+                    .is_empty()
+                {
+                    continue;
+                }
 
-            self.db
-                .insert_twitter_ids(
-                    lookup_results
-                        .iter()
-                        .map(|(account, twitter_id)| (account, twitter_id))
-                        .collect::<Vec<(&Account, &TwitterId)>>()
-                        .as_slice(),
-                )
-                .await?;
-        }
+                idents.push((account.clone(), twitter_id, false));
+                known.push((account, twitter_id));
+            }
 
-        for (account, twitter_id, init_msg) in &idents {
-            debug!("Starting verification process for {}", account.as_str());
+            self.db.insert_twitter_ids(known.as_slice()).await?;
 
-            let challenge_data = self
-                .db
-                .select_challenge_data(&account, &AccountType::Twitter)
-                .await?;
+            // Track the messages of unknown senders, so those are not looked
+            // up again on every poll.
+            for sender in to_lookup {
+                if known.iter().any(|(_, twitter_id)| *twitter_id == sender) {
+                    continue;
+                }
 
-            // TODO: `select_challenge_data` should return an error.
-            if challenge_data.is_empty() {
-                warn!(
-                    "No challenge data found for account {}. Ignoring.",
-                    account.as_str()
-                );
-                continue;
-            }
+                debug!("Ignoring messages of unknown sender {}", sender.as_u64());
 
-            let mut verifier = Verifier2::new(&challenge_data);
+                let event_ids = messages
+                    .iter()
+                    .filter(|msg| &msg.sender == sender)
+                    .map(|msg| msg.id)
+                    .collect::<Vec<u64>>();
 
-            if !*init_msg {
-                transport
-                    .send_message(&twitter_id, verifier.init_message_builder(false))
-                    .await?;
-                self.db.confirm_init_message(&account).await?;
-                continue;
+                self.db.track_twitter_events(sender, &event_ids).await?;
             }
+        }
 
-            // Verify each message received.
-            messages
+        // Each conversation is processed independently, a failure does not
+        // affect the conversations of other users.
+        for (account, twitter_id, init_msg) in &idents {
+            let conversation = messages
                 .iter()
                 .filter(|msg| &msg.sender == *twitter_id)
-                .for_each(|msg| verifier.verify(&msg.message));
+                .collect::<Vec<&ReceivedMessageContext>>();
 
-            // Update challenge statuses and notify manager
-            verification_handler(&verifier, &self.db, &self.comms, &AccountType::Twitter).await?;
+            if let Err(err) = self
+                .handle_conversation(transport, account, twitter_id, *init_msg, &conversation)
+                .await
+            {
+                error!(
+                    "Failed to process messages of {}: {}",
+                    account.as_str(),
+                    err
+                );
 
-            // Inform user about the current state of the verification
-            transport
-                .send_message(&twitter_id, verifier.response_message_builder())
-                .await?;
+                // Fetch the untracked messages of this conversation again on
+                // the next poll.
+                if let Some(oldest) = conversation.iter().map(|msg| msg.created).min() {
+                    new_watermark = new_watermark.min(oldest.saturating_sub(1));
+                }
+            }
         }
 
         self.db
-            .update_watermark(&AccountType::Twitter, watermark)
+            .update_watermark(&AccountType::Twitter, new_watermark)
             .await?;
 
-        Ok(())
+        Ok(delay)
     }
-}
-
-impl Twitter {
-    /// Creates a signature as documented here:
-    /// https://developer.twitter.com/en/docs/authentication/oauth-1-0a/creating-a-signature
-    fn authenticate_request(
+    /// Processes the new messages of a single conversation. The messages are
+    /// tracked once processed, so they are never handled twice.
+    async fn handle_conversation<T: TwitterTransport>(
         &self,
-        method: &HttpMethod,
-        url: &str,
-        request: &mut Request,
-        params: Option<&[(&str, &str)]>,
+        transport: &T,
+        account: &Account,
+        twitter_id: &TwitterId,
+        init_msg: bool,
+        messages: &[&ReceivedMessageContext],
     ) -> Result<()> {
-        use urlencoding::encode;
-
-        let challenge = Challenge::gen_random();
-        let timestamp = unix_time().to_string();
-        let version = format!("{:.1}", self.version);
-
-        let mut fields = vec![
-            ("oauth_consumer_key", self.consumer_key.as_str()),
-            ("oauth_nonce", challenge.as_str()),
-            ("oauth_signature_method", self.sig_method.as_str()),
-            ("oauth_timestamp", &timestamp),
-            ("oauth_token", self.token.as_str()),
-            ("oauth_version", &version),
-        ];
+        debug!("Starting verification process for {}", account.as_str());
 
-        if let Some(params) = params {
-            fields.append(&mut params.to_vec());
-        }
+        let event_ids = messages.iter().map(|msg| msg.id).collect::<Vec<u64>>();
+
+        let challenge_data = self
+            .db
+            .select_challenge_data(&account, &AccountType::Twitter)
+            .await?;
 
-        fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+        // TODO: `select_challenge_data` should return an error.
+        if challenge_data.is_empty() {
+            warn!(
+                "No challenge data found for account {}. Ignoring.",
+                account.as_str()
+            );
 
-        let mut params = String::new();
-        for (name, val) in &fields {
-            params.push_str(&format!("{}={}&", encode(name), encode(val)));
+            self.db.track_twitter_events(twitter_id, &event_ids).await?;
+            return Ok(());
         }
 
-        // Remove the trailing `&`.
-        params.pop();
+        let mut verifier = Verifier2::new(&challenge_data);
 
-        let base = format!("{}&{}&{}", method.as_str(), encode(url), encode(&params));
+        if !init_msg {
+            transport
+                .send_message(&twitter_id, self.instructions(&challenge_data))
+                .await?;
 
-        // Sign the base string.
-        let sign_key = format!(
-            "{}&{}",
-            encode(&self.consumer_secret),
-            encode(&self.token_secret)
-        );
+            self.db.confirm_init_message(&account).await?;
+            self.db.track_twitter_events(twitter_id, &event_ids).await?;
+            return Ok(());
+        }
 
-        let mut mac: Hmac<Sha1> = Hmac::new_varkey(sign_key.as_bytes()).unwrap();
-        mac.update(base.as_bytes());
+        // Verify each message received.
+        messages
+            .iter()
+            .for_each(|msg| verifier.verify(&msg.message));
 
-        // Create the resulting hash.
-        let sig = base64::encode(mac.finalize().into_bytes());
+        // Update challenge statuses and notify manager
+        verification_handler(&verifier, &self.db, &self.comms, &AccountType::Twitter).await?;
 
-        // Insert the signature;
-        fields.push(("oauth_signature", &sig));
-        fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+        // Track the messages before responding, so a failure of the transport
+        // does not result in the same response being sent twice.
+        self.db.track_twitter_events(twitter_id, &event_ids).await?;
 
-        let mut oauth_header = String::new();
-        oauth_header.push_str("OAuth ");
+        // Inform user about the current state of the verification
+        transport
+            .send_message(&twitter_id, verifier.response_message_builder().into())
+            .await?;
 
-        for (name, val) in &fields {
-            oauth_header.push_str(&format!("{}={}, ", encode(name), encode(val)))
-        }
+        Ok(())
+    }
+}
 
-        // Remove the trailing `, `.
-        oauth_header.pop();
-        oauth_header.pop();
+impl Twitter {
+    /// Signs the request as documented here:
+    /// https://developer.twitter.com/en/docs/authentication/oauth-1-0a/creating-a-signature
+    ///
+    /// The `url` must not contain any query, the query or form parameters
+    /// are passed unencoded via `params`.
+    fn authenticate_request(
+        &self,
+        method: &HttpMethod,
+        url: &str,
+        request: &mut Request,
+        params: Option<&[(&str, &str)]>,
+    ) -> Result<()> {
+        let oauth_header = self
+            .signer
+            .authorization(method.as_str(), url, params.unwrap_or(&[]));
 
-        // Inserth the authentication header into the request.
         request
             .headers_mut()
             .insert(header::AUTHORIZATION, HeaderValue::from_str(&oauth_header)?);
@@ -499,16 +1249,22 @@ impl Twitter {
         url: &str,
         params: Option<&[(&str, &str)]>,
     ) -> StdResult<T, TwitterError> {
+        self.get_request_with_rate_limit(url, params)
+            .await
+            .map(|(resp, _)| resp)
+    }
+    async fn get_request_with_rate_limit<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        params: Option<&[(&str, &str)]>,
+    ) -> StdResult<(T, Option<RateLimit>), TwitterError> {
         let mut full_url = String::from(url);
 
         if let Some(params) = params {
-            full_url.push('?');
-            for (key, val) in params {
-                full_url.push_str(&format!("{}={}&", key, val));
+            if !params.is_empty() {
+                full_url.push('?');
+                full_url.push_str(&encode_params(params));
             }
-
-            // Remove trailing `&` or `?` in case "params" is empty.
-            full_url.pop();
         }
 
         let mut request = self
@@ -526,6 +1282,15 @@ impl Twitter {
             .await
             .map_err(|err| TwitterError::Http(err.into()))?;
 
+        let rate_limit = RateLimit::from_headers(resp.headers());
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(TwitterError::RateLimited(rate_limit.unwrap_or(RateLimit {
+                limit: 0,
+                remaining: 0,
+                reset: unix_time() + DEFAULT_POLL_INTERVAL,
+            })));
+        }
+
         let txt = resp
             .text()
             .await
@@ -533,6 +1298,48 @@ impl Twitter {
 
         trace!("GET response: {}", txt);
 
+        serde_json::from_str::<T>(&txt)
+            .map(|resp| (resp, rate_limit))
+            .map_err(|err| {
+                if let Ok(api_err) = serde_json::from_str::<TwitterApiError>(&txt) {
+                    TwitterError::ApiCode(api_err)
+                } else {
+                    TwitterError::Serde(err.into())
+                }
+            })
+    }
+    async fn post_form_request<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        params: &[(&str, &str)],
+    ) -> StdResult<T, TwitterError> {
+        let body = encode_params(params);
+
+        let mut request = self
+            .client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(body)
+            .build()
+            .map_err(|err| TwitterError::RequestBuilder(err.into()))?;
+
+        // Form parameters are part of the signature.
+        self.authenticate_request(&HttpMethod::POST, url, &mut request, Some(params))
+            .map_err(|err| TwitterError::RequestBuilder(err.into()))?;
+
+        let resp = self
+            .client
+            .execute(request)
+            .await
+            .map_err(|err| TwitterError::Http(err.into()))?;
+
+        let txt = resp
+            .text()
+            .await
+            .map_err(|_| TwitterError::UnrecognizedData)?;
+
+        trace!("POST response: {}", txt);
+
         serde_json::from_str::<T>(&txt).map_err(|err| {
             if let Ok(api_err) = serde_json::from_str::<TwitterApiError>(&txt) {
                 TwitterError::ApiCode(api_err)
@@ -549,10 +1356,12 @@ impl Twitter {
         let mut request = self
             .client
             .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
             .body(serde_json::to_string(&body).map_err(|err| TwitterError::Serde(err.into()))?)
             .build()
             .map_err(|err| TwitterError::RequestBuilder(err.into()))?;
 
+        // JSON bodies are not part of the signature.
         self.authenticate_request(&HttpMethod::POST, url, &mut request, None)
             .map_err(|err| TwitterError::RequestBuilder(err.into()))?;
 
@@ -585,13 +1394,48 @@ impl TwitterTransport for Twitter {
         &self,
         exclude: &TwitterId,
         watermark: u64,
-    ) -> Result<(Vec<ReceivedMessageContext>, u64)> {
-        self.get_request::<ApiMessageRequest>(
-            "https://api.twitter.com/1.1/direct_messages/events/list.json",
-            None,
-        )
-        .await?
-        .get_messages(exclude, watermark)
+    ) -> Result<(Vec<ReceivedMessageContext>, u64, Option<RateLimit>)> {
+        let mut messages = vec![];
+        let mut new_watermark = watermark;
+        let mut cursor: Option<String> = None;
+
+        loop {
+            let mut params = vec![("count", MESSAGES_PAGE_SIZE)];
+            if let Some(cursor) = &cursor {
+                params.push(("cursor", cursor.as_str()));
+            }
+
+            let (page, rate_limit) = self
+                .get_request_with_rate_limit::<ApiMessageRequest>(
+                    "https://api.twitter.com/1.1/direct_messages/events/list.json",
+                    Some(&params),
+                )
+                .await?;
+
+            let next_cursor = page.next_cursor.clone();
+            let (mut page_messages, page_watermark, complete) =
+                page.get_messages(exclude, watermark)?;
+
+            messages.append(&mut page_messages);
+            new_watermark = new_watermark.max(page_watermark);
+
+            // The events are returned in reverse chronological order, so
+            // older pages are only fetched until the watermark is reached.
+            match next_cursor {
+                Some(next_cursor) if !complete => {
+                    // Rather fetch all pages again after the reset than
+                    // advancing the watermark beyond missed messages.
+                    if let Some(rate_limit) = rate_limit {
+                        if rate_limit.remaining == 0 {
+                            return Err(TwitterError::RateLimited(rate_limit).into());
+                        }
+                    }
+
+                    cursor = Some(next_cursor);
+                }
+                _ => return Ok((messages, new_watermark, rate_limit)),
+            }
+        }
     }
     async fn lookup_twitter_id(
         &self,
@@ -640,17 +1484,56 @@ impl TwitterTransport for Twitter {
                 "https://api.twitter.com/1.1/users/lookup.json",
                 Some(&params),
             )
-            .await?;
+            .await
+            .map_err(|err| match err {
+                // 17: "No user matches for specified terms."
+                TwitterError::ApiCode(api_err) if api_err.has_code(17) => {
+                    TwitterError::UserNotFound
+                }
+                err => err,
+            })?;
 
         if user_objects.is_empty() {
-            return Err(TwitterError::UnrecognizedData.into());
+            return Err(TwitterError::UserNotFound.into());
         }
 
         Ok(user_objects
             .into_iter()
-            .map(|obj| (Account::from(format!("@{}", obj.screen_name)), obj.id))
+            .filter_map(|obj| {
+                normalize_twitter_handle(&Account::from(obj.screen_name)).map(|acc| (acc, obj.id))
+            })
             .collect())
     }
+    async fn lookup_user(&self, account: &Account) -> Result<TwitterUser> {
+        #[derive(Deserialize)]
+        struct UserObject {
+            id: TwitterId,
+            #[serde(default)]
+            protected: bool,
+        }
+
+        let screen_name = account.as_str().replace("@", "");
+
+        match self
+            .get_request::<UserObject>(
+                "https://api.twitter.com/1.1/users/show.json",
+                Some(&[("screen_name", screen_name.as_str())]),
+            )
+            .await
+        {
+            Ok(user) if user.protected => Ok(TwitterUser::Protected(user.id)),
+            Ok(user) => Ok(TwitterUser::Active(user.id)),
+            // 50: "User not found."
+            Err(TwitterError::ApiCode(api_err)) if api_err.has_code(50) => {
+                Ok(TwitterUser::NotFound)
+            }
+            // 63: "User has been suspended."
+            Err(TwitterError::ApiCode(api_err)) if api_err.has_code(63) => {
+                Ok(TwitterUser::Suspended)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
     async fn send_message(&self, id: &TwitterId, message: String) -> StdResult<(), TwitterError> {
         self.post_request::<ApiMessageSend, _>(
             "https://api.twitter.com/1.1/direct_messages/events/new.json",
@@ -659,6 +1542,60 @@ impl TwitterTransport for Twitter {
         .await
         .map(|_| ())
     }
+    async fn send_mention(
+        &self,
+        account: &Account,
+        message: String,
+    ) -> StdResult<(), TwitterError> {
+        let status = format!("{} {}", account.as_str(), message);
+
+        self.post_form_request::<serde_json::Value>(
+            "https://api.twitter.com/1.1/statuses/update.json",
+            &[("status", status.as_str())],
+        )
+        .await
+        .map(|_| ())
+    }
+    async fn request_tweets(
+        &self,
+        twitter_id: &TwitterId,
+        since_id: Option<u64>,
+    ) -> Result<Vec<Tweet>> {
+        #[derive(Deserialize)]
+        struct ApiTweet {
+            id: u64,
+            full_text: String,
+        }
+
+        let user_id = twitter_id.as_u64().to_string();
+        let since_id = since_id.map(|id| id.to_string());
+
+        let mut params = vec![
+            ("user_id", user_id.as_str()),
+            ("count", TWEETS_PAGE_SIZE),
+            ("trim_user", "true"),
+            ("include_rts", "false"),
+            ("tweet_mode", "extended"),
+        ];
+
+        if let Some(since_id) = &since_id {
+            params.push(("since_id", since_id.as_str()));
+        }
+
+        Ok(self
+            .get_request::<Vec<ApiTweet>>(
+                "https://api.twitter.com/1.1/statuses/user_timeline.json",
+                Some(&params),
+            )
+            .await?
+            .into_iter()
+            .map(|tweet| Tweet {
+                id: tweet.id,
+                sender: twitter_id.clone(),
+                text: tweet.full_text,
+            })
+            .collect())
+    }
     fn my_screen_name(&self) -> &Account {
         &self.screen_name
     }
@@ -673,10 +1610,13 @@ struct ApiMessageSend {
 struct ApiMessageRequest {
     // Used for receiving messages.
     events: Vec<ApiEvent>,
+    next_cursor: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct ApiEvent {
+    #[serde(skip_serializing)]
+    id: Option<String>,
     #[serde(rename = "type")]
     t_type: String,
     created_timestamp: Option<String>,
@@ -702,15 +1642,25 @@ struct ApiMessageData {
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ReceivedMessageContext {
+    /// ID of the direct message event.
+    pub id: u64,
     pub sender: TwitterId,
     pub message: String,
     pub created: u64,
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tweet {
+    pub id: u64,
+    pub sender: TwitterId,
+    pub text: String,
+}
+
 impl ApiMessageSend {
     fn new(recipient: &TwitterId, msg: String) -> Self {
         ApiMessageSend {
             event: ApiEvent {
+                id: None,
                 t_type: "message_create".to_string(),
                 created_timestamp: None,
                 message_create: ApiMessageCreate {
@@ -726,16 +1676,24 @@ impl ApiMessageSend {
 }
 
 impl ApiMessageRequest {
+    /// Returns the new messages, the new watermark and whether the watermark
+    /// was reached, i.e. no older messages must be fetched.
     fn get_messages(
         self,
         my_id: &TwitterId,
         watermark: u64,
-    ) -> Result<(Vec<ReceivedMessageContext>, u64)> {
+    ) -> Result<(Vec<ReceivedMessageContext>, u64, bool)> {
         let mut msgs = vec![];
 
+        let mut complete = false;
         let mut new_watermark = watermark;
         for event in self.events {
             let msg = ReceivedMessageContext {
+                id: event
+                    .id
+                    .ok_or(TwitterError::UnrecognizedData)?
+                    .parse::<u64>()
+                    .map_err(|_| TwitterError::UnrecognizedData)?,
                 sender: event
                     .message_create
                     .sender_id
@@ -749,7 +1707,9 @@ impl ApiMessageRequest {
                     .map_err(|_| TwitterError::UnrecognizedData)?,
             };
 
-            if &msg.sender != my_id && msg.created > watermark {
+            if msg.created <= watermark {
+                complete = true;
+            } else if &msg.sender != my_id {
                 if msg.created > new_watermark {
                     new_watermark = msg.created;
                 }
@@ -758,6 +1718,312 @@ impl ApiMessageRequest {
             }
         }
 
-        Ok((msgs, new_watermark))
+        Ok((msgs, new_watermark, complete))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::TwitterV2Builder;
+    use crate::manager::OnChainIdentity;
+    use crate::tests::mocks::{Event, EventManager2, TwitterEvent, TwitterMocker};
+    use tokio::runtime::Runtime;
+
+    // Generate a random db path
+    fn db_path() -> String {
+        format!("/tmp/sqlite_{}", Challenge::gen_random().as_str())
+    }
+
+    #[test]
+    fn normalize_twitter_handles() {
+        let expected = Some(Account::from("@alice_01"));
+
+        for handle in &[
+            "@alice_01",
+            "alice_01",
+            " @Alice_01\n",
+            "https://twitter.com/Alice_01",
+            "https://twitter.com/alice_01?lang=en",
+            "x.com/alice_01/",
+        ] {
+            assert_eq!(normalize_twitter_handle(&Account::from(*handle)), expected);
+        }
+
+        for handle in &["", "@", "@ali ce", "@alice.eth", "@alice_is_too_long"] {
+            assert_eq!(normalize_twitter_handle(&Account::from(*handle)), None);
+        }
+    }
+
+    #[test]
+    fn rate_limit_poll_delay() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-rate-limit-limit", HeaderValue::from_static("15"));
+        headers.insert("x-rate-limit-remaining", HeaderValue::from_static("10"));
+        headers.insert(
+            "x-rate-limit-reset",
+            HeaderValue::from_str(&(unix_time() + 599).to_string()).unwrap(),
+        );
+
+        let mut rate_limit = RateLimit::from_headers(&headers).unwrap();
+        assert_eq!(rate_limit.limit, 15);
+        assert_eq!(rate_limit.remaining, 10);
+
+        // The remaining requests are spread until the reset. Allow for the
+        // clock to advance during the test.
+        let delay = rate_limit.next_poll().as_secs();
+        assert!(delay == 59 || delay == 60);
+
+        rate_limit.remaining = 0;
+        let delay = rate_limit.next_poll().as_secs();
+        assert!(delay == 599 || delay == 600);
+
+        rate_limit.reset = 0;
+        assert_eq!(
+            rate_limit.next_poll(),
+            Duration::from_secs(MIN_POLL_INTERVAL)
+        );
+
+        headers.remove("x-rate-limit-reset");
+        assert!(RateLimit::from_headers(&headers).is_none());
+    }
+
+    #[test]
+    fn ignore_unknown_senders() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+            let manager = EventManager2::new();
+            let (sender, child) = manager.child();
+
+            let my_screen_name = Account::from("@registrar");
+            let my_id = TwitterId::from(1000);
+            let eve = Account::from("@eve");
+            let eve_id = TwitterId::from(2000);
+            let carol_id = TwitterId::from(3000);
+
+            // Eve exists, but has no pending judgement request. Carol does
+            // not exist anymore.
+            let transport = TwitterMocker::new(
+                child,
+                my_screen_name.clone(),
+                vec![(my_screen_name, my_id.clone()), (eve, eve_id.clone())],
+            );
+
+            let handler =
+                TwitterHandler::new(db.clone(), CommsVerifier::new(), Templates::default());
+
+            let message = |id: u64, sender: &TwitterId| ReceivedMessageContext {
+                id: id,
+                sender: sender.clone(),
+                message: String::from("Hello"),
+                created: id,
+            };
+
+            sender.send_message(message(10, &eve_id)).await;
+            sender.send_message(message(20, &carol_id)).await;
+
+            handler
+                .handle_incoming_messages(&transport, &my_id)
+                .await
+                .unwrap();
+
+            // The messages are tracked and the watermark advances.
+            let res = db.find_untracked_twitter_events(&[10, 20]).await.unwrap();
+            assert!(res.is_empty());
+
+            let watermark = db.select_watermark(&AccountType::Twitter).await.unwrap();
+            assert_eq!(watermark, Some(20));
+        });
+    }
+
+    #[test]
+    fn contact_users() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+            let manager = EventManager2::new();
+            let (_, child) = manager.child();
+
+            let my_screen_name = Account::from("@registrar");
+            let alice = Account::from("@alice");
+            let alice_id = TwitterId::from(2000);
+            let bob = Account::from("@bob");
+            let bob_id = TwitterId::from(3000);
+
+            let mut transport = TwitterMocker::new(
+                child,
+                my_screen_name.clone(),
+                vec![
+                    (my_screen_name.clone(), TwitterId::from(1000)),
+                    (alice.clone(), alice_id.clone()),
+                    (bob.clone(), bob_id.clone()),
+                ],
+            );
+            transport.close_direct_messages(bob_id.clone());
+
+            let handler =
+                TwitterHandler::new(db.clone(), CommsVerifier::new(), Templates::default());
+
+            // Bob uses the same account for several identities.
+            let mut idents = vec![];
+            for (net_account, account) in &[
+                (NetAccount::alice(), &alice),
+                (NetAccount::bob(), &bob),
+                (NetAccount::eve(), &bob),
+            ] {
+                let mut ident = OnChainIdentity::new(net_account.clone()).unwrap();
+                ident
+                    .push_account(AccountType::Twitter, (*account).clone())
+                    .unwrap();
+                idents.push(ident);
+            }
+
+            db.insert_identity_batch(&idents.iter().collect::<Vec<&OnChainIdentity>>())
+                .await
+                .unwrap();
+
+            // Alice is contacted via direct message.
+            handler
+                .handle_account_verification(&transport, NetAccount::alice(), alice.clone())
+                .await
+                .unwrap();
+
+            let events = manager.events().await;
+            match events.last().unwrap() {
+                Event::Twitter(TwitterEvent::SendMessage { id, .. }) => {
+                    assert_eq!(id, &alice_id);
+                }
+                _ => panic!("expected a direct message to Alice"),
+            }
+
+            // Bob does not accept direct messages and is mentioned instead.
+            handler
+                .handle_account_verification(&transport, NetAccount::bob(), bob.clone())
+                .await
+                .unwrap();
+
+            let events = manager.events().await;
+            match events.last().unwrap() {
+                Event::Twitter(TwitterEvent::SendMention { account, message }) => {
+                    assert_eq!(account, &bob);
+                    assert!(message.contains(my_screen_name.as_str()));
+                }
+                _ => panic!("expected a mention of Bob"),
+            }
+
+            // The tweets of Bob are checked for signatures.
+            let res = db.select_tweet_verifications().await.unwrap();
+            assert_eq!(res, vec![(bob.clone(), bob_id.clone(), None)]);
+
+            // Nothing to retry.
+            let res = db.select_twitter_contacts().await.unwrap();
+            assert!(res.is_empty());
+        });
+    }
+
+    #[test]
+    fn check_tweets_per_user() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+            let manager = EventManager2::new();
+            let (_, child) = manager.child();
+
+            let my_screen_name = Account::from("@registrar");
+            let alice = Account::from("@alice");
+            let alice_id = TwitterId::from(2000);
+            let bob = Account::from("@bob");
+            let bob_id = TwitterId::from(3000);
+
+            let mut transport = TwitterMocker::new(
+                child,
+                my_screen_name.clone(),
+                vec![
+                    (my_screen_name.clone(), TwitterId::from(1000)),
+                    (alice.clone(), alice_id.clone()),
+                    (bob.clone(), bob_id.clone()),
+                ],
+            );
+            transport.delete_user(alice_id.clone());
+
+            let handler =
+                TwitterHandler::new(db.clone(), CommsVerifier::new(), Templates::default());
+
+            let mut alice_ident = OnChainIdentity::new(NetAccount::alice()).unwrap();
+            alice_ident
+                .push_account(AccountType::Twitter, alice.clone())
+                .unwrap();
+
+            let mut bob_ident = OnChainIdentity::new(NetAccount::bob()).unwrap();
+            bob_ident
+                .push_account(AccountType::Twitter, bob.clone())
+                .unwrap();
+
+            db.insert_identity_batch(&[&alice_ident, &bob_ident])
+                .await
+                .unwrap();
+
+            db.insert_tweet_verification(&alice, &alice_id)
+                .await
+                .unwrap();
+            db.insert_tweet_verification(&bob, &bob_id).await.unwrap();
+
+            // The account of Alice was deleted, which does not prevent checking
+            // the tweets of Bob.
+            handler
+                .handle_tweet_verifications(&transport)
+                .await
+                .unwrap();
+
+            let events = manager.events().await;
+            assert!(
+                events.contains(&Event::Twitter(TwitterEvent::RequestTweets {
+                    twitter_id: bob_id.clone(),
+                    since_id: None,
+                }))
+            );
+
+            // Alice is no longer checked.
+            let res = db.select_tweet_verifications().await.unwrap();
+            assert_eq!(res, vec![(bob.clone(), bob_id.clone(), None)]);
+        });
+    }
+
+    #[test]
+    fn fallback_to_v1() {
+        let screen_name = Account::from("@registrar");
+
+        let v1 = TwitterBuilder::new()
+            .screen_name(screen_name.clone())
+            .consumer_key(String::from("key"))
+            .consumer_secret(String::from("secret"))
+            .sig_method(String::from("HMAC-SHA1"))
+            .token(String::from("token"))
+            .token_secret(String::from("token_secret"))
+            .version(1.0)
+            .build()
+            .unwrap();
+
+        let v2 = TwitterV2Builder::new()
+            .db(Database2::new(&db_path()).unwrap())
+            .screen_name(screen_name)
+            .client_id(String::from("client_id"))
+            .refresh_token(String::from("refresh_token"))
+            .build()
+            .unwrap();
+
+        let token_err = TwitterError::Token(failure::err_msg("invalid refresh token"));
+
+        let client = TwitterClient::from(v2);
+        assert!(client.fallback(&token_err).is_none());
+
+        let client = client.with_fallback(v1.clone());
+        assert!(client.fallback(&token_err).is_some());
+        assert!(client.fallback(&TwitterError::UserNotFound).is_none());
+
+        // Only the API v2 client falls back.
+        let client = TwitterClient::from(v1.clone()).with_fallback(v1);
+        assert!(client.fallback(&token_err).is_none());
     }
 }
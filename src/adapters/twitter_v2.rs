@@ -0,0 +1,708 @@
+use super::twitter::{
+    normalize_twitter_handle, RateLimit, ReceivedMessageContext, Tweet, TwitterError, TwitterId,
+    TwitterTransport, TwitterUser,
+};
+use crate::db::Database2;
+use crate::primitives::{unix_time, Account, Result};
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use std::convert::TryFrom;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration};
+
+const API_URL: &str = "https://api.twitter.com/2";
+/// Access tokens are refreshed that long before they expire, in seconds.
+const TOKEN_EXPIRY_MARGIN: u64 = 60;
+/// How often persisting a refreshed token is attempted, with a delay in
+/// milliseconds in between.
+const TOKEN_PERSIST_ATTEMPTS: u32 = 3;
+const TOKEN_PERSIST_DELAY: u64 = 500;
+/// Number of direct message events fetched per page (max. 100).
+const MESSAGES_PAGE_SIZE: &str = "100";
+/// Epoch of Twitter snowflake IDs, in milliseconds.
+const SNOWFLAKE_EPOCH: u64 = 1288834974657;
+
+/// OAuth 2.0 user context token.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TwitterToken {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Unix timestamp of when the access token expires.
+    pub expires_at: u64,
+}
+
+pub struct TwitterV2Builder {
+    db: Option<Database2>,
+    screen_name: Option<Account>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    refresh_token: Option<String>,
+}
+
+impl TwitterV2Builder {
+    pub fn new() -> Self {
+        TwitterV2Builder {
+            db: None,
+            screen_name: None,
+            client_id: None,
+            client_secret: None,
+            refresh_token: None,
+        }
+    }
+    /// The database is used to persist the rotated refresh tokens.
+    pub fn db(mut self, db: Database2) -> Self {
+        self.db = Some(db);
+        self
+    }
+    pub fn screen_name(mut self, account: Account) -> Self {
+        self.screen_name = Some(account);
+        self
+    }
+    pub fn client_id(mut self, id: String) -> Self {
+        self.client_id = Some(id);
+        self
+    }
+    /// Only required for confidential clients.
+    pub fn client_secret(mut self, secret: String) -> Self {
+        self.client_secret = Some(secret);
+        self
+    }
+    /// The initial refresh token, only used if no token was persisted yet.
+    pub fn refresh_token(mut self, token: String) -> Self {
+        self.refresh_token = Some(token);
+        self
+    }
+    pub fn build(self) -> Result<TwitterV2> {
+        Ok(TwitterV2 {
+            client: Client::new(),
+            db: self.db.ok_or(TwitterError::IncompleteBuilder)?,
+            screen_name: self.screen_name.ok_or(TwitterError::IncompleteBuilder)?,
+            client_id: self.client_id.ok_or(TwitterError::IncompleteBuilder)?,
+            client_secret: self.client_secret,
+            refresh_token: self.refresh_token.ok_or(TwitterError::IncompleteBuilder)?,
+            token: Arc::new(Mutex::new(None)),
+        })
+    }
+}
+
+/// Client for the Twitter API v2, authenticated with OAuth 2.0 user context
+/// tokens. Requires the `dm.read`, `dm.write`, `tweet.read`, `tweet.write`,
+/// `users.read` and `offline.access` scopes.
+#[derive(Clone)]
+pub struct TwitterV2 {
+    client: Client,
+    db: Database2,
+    screen_name: Account,
+    client_id: String,
+    client_secret: Option<String>,
+    refresh_token: String,
+    token: Arc<Mutex<Option<TwitterToken>>>,
+}
+
+impl TwitterV2 {
+    /// Returns a valid access token, refreshing it if required.
+    async fn access_token(&self, force_refresh: bool) -> StdResult<String, TwitterError> {
+        let mut token = self.token.lock().await;
+
+        if token.is_none() {
+            *token = self
+                .db
+                .select_twitter_token()
+                .await
+                .map_err(|err| TwitterError::Token(err))?;
+        }
+
+        if let Some(token) = token.as_ref() {
+            if !force_refresh && token.expires_at > unix_time() + TOKEN_EXPIRY_MARGIN {
+                return Ok(token.access_token.clone());
+            }
+        }
+
+        let refresh_token = token
+            .as_ref()
+            .map(|token| token.refresh_token.clone())
+            .unwrap_or_else(|| self.refresh_token.clone());
+
+        debug!("Refreshing Twitter access token");
+        let new_token = self.refresh(&refresh_token).await?;
+        let access_token = new_token.access_token.clone();
+
+        // The previous refresh token is invalidated by the refresh, so the
+        // new one must not get lost. It is kept in memory regardless, but the
+        // secret itself is never logged.
+        let mut attempts = 0;
+        while let Err(err) = self.db.update_twitter_token(&new_token).await {
+            attempts += 1;
+            if attempts >= TOKEN_PERSIST_ATTEMPTS {
+                error!(
+                    "Failed to persist the new Twitter refresh token, it is only kept in memory \
+                    and must be re-authorized after a restart: {}",
+                    err
+                );
+                break;
+            }
+
+            warn!("Failed to persist the new Twitter token, retrying: {}", err);
+            time::delay_for(Duration::from_millis(TOKEN_PERSIST_DELAY)).await;
+        }
+
+        *token = Some(new_token);
+
+        Ok(access_token)
+    }
+    async fn refresh(&self, refresh_token: &str) -> StdResult<TwitterToken, TwitterError> {
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+            refresh_token: String,
+            expires_in: u64,
+        }
+
+        let mut request = self
+            .client
+            .post(&format!("{}/oauth2/token", API_URL))
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+                ("client_id", self.client_id.as_str()),
+            ]);
+
+        if let Some(secret) = &self.client_secret {
+            request = request.basic_auth(&self.client_id, Some(secret));
+        }
+
+        let resp = request
+            .send()
+            .await
+            .map_err(|err| TwitterError::Token(err.into()))?;
+
+        if !resp.status().is_success() {
+            let txt = resp.text().await.unwrap_or_default();
+            return Err(TwitterError::Token(failure::err_msg(txt)));
+        }
+
+        let resp = resp
+            .json::<TokenResponse>()
+            .await
+            .map_err(|err| TwitterError::Token(err.into()))?;
+
+        Ok(TwitterToken {
+            access_token: resp.access_token,
+            refresh_token: resp.refresh_token,
+            expires_at: unix_time() + resp.expires_in,
+        })
+    }
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> StdResult<(T, Option<RateLimit>), TwitterError> {
+        let url = format!("{}{}", API_URL, path);
+
+        let mut force_refresh = false;
+        let resp = loop {
+            let token = self.access_token(force_refresh).await?;
+
+            let resp = build(self.client.request(method.clone(), &url))
+                .bearer_auth(token)
+                .send()
+                .await
+                .map_err(|err| TwitterError::Http(err.into()))?;
+
+            // The access token might have been revoked, retry once.
+            if resp.status() == StatusCode::UNAUTHORIZED && !force_refresh {
+                force_refresh = true;
+                continue;
+            }
+
+            break resp;
+        };
+
+        let status = resp.status();
+        let rate_limit = RateLimit::from_headers(resp.headers());
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(TwitterError::RateLimited(rate_limit.unwrap_or(RateLimit {
+                limit: 0,
+                remaining: 0,
+                reset: unix_time() + super::twitter::DEFAULT_POLL_INTERVAL,
+            })));
+        }
+
+        let txt = resp
+            .text()
+            .await
+            .map_err(|_| TwitterError::UnrecognizedData)?;
+
+        trace!("{} response: {}", method, txt);
+
+        if !status.is_success() {
+            // Direct messages are rejected with "403 Forbidden" if the user
+            // does not accept messages from the registrar account.
+            if status == StatusCode::FORBIDDEN && path.starts_with("/dm_conversations") {
+                return Err(TwitterError::DirectMessagesClosed);
+            }
+
+            // Deleted or suspended users.
+            if status == StatusCode::NOT_FOUND && path.starts_with("/users/") {
+                return Err(TwitterError::UserNotFound);
+            }
+
+            return Err(TwitterError::Api(txt));
+        }
+
+        serde_json::from_str::<T>(&txt)
+            .map(|resp| (resp, rate_limit))
+            .map_err(|err| TwitterError::Serde(err.into()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiUsers {
+    #[serde(default)]
+    data: Vec<ApiUser>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiUser {
+    id: String,
+    username: String,
+    #[serde(default)]
+    protected: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiUserLookup {
+    data: Option<ApiUser>,
+    #[serde(default)]
+    errors: Vec<ApiProblem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiProblem {
+    #[serde(rename = "type", default)]
+    p_type: String,
+    #[serde(default)]
+    detail: String,
+}
+
+impl ApiUserLookup {
+    fn user_state(self) -> Result<TwitterUser> {
+        if let Some(user) = self.data {
+            let twitter_id = TwitterId::try_from(user.id)?;
+
+            return Ok(if user.protected {
+                TwitterUser::Protected(twitter_id)
+            } else {
+                TwitterUser::Active(twitter_id)
+            });
+        }
+
+        // Suspended accounts are reported as "not authorized for resource",
+        // with the reason in the details.
+        if self
+            .errors
+            .iter()
+            .any(|problem| problem.detail.contains("suspended"))
+        {
+            Ok(TwitterUser::Suspended)
+        } else if self
+            .errors
+            .iter()
+            .any(|problem| problem.p_type.ends_with("/resource-not-found"))
+        {
+            Ok(TwitterUser::NotFound)
+        } else {
+            Err(TwitterError::UnrecognizedData.into())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiDmEvents {
+    #[serde(default)]
+    data: Vec<ApiDmEvent>,
+    meta: Option<ApiMeta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiDmEvent {
+    id: String,
+    event_type: String,
+    text: Option<String>,
+    sender_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiTweets {
+    #[serde(default)]
+    data: Vec<ApiTweet>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiTweet {
+    id: String,
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiMeta {
+    next_token: Option<String>,
+}
+
+impl ApiDmEvents {
+    /// Returns the new messages, the new watermark and whether the watermark
+    /// was reached, i.e. no older messages must be fetched.
+    fn get_messages(
+        self,
+        my_id: &TwitterId,
+        watermark: u64,
+    ) -> Result<(Vec<ReceivedMessageContext>, u64, bool)> {
+        let mut msgs = vec![];
+
+        let mut complete = false;
+        let mut new_watermark = watermark;
+        for event in self.data {
+            if event.event_type != "MessageCreate" {
+                continue;
+            }
+
+            let created = snowflake_timestamp(&event.id)?;
+            if created <= watermark {
+                complete = true;
+                continue;
+            }
+
+            let sender =
+                TwitterId::try_from(event.sender_id.ok_or(TwitterError::UnrecognizedData)?)?;
+            if &sender == my_id {
+                continue;
+            }
+
+            if created > new_watermark {
+                new_watermark = created;
+            }
+
+            msgs.push(ReceivedMessageContext {
+                id: event
+                    .id
+                    .parse()
+                    .map_err(|_| TwitterError::UnrecognizedData)?,
+                sender: sender,
+                message: event.text.unwrap_or_default(),
+                created: created,
+            });
+        }
+
+        Ok((msgs, new_watermark, complete))
+    }
+}
+
+/// Returns the creation time of an event in milliseconds, which is encoded in
+/// its snowflake ID. The API v1.1 uses the same timestamps, so the watermark
+/// is compatible.
+fn snowflake_timestamp(id: &str) -> StdResult<u64, TwitterError> {
+    id.parse::<u64>()
+        .map(|id| (id >> 22) + SNOWFLAKE_EPOCH)
+        .map_err(|_| TwitterError::UnrecognizedData)
+}
+
+#[async_trait]
+impl TwitterTransport for TwitterV2 {
+    async fn request_messages(
+        &self,
+        exclude: &TwitterId,
+        watermark: u64,
+    ) -> Result<(Vec<ReceivedMessageContext>, u64, Option<RateLimit>)> {
+        let mut messages = vec![];
+        let mut new_watermark = watermark;
+        let mut cursor: Option<String> = None;
+
+        loop {
+            let mut params = vec![
+                ("event_types", "MessageCreate"),
+                ("dm_event.fields", "id,event_type,text,sender_id"),
+                ("max_results", MESSAGES_PAGE_SIZE),
+            ];
+            if let Some(cursor) = &cursor {
+                params.push(("pagination_token", cursor.as_str()));
+            }
+
+            let (page, rate_limit) = self
+                .request::<ApiDmEvents>(Method::GET, "/dm_events", |req| req.query(&params))
+                .await?;
+
+            let next_cursor = page.meta.as_ref().and_then(|meta| meta.next_token.clone());
+            let (mut page_messages, page_watermark, complete) =
+                page.get_messages(exclude, watermark)?;
+
+            messages.append(&mut page_messages);
+            new_watermark = new_watermark.max(page_watermark);
+
+            // Same as for the API v1.1, the events are returned in reverse
+            // chronological order.
+            match next_cursor {
+                Some(next_cursor) if !complete => {
+                    if let Some(rate_limit) = rate_limit {
+                        if rate_limit.remaining == 0 {
+                            return Err(TwitterError::RateLimited(rate_limit).into());
+                        }
+                    }
+
+                    cursor = Some(next_cursor);
+                }
+                _ => return Ok((messages, new_watermark, rate_limit)),
+            }
+        }
+    }
+    async fn lookup_twitter_id(
+        &self,
+        twitter_ids: Option<&[&TwitterId]>,
+        accounts: Option<&[&Account]>,
+    ) -> Result<Vec<(Account, TwitterId)>> {
+        let mut users = vec![];
+
+        if let Some(twitter_ids) = twitter_ids {
+            let ids = twitter_ids
+                .iter()
+                .map(|id| id.as_u64().to_string())
+                .collect::<Vec<String>>()
+                .join(",");
+
+            let (mut resp, _) = self
+                .request::<ApiUsers>(Method::GET, "/users", |req| {
+                    req.query(&[("ids", ids.as_str())])
+                })
+                .await?;
+
+            users.append(&mut resp.data);
+        }
+
+        if let Some(accounts) = accounts {
+            let usernames = accounts
+                .iter()
+                .map(|account| account.as_str().replace("@", ""))
+                .collect::<Vec<String>>()
+                .join(",");
+
+            let (mut resp, _) = self
+                .request::<ApiUsers>(Method::GET, "/users/by", |req| {
+                    req.query(&[("usernames", usernames.as_str())])
+                })
+                .await?;
+
+            users.append(&mut resp.data);
+        }
+
+        // Unknown users are reported in the `errors` field, alongside the
+        // found users.
+        if users.is_empty() {
+            return Err(TwitterError::UserNotFound.into());
+        }
+
+        users
+            .into_iter()
+            .filter_map(|user| {
+                normalize_twitter_handle(&Account::from(user.username))
+                    .map(|account| (account, user.id))
+            })
+            .map(|(account, id)| Ok((account, TwitterId::try_from(id)?)))
+            .collect()
+    }
+    async fn lookup_user(&self, account: &Account) -> Result<TwitterUser> {
+        let (lookup, _) = self
+            .request::<ApiUserLookup>(
+                Method::GET,
+                &format!("/users/by/username/{}", account.as_str().replace("@", "")),
+                |req| req.query(&[("user.fields", "protected")]),
+            )
+            .await?;
+
+        lookup.user_state()
+    }
+    async fn send_message(&self, id: &TwitterId, message: String) -> StdResult<(), TwitterError> {
+        let body = serde_json::json!({ "text": message });
+
+        self.request::<serde_json::Value>(
+            Method::POST,
+            &format!("/dm_conversations/with/{}/messages", id.as_u64()),
+            |req| req.json(&body),
+        )
+        .await
+        .map(|_| ())
+    }
+    async fn send_mention(
+        &self,
+        account: &Account,
+        message: String,
+    ) -> StdResult<(), TwitterError> {
+        let body = serde_json::json!({ "text": format!("{} {}", account.as_str(), message) });
+
+        self.request::<serde_json::Value>(Method::POST, "/tweets", |req| req.json(&body))
+            .await
+            .map(|_| ())
+    }
+    async fn request_tweets(
+        &self,
+        twitter_id: &TwitterId,
+        since_id: Option<u64>,
+    ) -> Result<Vec<Tweet>> {
+        let since_id = since_id.map(|id| id.to_string());
+
+        let (tweets, _) = self
+            .request::<ApiTweets>(
+                Method::GET,
+                &format!("/users/{}/tweets", twitter_id.as_u64()),
+                |req| {
+                    let req = req.query(&[("max_results", "20"), ("exclude", "retweets")]);
+
+                    if let Some(since_id) = &since_id {
+                        req.query(&[("since_id", since_id.as_str())])
+                    } else {
+                        req
+                    }
+                },
+            )
+            .await?;
+
+        tweets
+            .data
+            .into_iter()
+            .map(|tweet| {
+                Ok(Tweet {
+                    id: tweet
+                        .id
+                        .parse()
+                        .map_err(|_| TwitterError::UnrecognizedData)?,
+                    sender: twitter_id.clone(),
+                    text: tweet.text,
+                })
+            })
+            .collect()
+    }
+    fn my_screen_name(&self) -> &Account {
+        &self.screen_name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_lookup_state() {
+        let lookup = serde_json::from_str::<ApiUserLookup>(
+            r#"{
+                "data": {
+                    "id": "2244994945",
+                    "name": "Twitter Dev",
+                    "username": "TwitterDev",
+                    "protected": true
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            lookup.user_state().unwrap(),
+            TwitterUser::Protected(TwitterId::from(2244994945))
+        );
+
+        let lookup = serde_json::from_str::<ApiUserLookup>(
+            r#"{
+                "errors": [
+                    {
+                        "value": "alice",
+                        "detail": "User has been suspended: [alice].",
+                        "title": "Forbidden",
+                        "resource_type": "user",
+                        "parameter": "username",
+                        "resource_id": "alice",
+                        "type": "https://api.twitter.com/2/problems/resource-not-found"
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(lookup.user_state().unwrap(), TwitterUser::Suspended);
+
+        let lookup = serde_json::from_str::<ApiUserLookup>(
+            r#"{
+                "errors": [
+                    {
+                        "value": "bob",
+                        "detail": "Could not find user with username: [bob].",
+                        "title": "Not Found Error",
+                        "resource_type": "user",
+                        "parameter": "username",
+                        "resource_id": "bob",
+                        "type": "https://api.twitter.com/2/problems/resource-not-found"
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(lookup.user_state().unwrap(), TwitterUser::NotFound);
+    }
+
+    #[test]
+    fn get_dm_events() {
+        let events = serde_json::from_str::<ApiDmEvents>(
+            r#"{
+                "data": [
+                    {
+                        "id": "1580705921830768647",
+                        "event_type": "MessageCreate",
+                        "text": "second",
+                        "sender_id": "2244994945"
+                    },
+                    {
+                        "id": "1580705921830768646",
+                        "event_type": "ParticipantsJoin"
+                    },
+                    {
+                        "id": "1580705921830768645",
+                        "event_type": "MessageCreate",
+                        "text": "own message",
+                        "sender_id": "1"
+                    },
+                    {
+                        "id": "1480705921830768640",
+                        "event_type": "MessageCreate",
+                        "text": "old",
+                        "sender_id": "2244994945"
+                    }
+                ],
+                "meta": {
+                    "result_count": 4,
+                    "next_token": "18LAA581J5II7LA00C00ZZZZ"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let created = snowflake_timestamp("1580705921830768647").unwrap();
+        assert_eq!(created, 1665704634517);
+
+        let watermark = snowflake_timestamp("1480705921830768640").unwrap();
+        let (messages, new_watermark, complete) =
+            events.get_messages(&TwitterId::from(1), watermark).unwrap();
+
+        assert_eq!(
+            messages,
+            vec![ReceivedMessageContext {
+                id: 1580705921830768647,
+                sender: TwitterId::from(2244994945),
+                message: "second".to_string(),
+                created: created,
+            }]
+        );
+        assert_eq!(new_watermark, created);
+        assert!(complete);
+    }
+}
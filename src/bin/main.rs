@@ -4,8 +4,8 @@ extern crate log;
 use failure::Error;
 use registrar::{block, init_env, run};
 use registrar::{
-    Account, Database2, HealthCheck, MatrixClient, SmtpImapClientBuilder, TwitterBuilder,
-    WebSocketReader, WebSocketWriter, WebSockets,
+    Database2, HealthCheck, MatrixClient, SmtpImapClientBuilder, WebSocketReader, WebSocketWriter,
+    WebSockets,
 };
 
 #[tokio::main]
@@ -15,10 +15,14 @@ async fn main() -> Result<(), Error> {
     info!("Setting up database");
     let db2 = Database2::new(&config.registrar_db_path)?;
 
+    // Adapters report themselves as unhealthy again if the issue persists.
+    db2.clear_unhealthy_adapters().await?;
+
     info!("Starting health check thread");
     if config.enable_health_check {
-        std::thread::spawn(|| {
-            HealthCheck::start()
+        let l_db = db2.clone();
+        std::thread::spawn(move || {
+            HealthCheck::start(l_db)
                 .map_err(|err| {
                     error!("Failed to start health check service: {}", err);
                     std::process::exit(1);
@@ -28,6 +32,11 @@ async fn main() -> Result<(), Error> {
     }
 
     if config.enable_accounts {
+        let email_mode = config.email_verification_mode();
+
+        info!("Loading message templates");
+        let templates = config.templates()?;
+
         info!("Setting up Matrix client");
         let matrix_transport = MatrixClient::new(
             &config.matrix_homeserver,
@@ -39,24 +48,21 @@ async fn main() -> Result<(), Error> {
         .await?;
 
         info!("Setting up Twitter client");
-        let twitter_transport = TwitterBuilder::new()
-            .screen_name(Account::from(config.twitter_screen_name))
-            .consumer_key(config.twitter_api_key)
-            .consumer_secret(config.twitter_api_secret)
-            .sig_method("HMAC-SHA1".to_string())
-            .token(config.twitter_token)
-            .token_secret(config.twitter_token_secret)
-            .version(1.0)
-            .build()?;
+        let twitter_transport = config.twitter_client(db2.clone())?;
 
         info!("Setting up Email client");
-        let email_transport = SmtpImapClientBuilder::new()
+        let mut email_builder = SmtpImapClientBuilder::new()
             .email_server(config.email_server)
             .imap_server(config.imap_server)
             .email_inbox(config.email_inbox)
             .email_user(config.email_user)
-            .email_password(config.email_password)
-            .build()?;
+            .email_password(config.email_password);
+
+        if let Some(domain) = config.email_message_id_domain {
+            email_builder = email_builder.message_id_domain(domain);
+        }
+
+        let email_transport = email_builder.build()?;
 
         run::<WebSockets, WebSocketWriter, WebSocketReader, _, _, _, _>(
             config.enable_watcher,
@@ -65,6 +71,8 @@ async fn main() -> Result<(), Error> {
             matrix_transport,
             twitter_transport,
             email_transport,
+            email_mode,
+            templates,
         )
         .await
         .map_err(|err| {
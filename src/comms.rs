@@ -1,4 +1,4 @@
-use crate::manager::OnChainIdentity;
+use crate::manager::{InvalidReason, OnChainIdentity};
 use crate::primitives::{Account, AccountType, Fatal, Judgement, NetAccount};
 #[cfg(test)]
 use crate::tests::mocks::MatrixEventMock;
@@ -39,10 +39,13 @@ pub enum CommsMessage {
     NotifyStatusChange {
         net_account: NetAccount,
     },
+    CancelJudgementRequest {
+        net_account: NetAccount,
+    },
     MessageAcknowledged,
     NotifyInvalidAccount {
         net_account: NetAccount,
-        accounts: Vec<(AccountType, Account)>,
+        accounts: Vec<(AccountType, Account, Option<InvalidReason>)>,
     },
     ExistingDisplayNames {
         accounts: Vec<Account>,
@@ -89,7 +92,7 @@ impl CommsMain {
     pub fn notify_invalid_accounts(
         &self,
         net_account: NetAccount,
-        accounts: Vec<(AccountType, Account)>,
+        accounts: Vec<(AccountType, Account, Option<InvalidReason>)>,
     ) {
         self.sender
             .send(CommsMessage::NotifyInvalidAccount {
@@ -157,6 +160,13 @@ impl CommsVerifier {
             })
             .fatal()
     }
+    pub fn notify_cancel(&self, net_account: NetAccount) {
+        self.sender
+            .send(CommsMessage::CancelJudgementRequest {
+                net_account: net_account,
+            })
+            .fatal()
+    }
     pub fn notify_ack(&self) {
         self.sender.send(CommsMessage::MessageAcknowledged).fatal();
     }
@@ -1,6 +1,8 @@
 use super::Result;
-use crate::adapters::{EmailId, TwitterId};
-use crate::manager::{AccountStatus, OnChainIdentity};
+use crate::adapters::{
+    normalize_matrix_id, normalize_twitter_handle, EmailId, TwitterId, TwitterToken,
+};
+use crate::manager::{AccountStatus, InvalidReason, OnChainIdentity};
 use crate::primitives::{
     unix_time, Account, AccountType, Challenge, ChallengeStatus, NetAccount, NetworkAddress,
 };
@@ -128,6 +130,7 @@ impl Database2 {
                 account_status_id    INTEGER NOT NULL,
                 challenge            TEXT NOT NULL,
                 challenge_status_id  INTEGER NOT NULL,
+                invalid_reason       TEXT,
 
                 UNIQUE (net_account_id, account_ty_id)
 
@@ -147,6 +150,18 @@ impl Database2 {
             params![],
         )?;
 
+        // Tables created by earlier versions lack the invalid reason column.
+        if con
+            .prepare("SELECT invalid_reason FROM account_states")
+            .is_err()
+        {
+            con.execute(
+                "ALTER TABLE account_states
+                    ADD COLUMN invalid_reason TEXT",
+                params![],
+            )?;
+        }
+
         // Table for known Matrix rooms.
         con.execute(
             "CREATE TABLE IF NOT EXISTS known_matrix_rooms (
@@ -161,6 +176,60 @@ impl Database2 {
             params![],
         )?;
 
+        // Table for direct message rooms with Matrix users, which are reused
+        // across identities.
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS matrix_direct_rooms (
+                id          INTEGER PRIMARY KEY,
+                account     TEXT NOT NULL UNIQUE,
+                room_id     TEXT NOT NULL
+            )",
+            params![],
+        )?;
+
+        // Table for Matrix rooms which must be left. Leaving rooms is subject
+        // to rate limits, so this is retried in the background.
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS matrix_rooms_to_leave (
+                id              INTEGER PRIMARY KEY,
+                room_id         TEXT NOT NULL UNIQUE,
+                attempts        INTEGER NOT NULL,
+                next_attempt    INTEGER NOT NULL
+            )",
+            params![],
+        )?;
+
+        // Table for Matrix users whose homeserver could not be reached. The
+        // lookup is retried in the background before marking them invalid.
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS matrix_user_lookups (
+                id              INTEGER PRIMARY KEY,
+                net_account     TEXT NOT NULL,
+                account         TEXT NOT NULL,
+                attempts        INTEGER NOT NULL,
+                next_attempt    INTEGER NOT NULL,
+
+                UNIQUE (net_account, account)
+            )",
+            params![],
+        )?;
+
+        // Table for Twitter users who could not be contacted, e.g. because
+        // the Twitter API failed. Contacting them is retried in the
+        // background.
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS twitter_contact_retries (
+                id              INTEGER PRIMARY KEY,
+                net_account     TEXT NOT NULL,
+                account         TEXT NOT NULL,
+                attempts        INTEGER NOT NULL,
+                next_attempt    INTEGER NOT NULL,
+
+                UNIQUE (net_account, account)
+            )",
+            params![],
+        )?;
+
         // Table for known Twitter IDs.
         con.execute(
             "
@@ -195,18 +264,124 @@ impl Database2 {
             params![],
         )?;
 
+        // Table for adapters which are currently unhealthy, e.g. because the
+        // remote service could not be reached on startup.
+        con.execute(
+            "
+            CREATE TABLE IF NOT EXISTS unhealthy_adapters (
+                id             INTEGER PRIMARY KEY,
+                account_ty_id  INTEGER NOT NULL UNIQUE,
+                reason         TEXT NOT NULL,
+                timestamp      INTEGER NOT NULL,
+
+                FOREIGN KEY (account_ty_id)
+                    REFERENCES account_types (id)
+                        ON DELETE CASCADE
+            )
+        ",
+            params![],
+        )?;
+
+        // Table for the OAuth 2.0 token of the Twitter API v2. Refresh tokens
+        // are rotated on every use, so the latest one must be persisted.
+        con.execute(
+            "
+            CREATE TABLE IF NOT EXISTS twitter_oauth2_token (
+                id             INTEGER PRIMARY KEY,
+                access_token   TEXT NOT NULL,
+                refresh_token  TEXT NOT NULL,
+                expires_at     INTEGER NOT NULL
+            )
+        ",
+            params![],
+        )?;
+
+        // Table for Twitter accounts which are verified via a public tweet,
+        // including the ID of the last tweet that was checked.
+        con.execute(
+            "
+            CREATE TABLE IF NOT EXISTS twitter_tweet_verifications (
+                id          INTEGER PRIMARY KEY,
+                account     TEXT NOT NULL UNIQUE,
+                twitter_id  INTEGER NOT NULL,
+                since_id    INTEGER
+            )
+        ",
+            params![],
+        )?;
+
+        // Table for processed Twitter direct message events.
+        con.execute(
+            "
+            CREATE TABLE IF NOT EXISTS twitter_processed_events (
+                id          INTEGER PRIMARY KEY,
+                event_id    INTEGER NOT NULL UNIQUE,
+                twitter_id  INTEGER NOT NULL,
+                timestamp   INTEGER NOT NULL
+            )
+        ",
+            params![],
+        )?;
+
         // Table for processed email IDs.
         con.execute(
             "
             CREATE TABLE IF NOT EXISTS email_processed_ids (
+                id            INTEGER PRIMARY KEY,
+                email_id      INTEGER NOT NULL UNIQUE,
+                uid_validity  INTEGER NOT NULL,
+                timestamp     INTEGER NOT NULL
+            )
+        ",
+            params![],
+        )?;
+
+        // Tables created by earlier versions lack the UIDVALIDITY column. The
+        // UIDVALIDITY of those entries is unknown (0) and gets adopted from
+        // the mailbox, see `invalidate_email_ids`.
+        if con
+            .prepare("SELECT uid_validity FROM email_processed_ids")
+            .is_err()
+        {
+            con.execute(
+                "ALTER TABLE email_processed_ids
+                    ADD COLUMN uid_validity INTEGER NOT NULL DEFAULT 0",
+                params![],
+            )?;
+        }
+
+        // Table for the Message-IDs of sent emails, used to authenticate
+        // bounces.
+        con.execute(
+            "
+            CREATE TABLE IF NOT EXISTS email_sent_message_ids (
                 id          INTEGER PRIMARY KEY,
-                email_id    INTEGER NOT NULL UNIQUE,
+                message_id  TEXT NOT NULL UNIQUE,
+                account     TEXT NOT NULL,
                 timestamp   INTEGER NOT NULL
             )
         ",
             params![],
         )?;
 
+        // Table for one-time email verification codes.
+        con.execute(
+            "
+            CREATE TABLE IF NOT EXISTS email_verification_codes (
+                id          INTEGER PRIMARY KEY,
+                account_id  INTEGER NOT NULL UNIQUE,
+                code        TEXT NOT NULL UNIQUE,
+                confirmed   INTEGER NOT NULL,
+                created     INTEGER NOT NULL,
+
+                FOREIGN KEY (account_id)
+                    REFERENCES account_states (id)
+                        ON DELETE CASCADE
+            )
+        ",
+            params![],
+        )?;
+
         // Table for all display names.
         con.execute(
             "
@@ -236,10 +411,73 @@ impl Database2 {
             params![],
         )?;
 
+        // Identities stored by earlier versions contain the accounts as
+        // specified on-chain.
+        Self::normalize_accounts(&con)?;
+
         Ok(Database2 {
             con: Arc::new(Mutex::new(con)),
         })
     }
+    /// Canonicalizes the stored Matrix IDs and Twitter handles, the same way
+    /// new judgement requests are.
+    fn normalize_accounts(con: &Connection) -> Result<()> {
+        let mut stmt = con.prepare(
+            "
+            SELECT
+                account_states.id, account_states.account
+            FROM
+                account_states
+            LEFT JOIN account_types
+                ON account_states.account_ty_id = account_types.id
+            WHERE
+                account_types.account_ty = :account_ty
+        ",
+        )?;
+
+        let mut update_stmt = con.prepare(
+            "
+            UPDATE
+                account_states
+            SET
+                account = :account
+            WHERE
+                id = :id
+        ",
+        )?;
+
+        for (account_ty, normalize) in &[
+            (
+                AccountType::Matrix,
+                normalize_matrix_id as fn(&Account) -> Option<Account>,
+            ),
+            (AccountType::Twitter, normalize_twitter_handle),
+        ] {
+            let mut rows = stmt.query_named(named_params! {
+                ":account_ty": account_ty,
+            })?;
+
+            let mut updates = vec![];
+            while let Some(row) = rows.next()? {
+                let (id, account) = (row.get::<_, i64>(0)?, row.get::<_, Account>(1)?);
+
+                // Accounts which cannot be parsed are left as is.
+                match normalize(&account) {
+                    Some(normalized) if normalized != account => updates.push((id, normalized)),
+                    _ => {}
+                }
+            }
+
+            for (id, account) in updates {
+                update_stmt.execute_named(named_params! {
+                    ":id": id,
+                    ":account": account,
+                })?;
+            }
+        }
+
+        Ok(())
+    }
     pub async fn insert_identity(&self, ident: &OnChainIdentity) -> Result<()> {
         self.insert_identity_batch(&[ident]).await
     }
@@ -273,6 +511,7 @@ impl Database2 {
                     account,
                     account_ty_id,
                     account_status_id,
+                    invalid_reason,
                     challenge,
                     challenge_status_id
                 ) VALUES (
@@ -283,6 +522,7 @@ impl Database2 {
                         WHERE account_ty = :account_ty),
                     (SELECT id FROM account_status
                         WHERE status = :account_status),
+                    :invalid_reason,
                     :challenge,
                     (SELECT id FROM challenge_status
                         WHERE status = :challenge_status)
@@ -296,6 +536,7 @@ impl Database2 {
                         ":account": &state.account,
                         ":account_ty": &state.account_ty,
                         ":account_status": &state.account_status,
+                        ":invalid_reason": invalid_reason(&state.account_status),
                         ":challenge": &state.challenge.as_str(),
                         ":challenge_status": &state.challenge_status,
                     })?;
@@ -483,7 +724,11 @@ impl Database2 {
     }
     pub async fn select_room_ids(&self) -> Result<Vec<RoomId>> {
         let con = self.con.lock().await;
-        let mut stmt = con.prepare("SELECT room_id FROM known_matrix_rooms")?;
+        let mut stmt = con.prepare(
+            "SELECT room_id FROM known_matrix_rooms
+                UNION
+            SELECT room_id FROM matrix_direct_rooms",
+        )?;
 
         let mut rows = stmt.query(params![])?;
 
@@ -495,156 +740,464 @@ impl Database2 {
 
         Ok(room_ids)
     }
-    pub async fn set_account_status(
+    pub async fn select_net_accounts_by_room_id(
         &self,
-        net_account: &NetAccount,
-        account_ty: &AccountType,
-        status: &AccountStatus,
-    ) -> StdResult<(), DatabaseError> {
+        room_id: &RoomId,
+    ) -> Result<Vec<NetAccount>> {
+        let con = self.con.lock().await;
+        let mut stmt = con.prepare(
+            "SELECT
+                net_account
+            FROM
+                pending_judgments
+            INNER JOIN
+                known_matrix_rooms
+            ON
+                pending_judgments.id = known_matrix_rooms.net_account_id
+            WHERE
+                known_matrix_rooms.room_id = :room_id
+            ",
+        )?;
+
+        let mut rows = stmt.query_named(named_params! {
+            ":room_id": room_id.as_str(),
+        })?;
+
+        let mut net_accounts = vec![];
+        while let Some(row) = rows.next()? {
+            net_accounts.push(row.get::<_, NetAccount>(0)?);
+        }
+
+        Ok(net_accounts)
+    }
+    /// Forgets about the room, including its use as a direct message room.
+    pub async fn remove_room_id(&self, room_id: &RoomId) -> Result<()> {
         let con = self.con.lock().await;
 
         con.execute_named(
-            "UPDATE
-                    account_states
-                SET account_status_id =
-                    (SELECT id FROM account_status
-                        WHERE status = :account_status)
-                WHERE
-                    net_account_id =
-                        (SELECT id FROM pending_judgments
-                            WHERE net_account = :net_account)
-                AND
-                    account_ty_id =
-                        (SELECT id FROM account_types
-                            WHERE account_ty = :account_ty)
-            ",
+            "DELETE FROM known_matrix_rooms WHERE room_id = :room_id",
             named_params! {
-                ":account_status": status,
-                ":net_account": net_account,
-                ":account_ty": account_ty,
+                ":room_id": room_id.as_str(),
             },
-        )
-        .map_err(|err| err.into())
-        .and_then(|changes| {
-            if changes == 0 {
-                Err(DatabaseError::NoChange)
-            } else {
-                Ok(changes)
-            }
-        })?;
+        )?;
+
+        con.execute_named(
+            "DELETE FROM matrix_direct_rooms WHERE room_id = :room_id",
+            named_params! {
+                ":room_id": room_id.as_str(),
+            },
+        )?;
 
         Ok(())
     }
-    pub async fn set_challenge_status(
-        &self,
-        net_account: &NetAccount,
-        account_ty: &AccountType,
-        status: &ChallengeStatus,
-    ) -> Result<()> {
+    /// Queues the room to be left. Rooms already in the queue are ignored.
+    pub async fn insert_room_to_leave(&self, room_id: &RoomId) -> Result<()> {
         self.con.lock().await.execute_named(
-            "UPDATE
-                    account_states
-                SET challenge_status_id =
-                    (SELECT id FROM challenge_status
-                        WHERE status = :challenge_status)
-                WHERE
-                    net_account_id =
-                        (SELECT id FROM pending_judgments
-                            WHERE net_account = :net_account)
-                AND
-                    account_ty_id =
-                        (SELECT id FROM account_types
-                            WHERE account_ty = :account_ty)
-            ",
+            "INSERT OR IGNORE INTO matrix_rooms_to_leave (
+                    room_id,
+                    attempts,
+                    next_attempt
+                ) VALUES (
+                    :room_id,
+                    0,
+                    :timestamp
+                )",
             named_params! {
-                ":challenge_status": status,
-                ":net_account": net_account,
-                ":account_ty": account_ty,
+                ":room_id": room_id.as_str(),
+                ":timestamp": unix_time() as i64,
             },
         )?;
 
         Ok(())
     }
-    pub async fn select_challenge_data(
-        &self,
-        account: &Account,
-        account_ty: &AccountType,
-    ) -> Result<Vec<(NetworkAddress, Challenge)>> {
+    /// Returns the rooms which are due to be left, alongside the number of
+    /// previous attempts.
+    pub async fn select_rooms_to_leave(&self) -> Result<Vec<(RoomId, u32)>> {
         let con = self.con.lock().await;
-
-        // TODO: Figure out why `IN` does not work here...
         let mut stmt = con.prepare(
-            "
-            SELECT
-                net_account, challenge
+            "SELECT
+                room_id, attempts
             FROM
-                pending_judgments
-            INNER JOIN
-                account_states
-            ON
-                pending_judgments.id = account_states.net_account_id
+                matrix_rooms_to_leave
             WHERE
-                account_states.account = :account
-            AND
-                account_states.challenge_status_id != (
-                    SELECT
-                        id
-                    FROM
-                        challenge_status
-                    WHERE
-                        status = 'accepted'
-                )
-            AND
-                account_states.account_ty_id = (
-                    SELECT
-                        id
-                    FROM
-                        account_types
-                    WHERE
-                        account_ty = :account_ty
-                )
-        ",
+                next_attempt <= :timestamp
+            ORDER BY
+                next_attempt ASC
+            ",
         )?;
 
         let mut rows = stmt.query_named(named_params! {
-            ":account": account,
-            ":account_ty": account_ty
+            ":timestamp": unix_time() as i64,
         })?;
 
-        let mut challenge_set = vec![];
+        let mut rooms = vec![];
         while let Some(row) = rows.next()? {
-            challenge_set.push((
-                NetworkAddress::try_from(row.get::<_, NetAccount>(0)?)?,
-                Challenge(row.get::<_, String>(1)?),
+            rooms.push((
+                RoomId::try_from(row.get::<_, String>(0)?)?,
+                row.get::<_, u32>(1)?,
             ));
         }
 
-        Ok(challenge_set)
+        Ok(rooms)
     }
-    // Check whether the identity is fully verified.
-    pub async fn is_fully_verified(&self, net_account: &NetAccount) -> Result<bool> {
+    pub async fn postpone_room_to_leave(&self, room_id: &RoomId, delay: u64) -> Result<()> {
+        self.con.lock().await.execute_named(
+            "UPDATE
+                matrix_rooms_to_leave
+            SET
+                attempts = attempts + 1,
+                next_attempt = :next_attempt
+            WHERE
+                room_id = :room_id
+            ",
+            named_params! {
+                ":room_id": room_id.as_str(),
+                ":next_attempt": (unix_time() + delay) as i64,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn remove_room_to_leave(&self, room_id: &RoomId) -> Result<()> {
+        self.con.lock().await.execute_named(
+            "DELETE FROM matrix_rooms_to_leave WHERE room_id = :room_id",
+            named_params! {
+                ":room_id": room_id.as_str(),
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn count_rooms_to_leave(&self) -> Result<usize> {
+        let con = self.con.lock().await;
+        con.query_row(
+            "SELECT COUNT(*) FROM matrix_rooms_to_leave",
+            params![],
+            |row| row.get::<_, i64>(0),
+        )
+        .map(|count| count as usize)
+        .map_err(|err| err.into())
+    }
+    /// Schedules the next lookup of the Matrix user and returns the number of
+    /// failed lookups so far.
+    pub async fn postpone_user_lookup(
+        &self,
+        net_account: &NetAccount,
+        account: &Account,
+        delay: u64,
+    ) -> Result<u32> {
         let con = self.con.lock().await;
+        con.execute_named(
+            "INSERT OR IGNORE INTO matrix_user_lookups (
+                    net_account,
+                    account,
+                    attempts,
+                    next_attempt
+                ) VALUES (
+                    :net_account,
+                    :account,
+                    0,
+                    0
+                )",
+            named_params! {
+                ":net_account": net_account,
+                ":account": account,
+            },
+        )?;
+
+        con.execute_named(
+            "UPDATE
+                matrix_user_lookups
+            SET
+                attempts = attempts + 1,
+                next_attempt = :next_attempt
+            WHERE
+                net_account = :net_account
+            AND
+                account = :account
+            ",
+            named_params! {
+                ":net_account": net_account,
+                ":account": account,
+                ":next_attempt": (unix_time() + delay) as i64,
+            },
+        )?;
 
+        con.query_row_named(
+            "SELECT
+                attempts
+            FROM
+                matrix_user_lookups
+            WHERE
+                net_account = :net_account
+            AND
+                account = :account
+            ",
+            named_params! {
+                ":net_account": net_account,
+                ":account": account,
+            },
+            |row| row.get::<_, u32>(0),
+        )
+        .map_err(|err| err.into())
+    }
+    /// Returns the Matrix users which are due to be looked up again.
+    pub async fn select_user_lookups(&self) -> Result<Vec<(NetAccount, Account)>> {
+        let con = self.con.lock().await;
         let mut stmt = con.prepare(
             "SELECT
-                        status
+                net_account, account
+            FROM
+                matrix_user_lookups
+            WHERE
+                next_attempt <= :timestamp
+            ORDER BY
+                next_attempt ASC
+            ",
+        )?;
+
+        let mut rows = stmt.query_named(named_params! {
+            ":timestamp": unix_time() as i64,
+        })?;
+
+        let mut lookups = vec![];
+        while let Some(row) = rows.next()? {
+            lookups.push((row.get::<_, NetAccount>(0)?, row.get::<_, Account>(1)?));
+        }
+
+        Ok(lookups)
+    }
+    pub async fn remove_user_lookup(
+        &self,
+        net_account: &NetAccount,
+        account: &Account,
+    ) -> Result<()> {
+        self.con.lock().await.execute_named(
+            "DELETE FROM
+                matrix_user_lookups
+            WHERE
+                net_account = :net_account
+            AND
+                account = :account
+            ",
+            named_params! {
+                ":net_account": net_account,
+                ":account": account,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn insert_direct_room(&self, account: &Account, room_id: &RoomId) -> Result<()> {
+        self.con.lock().await.execute_named(
+            "INSERT OR REPLACE INTO matrix_direct_rooms (
+                    account,
+                    room_id
+                ) VALUES (
+                    :account,
+                    :room_id
+                )",
+            named_params! {
+                ":account": account,
+                ":room_id": room_id.as_str(),
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn select_direct_room(&self, account: &Account) -> Result<Option<RoomId>> {
+        let con = self.con.lock().await;
+        con.query_row_named(
+            "SELECT room_id FROM matrix_direct_rooms WHERE account = :account",
+            named_params! {
+                ":account": account,
+            },
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?
+        .map(|room_id| RoomId::try_from(room_id).map_err(|err| err.into()))
+        .transpose()
+    }
+    pub async fn select_direct_rooms(&self) -> Result<Vec<(Account, RoomId)>> {
+        let con = self.con.lock().await;
+        let mut stmt = con.prepare("SELECT account, room_id FROM matrix_direct_rooms")?;
+
+        let mut rows = stmt.query(params![])?;
+
+        let mut rooms = vec![];
+        while let Some(row) = rows.next()? {
+            rooms.push((
+                row.get::<_, Account>(0)?,
+                RoomId::try_from(row.get::<_, String>(1)?)?,
+            ));
+        }
+
+        Ok(rooms)
+    }
+    pub async fn remove_direct_room(&self, account: &Account) -> Result<()> {
+        self.con.lock().await.execute_named(
+            "DELETE FROM matrix_direct_rooms WHERE account = :account",
+            named_params! {
+                ":account": account,
+            },
+        )?;
+
+        Ok(())
+    }
+    /// Sets the status of the account. The reason of an invalid account is
+    /// stored alongside, any other status resets it.
+    pub async fn set_account_status(
+        &self,
+        net_account: &NetAccount,
+        account_ty: &AccountType,
+        status: &AccountStatus,
+    ) -> StdResult<(), DatabaseError> {
+        let con = self.con.lock().await;
+
+        con.execute_named(
+            "UPDATE
+                    account_states
+                SET account_status_id =
+                    (SELECT id FROM account_status
+                        WHERE status = :account_status),
+                    invalid_reason = :invalid_reason
+                WHERE
+                    net_account_id =
+                        (SELECT id FROM pending_judgments
+                            WHERE net_account = :net_account)
+                AND
+                    account_ty_id =
+                        (SELECT id FROM account_types
+                            WHERE account_ty = :account_ty)
+            ",
+            named_params! {
+                ":account_status": status,
+                ":invalid_reason": invalid_reason(status),
+                ":net_account": net_account,
+                ":account_ty": account_ty,
+            },
+        )
+        .map_err(|err| err.into())
+        .and_then(|changes| {
+            if changes == 0 {
+                Err(DatabaseError::NoChange)
+            } else {
+                Ok(changes)
+            }
+        })?;
+
+        Ok(())
+    }
+    pub async fn set_challenge_status(
+        &self,
+        net_account: &NetAccount,
+        account_ty: &AccountType,
+        status: &ChallengeStatus,
+    ) -> Result<()> {
+        self.con.lock().await.execute_named(
+            "UPDATE
+                    account_states
+                SET challenge_status_id =
+                    (SELECT id FROM challenge_status
+                        WHERE status = :challenge_status)
+                WHERE
+                    net_account_id =
+                        (SELECT id FROM pending_judgments
+                            WHERE net_account = :net_account)
+                AND
+                    account_ty_id =
+                        (SELECT id FROM account_types
+                            WHERE account_ty = :account_ty)
+            ",
+            named_params! {
+                ":challenge_status": status,
+                ":net_account": net_account,
+                ":account_ty": account_ty,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn select_challenge_data(
+        &self,
+        account: &Account,
+        account_ty: &AccountType,
+    ) -> Result<Vec<(NetworkAddress, Challenge)>> {
+        let con = self.con.lock().await;
+
+        // TODO: Figure out why `IN` does not work here...
+        let mut stmt = con.prepare(
+            "
+            SELECT
+                net_account, challenge
+            FROM
+                pending_judgments
+            INNER JOIN
+                account_states
+            ON
+                pending_judgments.id = account_states.net_account_id
+            WHERE
+                account_states.account = :account
+            AND
+                account_states.challenge_status_id != (
+                    SELECT
+                        id
                     FROM
                         challenge_status
-                    LEFT JOIN
-                        account_states
-                    ON
-                        account_states.challenge_status_id = challenge_status.id
                     WHERE
-                        account_states.net_account_id = (
-                            SELECT
-                                id
-                            FROM
-                                pending_judgments
-                            WHERE
-                                net_account = :net_account
-                        )
-                    AND account_ty_id
-                        IN (
+                        status = 'accepted'
+                )
+            AND
+                account_states.account_ty_id = (
+                    SELECT
+                        id
+                    FROM
+                        account_types
+                    WHERE
+                        account_ty = :account_ty
+                )
+        ",
+        )?;
+
+        let mut rows = stmt.query_named(named_params! {
+            ":account": account,
+            ":account_ty": account_ty
+        })?;
+
+        let mut challenge_set = vec![];
+        while let Some(row) = rows.next()? {
+            challenge_set.push((
+                NetworkAddress::try_from(row.get::<_, NetAccount>(0)?)?,
+                Challenge(row.get::<_, String>(1)?),
+            ));
+        }
+
+        Ok(challenge_set)
+    }
+    // Check whether the identity is fully verified.
+    pub async fn is_fully_verified(&self, net_account: &NetAccount) -> Result<bool> {
+        let con = self.con.lock().await;
+
+        let mut stmt = con.prepare(
+            "SELECT
+                        status
+                    FROM
+                        challenge_status
+                    LEFT JOIN
+                        account_states
+                    ON
+                        account_states.challenge_status_id = challenge_status.id
+                    WHERE
+                        account_states.net_account_id = (
+                            SELECT
+                                id
+                            FROM
+                                pending_judgments
+                            WHERE
+                                net_account = :net_account
+                        )
+                    AND account_ty_id
+                        IN (
                             SELECT
                                 id
                             FROM
@@ -660,384 +1213,1227 @@ impl Database2 {
                     ",
         )?;
 
-        let mut rows = stmt.query_named(named_params! {
-            ":net_account": net_account,
-        })?;
-
-        while let Some(row) = rows.next()? {
-            if row.get::<_, ChallengeStatus>(0)? != ChallengeStatus::Accepted {
-                return Ok(false);
-            }
-        }
-
-        Ok(true)
+        let mut rows = stmt.query_named(named_params! {
+            ":net_account": net_account,
+        })?;
+
+        while let Some(row) = rows.next()? {
+            if row.get::<_, ChallengeStatus>(0)? != ChallengeStatus::Accepted {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+    pub async fn select_timed_out_identities(&self, timeout_limit: u64) -> Result<Vec<NetAccount>> {
+        let con = self.con.lock().await;
+
+        let mut stmt = con.prepare(
+            "
+            SELECT
+                net_account
+            FROM
+                pending_judgments
+            LEFT JOIN
+                account_states
+            ON
+                pending_judgments.id = account_states.net_account_id
+            WHERE
+                pending_judgments.created < :timeout_limit
+            AND
+                account_states.challenge_status_id != (
+                    SELECT
+                        id
+                    FROM
+                        challenge_status
+                    WHERE
+                        status = 'accepted'
+                )
+        ",
+        )?;
+
+        let mut rows = stmt.query_named(named_params! {
+            ":timeout_limit": (unix_time() - timeout_limit) as i64,
+        })?;
+
+        let mut net_accounts = vec![];
+        while let Some(row) = rows.next()? {
+            net_accounts.push(row.get::<_, NetAccount>(0)?);
+        }
+
+        Ok(net_accounts)
+    }
+    pub async fn cleanup_timed_out_identities(&self, timeout_limit: u64) -> Result<()> {
+        let net_accounts = self.select_timed_out_identities(timeout_limit).await?;
+        let con = self.con.lock().await;
+
+        let mut stmt = con.prepare(
+            "
+            DELETE FROM
+                pending_judgments
+            WHERE
+                net_account = :net_account
+        ",
+        )?;
+
+        for net_account in net_accounts {
+            stmt.execute_named(named_params! {
+                ":net_account": net_account,
+            })?;
+        }
+
+        Ok(())
+    }
+    /// Returns the invalid accounts of the identity, including the reason if
+    /// specified.
+    pub async fn select_invalid_accounts(
+        &self,
+        net_account: &NetAccount,
+    ) -> Result<Vec<(AccountType, Account, Option<InvalidReason>)>> {
+        let con = self.con.lock().await;
+        let mut stmt = con.prepare(
+            "
+            SELECT
+                account_ty, account, invalid_reason
+            FROM
+                account_states
+            LEFT JOIN
+                account_types
+            ON
+                account_states.account_ty_id =
+                    account_types.id
+            WHERE
+                account_states.net_account_id = (
+                    SELECT
+                        id
+                    FROM
+                        pending_judgments
+                    WHERE
+                        net_account = :net_account
+                )
+            AND
+                account_states.account_status_id = (
+                    SELECT
+                        id
+                    FROM
+                        account_status
+                    WHERE
+                        status = :account_status
+                )
+            ",
+        )?;
+
+        let mut rows = stmt.query_named(named_params! {
+            ":net_account": net_account,
+            ":account_status": &AccountStatus::Invalid(None),
+        })?;
+
+        let mut accounts = vec![];
+        while let Some(row) = rows.next()? {
+            accounts.push((
+                row.get::<_, AccountType>(0)?,
+                row.get::<_, Account>(1)?,
+                row.get::<_, Option<InvalidReason>>(2)?,
+            ));
+        }
+
+        Ok(accounts)
+    }
+    pub async fn select_account_statuses(
+        &self,
+        net_account: &NetAccount,
+    ) -> Result<Vec<(AccountType, Account, AccountStatus)>> {
+        let mut con = self.con.lock().await;
+        let transaction = con.transaction()?;
+
+        let account_set = {
+            let mut stmt = transaction.prepare(
+                "
+            SELECT
+                account_ty, account, status, invalid_reason
+            FROM
+                account_states
+            LEFT JOIN
+                account_types
+            ON
+                account_states.account_ty_id =
+                    account_types.id
+            LEFT JOIN
+                account_status
+            ON
+                account_states.account_status_id =
+                    account_status.id
+            WHERE
+                account_states.net_account_id = (
+                    SELECT
+                        id
+                    FROM
+                        pending_judgments
+                    WHERE
+                        net_account = :net_account
+                )
+            ",
+            )?;
+
+            let mut rows = stmt.query_named(named_params! {
+                ":net_account": net_account,
+            })?;
+
+            let mut account_set = vec![];
+            while let Some(row) = rows.next()? {
+                let status = match row.get::<_, AccountStatus>(2)? {
+                    AccountStatus::Invalid(_) => {
+                        AccountStatus::Invalid(row.get::<_, Option<InvalidReason>>(3)?)
+                    }
+                    status => status,
+                };
+
+                account_set.push((
+                    row.get::<_, AccountType>(0)?,
+                    row.get::<_, Account>(1)?,
+                    status,
+                ))
+            }
+
+            account_set
+        };
+
+        transaction.commit()?;
+
+        Ok(account_set)
+    }
+    #[cfg(test)]
+    pub async fn insert_twitter_id(&self, account: &Account, twitter_id: &TwitterId) -> Result<()> {
+        self.insert_twitter_ids(&[(account, twitter_id)]).await
+    }
+    pub async fn insert_twitter_ids(&self, pair: &[(&Account, &TwitterId)]) -> Result<()> {
+        let con = self.con.lock().await;
+
+        // The Twitter ID is the stable identifier, while the screen name
+        // might change. Remove any outdated screen names of the Twitter ID.
+        let mut remove_stmt = con.prepare(
+            "
+            DELETE FROM
+                known_twitter_ids
+            WHERE
+                twitter_id = :twitter_id
+            AND
+                account_id NOT IN (
+                    SELECT
+                        id
+                    FROM
+                        account_states
+                    WHERE
+                        account = :account
+                    AND
+                        account_ty_id =
+                            (SELECT id FROM account_types
+                                WHERE account_ty = :account_ty)
+                )
+        ",
+        )?;
+
+        let mut stmt = con.prepare(
+            "
+            INSERT OR REPLACE INTO
+                known_twitter_ids (
+                    account_id,
+                    twitter_id,
+                    init_msg,
+                    timestamp
+                )
+            VALUES (
+                (
+                    SELECT
+                        id
+                    FROM
+                        account_states
+                    WHERE
+                        account = :account
+                    AND
+                        account_ty_id =
+                            (SELECT id FROM account_types
+                                WHERE account_ty = :account_ty)
+                ),
+                :twitter_id,
+                '0',
+                :timestamp
+            )
+        ",
+        )?;
+
+        for (account, twitter_id) in pair {
+            remove_stmt.execute_named(named_params! {
+                    ":account": account,
+                    ":account_ty": &AccountType::Twitter,
+                    ":twitter_id": twitter_id,
+            })?;
+
+            stmt.execute_named(named_params! {
+                    ":account": account,
+                    ":account_ty": &AccountType::Twitter,
+                    ":twitter_id": twitter_id,
+                    ":timestamp": unix_time() as i64,
+            })?;
+        }
+
+        Ok(())
+    }
+    pub async fn select_account_from_twitter_id(
+        &self,
+        twitter_id: &TwitterId,
+    ) -> Result<Option<(Account, bool)>> {
+        let con = self.con.lock().await;
+        con.query_row_named(
+            "
+            SELECT
+                account, init_msg
+            FROM
+                known_twitter_ids
+            LEFT JOIN
+                account_states
+            ON
+                known_twitter_ids.account_id = account_states.id
+            WHERE
+                twitter_id = :twitter_id
+
+        ",
+            named_params! {
+                ":twitter_id": twitter_id,
+            },
+            |row| Ok((row.get::<_, Account>(0)?, row.get::<_, bool>(1)?)),
+        )
+        .optional()
+        .map_err(|err| failure::Error::from(err))
+    }
+    pub async fn select_twitter_id(&self, account: &Account) -> Result<Option<TwitterId>> {
+        let con = self.con.lock().await;
+        con.query_row_named(
+            "
+            SELECT
+                twitter_id
+            FROM
+                known_twitter_ids
+            WHERE
+                account_id = (
+                    SELECT
+                        id
+                    FROM
+                        account_states
+                    WHERE
+                        account = :account
+                    AND
+                        account_ty_id =
+                            (SELECT id FROM account_types
+                                WHERE account_ty = :account_ty)
+                )
+        ",
+            named_params! {
+                ":account": account,
+                ":account_ty": &AccountType::Twitter,
+            },
+            |row| row.get::<_, TwitterId>(0),
+        )
+        .optional()
+        .map_err(|err| err.into())
+    }
+    pub async fn confirm_init_message(&self, account: &Account) -> Result<()> {
+        let con = self.con.lock().await;
+        con.execute_named(
+            "
+            UPDATE
+                known_twitter_ids
+            SET
+                init_msg = 1
+            WHERE
+                account_id = (
+                    SELECT
+                        id
+                    FROM
+                        account_states
+                    WHERE
+                        account = :account
+                    AND
+                        account_ty_id =
+                            (SELECT id FROM account_types
+                                WHERE account_ty = :account_ty)
+                )
+        ",
+            named_params! {
+                ":account": account,
+                ":account_ty": &AccountType::Twitter,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn reset_init_message(&self, account: &Account) -> Result<()> {
+        let con = self.con.lock().await;
+        con.execute_named(
+            "
+            UPDATE
+                known_twitter_ids
+            SET
+                init_msg = 0
+            WHERE
+                account_id = (
+                    SELECT
+                        id
+                    FROM
+                        account_states
+                    WHERE
+                        account = :account
+                    AND
+                        account_ty_id =
+                            (SELECT id FROM account_types
+                                WHERE account_ty = :account_ty)
+                )
+        ",
+            named_params! {
+                ":account": account,
+                ":account_ty": &AccountType::Twitter,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn select_watermark(&self, account_ty: &AccountType) -> Result<Option<u64>> {
+        let con = self.con.lock().await;
+        con.query_row_named(
+            "SELECT
+                watermark
+            FROM
+                watermarks
+            WHERE
+                account_ty_id = (
+                    SELECT
+                        id
+                    FROM
+                        account_types
+                    WHERE
+                        account_ty = :account_ty
+                )
+            ",
+            named_params! {
+                ":account_ty": account_ty,
+            },
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()
+        .map_err(|err| failure::Error::from(err))
+        .map(|v| v.map(|v| v as u64))
+    }
+    pub async fn update_watermark(&self, account_ty: &AccountType, value: u64) -> Result<()> {
+        let con = self.con.lock().await;
+        con.execute_named(
+            "
+            INSERT OR REPlACE INTO watermarks (
+                account_ty_id,
+                watermark
+            ) VALUES (
+                (
+                    SELECT
+                        id
+                    FROM
+                        account_types
+                    WHERE
+                        account_ty = :account_ty
+                ),
+                :value
+            )
+        ",
+            named_params! {
+                ":value": value as i64,
+                ":account_ty": account_ty,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn set_adapter_unhealthy(
+        &self,
+        account_ty: &AccountType,
+        reason: &str,
+    ) -> Result<()> {
+        let con = self.con.lock().await;
+        con.execute_named(
+            "
+            INSERT OR REPLACE INTO unhealthy_adapters (
+                account_ty_id,
+                reason,
+                timestamp
+            ) VALUES (
+                (
+                    SELECT
+                        id
+                    FROM
+                        account_types
+                    WHERE
+                        account_ty = :account_ty
+                ),
+                :reason,
+                :timestamp
+            )
+        ",
+            named_params! {
+                ":account_ty": account_ty,
+                ":reason": reason,
+                ":timestamp": unix_time() as i64,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn set_adapter_healthy(&self, account_ty: &AccountType) -> Result<()> {
+        let con = self.con.lock().await;
+        con.execute_named(
+            "
+            DELETE FROM
+                unhealthy_adapters
+            WHERE
+                account_ty_id = (
+                    SELECT
+                        id
+                    FROM
+                        account_types
+                    WHERE
+                        account_ty = :account_ty
+                )
+        ",
+            named_params! {
+                ":account_ty": account_ty,
+            },
+        )?;
+
+        Ok(())
+    }
+    /// Removes all reports of unhealthy adapters. Called on startup, since the
+    /// reports of a previous run are no longer meaningful.
+    pub async fn clear_unhealthy_adapters(&self) -> Result<()> {
+        let con = self.con.lock().await;
+        con.execute("DELETE FROM unhealthy_adapters", params![])?;
+
+        Ok(())
+    }
+    pub async fn select_unhealthy_adapters(&self) -> Result<Vec<(AccountType, String)>> {
+        let con = self.con.lock().await;
+        let mut stmt = con.prepare(
+            "
+            SELECT
+                account_ty, reason
+            FROM
+                unhealthy_adapters
+            INNER JOIN
+                account_types
+            ON
+                unhealthy_adapters.account_ty_id = account_types.id
+        ",
+        )?;
+
+        let mut rows = stmt.query(params![])?;
+
+        let mut adapters = vec![];
+        while let Some(row) = rows.next()? {
+            adapters.push((row.get::<_, AccountType>(0)?, row.get::<_, String>(1)?));
+        }
+
+        Ok(adapters)
+    }
+    pub async fn update_twitter_token(&self, token: &TwitterToken) -> Result<()> {
+        let con = self.con.lock().await;
+        con.execute_named(
+            "
+            INSERT OR REPLACE INTO twitter_oauth2_token (
+                id,
+                access_token,
+                refresh_token,
+                expires_at
+            ) VALUES (
+                0,
+                :access_token,
+                :refresh_token,
+                :expires_at
+            )
+        ",
+            named_params! {
+                ":access_token": token.access_token,
+                ":refresh_token": token.refresh_token,
+                ":expires_at": token.expires_at as i64,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn select_twitter_token(&self) -> Result<Option<TwitterToken>> {
+        let con = self.con.lock().await;
+        con.query_row(
+            "
+            SELECT
+                access_token, refresh_token, expires_at
+            FROM
+                twitter_oauth2_token
+            WHERE
+                id = 0
+        ",
+            params![],
+            |row| {
+                Ok(TwitterToken {
+                    access_token: row.get(0)?,
+                    refresh_token: row.get(1)?,
+                    expires_at: row.get::<_, i64>(2)? as u64,
+                })
+            },
+        )
+        .optional()
+        .map_err(|err| err.into())
+    }
+    pub async fn insert_tweet_verification(
+        &self,
+        account: &Account,
+        twitter_id: &TwitterId,
+    ) -> Result<()> {
+        self.con.lock().await.execute_named(
+            "
+            INSERT OR REPLACE INTO twitter_tweet_verifications (
+                account,
+                twitter_id
+            ) VALUES (
+                :account,
+                :twitter_id
+            )
+        ",
+            named_params! {
+                ":account": account,
+                ":twitter_id": twitter_id,
+            },
+        )?;
+
+        Ok(())
     }
-    pub async fn select_timed_out_identities(&self, timeout_limit: u64) -> Result<Vec<NetAccount>> {
+    pub async fn select_tweet_verifications(
+        &self,
+    ) -> Result<Vec<(Account, TwitterId, Option<u64>)>> {
         let con = self.con.lock().await;
-
         let mut stmt = con.prepare(
             "
             SELECT
-                net_account
+                account, twitter_id, since_id
             FROM
-                pending_judgments
-            LEFT JOIN
-                account_states
-            ON
-                pending_judgments.id = account_states.net_account_id
-            WHERE
-                pending_judgments.created < :timeout_limit
-            AND
-                account_states.challenge_status_id != (
-                    SELECT
-                        id
-                    FROM
-                        challenge_status
-                    WHERE
-                        status = 'accepted'
-                )
+                twitter_tweet_verifications
         ",
         )?;
 
-        let mut rows = stmt.query_named(named_params! {
-            ":timeout_limit": (unix_time() - timeout_limit) as i64,
-        })?;
+        let mut rows = stmt.query(params![])?;
 
-        let mut net_accounts = vec![];
+        let mut verifications = vec![];
         while let Some(row) = rows.next()? {
-            net_accounts.push(row.get::<_, NetAccount>(0)?);
+            verifications.push((
+                row.get::<_, Account>(0)?,
+                row.get::<_, TwitterId>(1)?,
+                row.get::<_, Option<i64>>(2)?.map(|id| id as u64),
+            ));
         }
 
-        Ok(net_accounts)
+        Ok(verifications)
     }
-    pub async fn cleanup_timed_out_identities(&self, timeout_limit: u64) -> Result<()> {
-        let net_accounts = self.select_timed_out_identities(timeout_limit).await?;
-        let con = self.con.lock().await;
-
-        let mut stmt = con.prepare(
+    pub async fn update_tweet_verification(&self, account: &Account, since_id: u64) -> Result<()> {
+        self.con.lock().await.execute_named(
             "
-            DELETE FROM
-                pending_judgments
+            UPDATE
+                twitter_tweet_verifications
+            SET
+                since_id = :since_id
             WHERE
-                net_account = :net_account
+                account = :account
         ",
+            named_params! {
+                ":account": account,
+                ":since_id": since_id as i64,
+            },
         )?;
 
-        for net_account in net_accounts {
-            stmt.execute_named(named_params! {
-                ":net_account": net_account,
-            })?;
-        }
-
         Ok(())
     }
-    pub async fn select_account_statuses(
+    /// Schedules the next attempt to contact the Twitter user and returns
+    /// the number of failed attempts so far.
+    pub async fn postpone_twitter_contact(
         &self,
         net_account: &NetAccount,
-    ) -> Result<Vec<(AccountType, Account, AccountStatus)>> {
-        let mut con = self.con.lock().await;
-        let transaction = con.transaction()?;
+        account: &Account,
+        delay: u64,
+    ) -> Result<u32> {
+        let con = self.con.lock().await;
+        con.execute_named(
+            "INSERT OR IGNORE INTO twitter_contact_retries (
+                    net_account,
+                    account,
+                    attempts,
+                    next_attempt
+                ) VALUES (
+                    :net_account,
+                    :account,
+                    0,
+                    0
+                )",
+            named_params! {
+                ":net_account": net_account,
+                ":account": account,
+            },
+        )?;
 
-        let account_set = {
-            let mut stmt = transaction.prepare(
-                "
-            SELECT
-                account_ty, account, status
-            FROM
-                account_states
-            LEFT JOIN
-                account_types
-            ON
-                account_states.account_ty_id =
-                    account_types.id
-            LEFT JOIN
-                account_status
-            ON
-                account_states.account_status_id =
-                    account_status.id
+        con.execute_named(
+            "UPDATE
+                twitter_contact_retries
+            SET
+                attempts = attempts + 1,
+                next_attempt = :next_attempt
             WHERE
-                account_states.net_account_id = (
-                    SELECT
-                        id
-                    FROM
-                        pending_judgments
-                    WHERE
-                        net_account = :net_account
-                )
+                net_account = :net_account
+            AND
+                account = :account
             ",
-            )?;
+            named_params! {
+                ":net_account": net_account,
+                ":account": account,
+                ":next_attempt": (unix_time() + delay) as i64,
+            },
+        )?;
 
-            let mut rows = stmt.query_named(named_params! {
+        con.query_row_named(
+            "SELECT
+                attempts
+            FROM
+                twitter_contact_retries
+            WHERE
+                net_account = :net_account
+            AND
+                account = :account
+            ",
+            named_params! {
                 ":net_account": net_account,
-            })?;
+                ":account": account,
+            },
+            |row| row.get::<_, u32>(0),
+        )
+        .map_err(|err| err.into())
+    }
+    /// Returns the Twitter users which are due to be contacted again.
+    pub async fn select_twitter_contacts(&self) -> Result<Vec<(NetAccount, Account)>> {
+        let con = self.con.lock().await;
+        let mut stmt = con.prepare(
+            "SELECT
+                net_account, account
+            FROM
+                twitter_contact_retries
+            WHERE
+                next_attempt <= :timestamp
+            ORDER BY
+                next_attempt ASC
+            ",
+        )?;
 
-            let mut account_set = vec![];
-            while let Some(row) = rows.next()? {
-                account_set.push((
-                    row.get::<_, AccountType>(0)?,
-                    row.get::<_, Account>(1)?,
-                    row.get::<_, AccountStatus>(2)?,
-                ))
-            }
+        let mut rows = stmt.query_named(named_params! {
+            ":timestamp": unix_time() as i64,
+        })?;
 
-            account_set
-        };
+        let mut contacts = vec![];
+        while let Some(row) = rows.next()? {
+            contacts.push((row.get::<_, NetAccount>(0)?, row.get::<_, Account>(1)?));
+        }
 
-        transaction.commit()?;
+        Ok(contacts)
+    }
+    pub async fn remove_twitter_contact(
+        &self,
+        net_account: &NetAccount,
+        account: &Account,
+    ) -> Result<()> {
+        self.con.lock().await.execute_named(
+            "DELETE FROM
+                twitter_contact_retries
+            WHERE
+                net_account = :net_account
+            AND
+                account = :account
+            ",
+            named_params! {
+                ":net_account": net_account,
+                ":account": account,
+            },
+        )?;
 
-        Ok(account_set)
+        Ok(())
     }
-    #[cfg(test)]
-    pub async fn insert_twitter_id(&self, account: &Account, twitter_id: &TwitterId) -> Result<()> {
-        self.insert_twitter_ids(&[(account, twitter_id)]).await
+    pub async fn remove_tweet_verification(&self, account: &Account) -> Result<()> {
+        self.con.lock().await.execute_named(
+            "
+            DELETE FROM
+                twitter_tweet_verifications
+            WHERE
+                account = :account
+        ",
+            named_params! {
+                ":account": account,
+            },
+        )?;
+
+        Ok(())
     }
-    pub async fn insert_twitter_ids(&self, pair: &[(&Account, &TwitterId)]) -> Result<()> {
+    pub async fn track_twitter_events(
+        &self,
+        twitter_id: &TwitterId,
+        event_ids: &[u64],
+    ) -> Result<()> {
         let con = self.con.lock().await;
         let mut stmt = con.prepare(
             "
-            INSERT OR REPLACE INTO
-                known_twitter_ids (
-                    account_id,
-                    twitter_id,
-                    init_msg,
-                    timestamp
-                )
-            VALUES (
-                (
-                    SELECT
-                        id
-                    FROM
-                        account_states
-                    WHERE
-                        account = :account
-                ),
+            INSERT OR REPLACE INTO twitter_processed_events (
+                event_id,
+                twitter_id,
+                timestamp
+            ) VALUES (
+                :event_id,
                 :twitter_id,
-                '0',
                 :timestamp
             )
         ",
         )?;
 
-        for (account, twitter_id) in pair {
+        for event_id in event_ids {
             stmt.execute_named(named_params! {
-                    ":account": account,
-                    ":twitter_id": twitter_id,
-                    ":timestamp": unix_time() as i64,
+                ":event_id": *event_id as i64,
+                ":twitter_id": twitter_id,
+                ":timestamp": unix_time() as i64,
             })?;
         }
 
         Ok(())
     }
-    pub async fn select_account_from_twitter_id(
+    pub async fn find_untracked_twitter_events(&self, event_ids: &[u64]) -> Result<Vec<u64>> {
+        let con = self.con.lock().await;
+        let mut stmt = con.prepare(
+            "
+            SELECT
+                id
+            FROM
+                twitter_processed_events
+            WHERE
+                event_id = :event_id
+        ",
+        )?;
+
+        let mut untracked = vec![];
+        for event_id in event_ids {
+            if stmt
+                .query_row_named(
+                    named_params! {
+                        ":event_id": *event_id as i64,
+                    },
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?
+                .is_none()
+            {
+                untracked.push(*event_id);
+            }
+        }
+
+        Ok(untracked)
+    }
+    /// Removes tracked Twitter events which are older than `retention`
+    /// seconds.
+    pub async fn prune_twitter_events(&self, retention: u64) -> Result<()> {
+        let con = self.con.lock().await;
+
+        con.execute_named(
+            "
+            DELETE FROM
+                twitter_processed_events
+            WHERE
+                timestamp < :retention_limit
+        ",
+            named_params! {
+                ":retention_limit": (unix_time().saturating_sub(retention)) as i64,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn track_email_id(&self, email_id: &EmailId, uid_validity: u32) -> Result<()> {
+        let con = self.con.lock().await;
+
+        con.execute_named(
+            "
+            INSERT OR REPLACE INTO email_processed_ids (
+                email_id,
+                uid_validity,
+                timestamp
+            ) VALUES (
+                :email_id,
+                :uid_validity,
+                :timestamp
+            )
+            ",
+            named_params! {
+                ":email_id": email_id,
+                ":uid_validity": uid_validity,
+                ":timestamp": unix_time() as i64,
+            },
+        )?;
+
+        Ok(())
+    }
+    pub async fn find_untracked_email_ids<'id>(
         &self,
-        twitter_id: &TwitterId,
-    ) -> Result<Option<(Account, bool)>> {
+        ids: &'id [EmailId],
+        uid_validity: u32,
+    ) -> Result<Vec<&'id EmailId>> {
         let con = self.con.lock().await;
-        con.query_row_named(
+        let mut stmt = con.prepare(
             "
             SELECT
-                account, init_msg
+                id
             FROM
-                known_twitter_ids
-            LEFT JOIN
-                account_states
-            ON
-                known_twitter_ids.account_id = account_states.id
+                email_processed_ids
             WHERE
-                twitter_id = :twitter_id
+                email_id = :email_id
+            AND
+                uid_validity = :uid_validity
+        ",
+        )?;
+
+        let mut untracked_email_ids = vec![];
+        for email_id in ids {
+            stmt.query_row_named(
+                named_params! {
+                    ":email_id": email_id,
+                    ":uid_validity": uid_validity,
+                },
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?
+            .map(|_| ())
+            .or_else(|| {
+                untracked_email_ids.push(email_id);
+                Some(())
+            });
+        }
+
+        Ok(untracked_email_ids)
+    }
+    /// Removes all tracked email IDs which belong to a different UIDVALIDITY.
+    /// If the UIDVALIDITY of the mailbox changes, the previous IDs are no
+    /// longer meaningful. Entries with an unknown UIDVALIDITY (0), tracked by
+    /// earlier versions, are assigned the current one instead.
+    pub async fn invalidate_email_ids(&self, uid_validity: u32) -> Result<()> {
+        let con = self.con.lock().await;
 
+        con.execute_named(
+            "
+            UPDATE
+                email_processed_ids
+            SET
+                uid_validity = :uid_validity
+            WHERE
+                uid_validity = 0
         ",
             named_params! {
-                ":twitter_id": twitter_id,
+                ":uid_validity": uid_validity,
             },
-            |row| Ok((row.get::<_, Account>(0)?, row.get::<_, bool>(1)?)),
-        )
-        .optional()
-        .map_err(|err| failure::Error::from(err))
-    }
-    pub async fn select_twitter_id(&self, account: &Account) -> Result<Option<TwitterId>> {
-        let con = self.con.lock().await;
-        con.query_row_named(
+        )?;
+
+        let changes = con.execute_named(
             "
-            SELECT
-                twitter_id
-            FROM
-                known_twitter_ids
+            DELETE FROM
+                email_processed_ids
             WHERE
-                account_id = (
-                    SELECT
-                        id
-                    FROM
-                        account_states
-                    WHERE
-                        account = :account
-                ) 
+                uid_validity != :uid_validity
         ",
             named_params! {
-                ":account": account,
+                ":uid_validity": uid_validity,
             },
-            |row| row.get::<_, TwitterId>(0),
-        )
-        .optional()
-        .map_err(|err| err.into())
+        )?;
+
+        if changes > 0 {
+            warn!(
+                "Mailbox UIDVALIDITY changed, invalidated {} tracked email IDs",
+                changes
+            );
+        }
+
+        Ok(())
     }
-    pub async fn confirm_init_message(&self, account: &Account) -> Result<()> {
+    /// Removes tracked email IDs which are older than `retention` seconds.
+    pub async fn prune_email_ids(&self, retention: u64) -> Result<()> {
         let con = self.con.lock().await;
+
         con.execute_named(
             "
-            UPDATE
-                known_twitter_ids
-            SET
-                init_msg = 1
+            DELETE FROM
+                email_processed_ids
             WHERE
-                account_id = (
-                    SELECT
-                        id
-                    FROM
-                        account_states
-                    WHERE
-                        account = :account
-                )
+                timestamp < :retention_limit
         ",
             named_params! {
-                ":account": account,
+                ":retention_limit": (unix_time().saturating_sub(retention)) as i64,
             },
         )?;
 
         Ok(())
     }
-    pub async fn reset_init_message(&self, account: &Account) -> Result<()> {
+    pub async fn track_sent_email(&self, message_id: &str, account: &Account) -> Result<()> {
         let con = self.con.lock().await;
+
         con.execute_named(
             "
-            UPDATE
-                known_twitter_ids
-            SET
-                init_msg = 0
-            WHERE
-                account_id = (
-                    SELECT
-                        id
-                    FROM
-                        account_states
-                    WHERE
-                        account = :account
-                )
-        ",
+            INSERT OR REPLACE INTO email_sent_message_ids (
+                message_id,
+                account,
+                timestamp
+            ) VALUES (
+                :message_id,
+                :account,
+                :timestamp
+            )
+            ",
             named_params! {
+                ":message_id": message_id,
                 ":account": account,
+                ":timestamp": unix_time() as i64,
             },
         )?;
 
         Ok(())
     }
-    pub async fn select_watermark(&self, account_ty: &AccountType) -> Result<Option<u64>> {
+    /// Whether any of the Message-IDs belongs to an email which was sent to
+    /// the given address.
+    pub async fn is_sent_email(&self, message_ids: &[String], account: &Account) -> Result<bool> {
         let con = self.con.lock().await;
-        con.query_row_named(
-            "SELECT
-                watermark
+        let mut stmt = con.prepare(
+            "
+            SELECT
+                id
             FROM
-                watermarks
+                email_sent_message_ids
             WHERE
-                account_ty_id = (
-                    SELECT
-                        id
-                    FROM
-                        account_types
-                    WHERE
-                        account_ty = :account_ty
+                message_id = :message_id
+            AND
+                LOWER(account) = LOWER(:account)
+        ",
+        )?;
+
+        for message_id in message_ids {
+            let found = stmt
+                .query_row_named(
+                    named_params! {
+                        ":message_id": message_id,
+                        ":account": account,
+                    },
+                    |row| row.get::<_, i64>(0),
                 )
-            ",
+                .optional()?;
+
+            if found.is_some() {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+    /// Removes Message-IDs of sent emails which are older than `retention`
+    /// seconds.
+    pub async fn prune_sent_emails(&self, retention: u64) -> Result<()> {
+        let con = self.con.lock().await;
+
+        con.execute_named(
+            "
+            DELETE FROM
+                email_sent_message_ids
+            WHERE
+                timestamp < :retention_limit
+        ",
             named_params! {
-                ":account_ty": account_ty,
+                ":retention_limit": (unix_time().saturating_sub(retention)) as i64,
             },
-            |row| row.get::<_, i64>(0),
-        )
-        .optional()
-        .map_err(|err| failure::Error::from(err))
-        .map(|v| v.map(|v| v as u64))
+        )?;
+
+        Ok(())
     }
-    pub async fn update_watermark(&self, account_ty: &AccountType, value: u64) -> Result<()> {
+    pub async fn insert_email_code(
+        &self,
+        net_account: &NetAccount,
+        code: &Challenge,
+    ) -> Result<()> {
         let con = self.con.lock().await;
+
         con.execute_named(
             "
-            INSERT OR REPlACE INTO watermarks (
-                account_ty_id,
-                watermark
+            INSERT OR REPLACE INTO email_verification_codes (
+                account_id,
+                code,
+                confirmed,
+                created
             ) VALUES (
                 (
                     SELECT
                         id
                     FROM
-                        account_types
+                        account_states
                     WHERE
-                        account_ty = :account_ty
+                        net_account_id = (
+                            SELECT
+                                id
+                            FROM
+                                pending_judgments
+                            WHERE
+                                net_account = :net_account
+                        )
+                    AND
+                        account_ty_id = (
+                            SELECT
+                                id
+                            FROM
+                                account_types
+                            WHERE
+                                account_ty = 'email'
+                        )
                 ),
-                :value
+                :code,
+                '0',
+                :created
             )
         ",
             named_params! {
-                ":value": value as i64,
-                ":account_ty": account_ty,
+                ":net_account": net_account,
+                ":code": code.as_str(),
+                ":created": unix_time() as i64,
             },
         )?;
 
         Ok(())
     }
-    pub async fn track_email_id(&self, email_id: &EmailId) -> Result<()> {
+    /// Marks the code as confirmed. Returns `false` if the code is unknown or
+    /// older than `expiry` seconds. If `sender` is specified, the code must
+    /// belong to that email address.
+    pub async fn confirm_email_code(
+        &self,
+        code: &str,
+        expiry: u64,
+        sender: Option<&Account>,
+    ) -> Result<bool> {
         let con = self.con.lock().await;
 
-        con.execute_named(
+        let changes = con.execute_named(
             "
-            INSERT OR IGNORE INTO email_processed_ids (
-                email_id,
-                timestamp
-            ) VALUES (
-                :email_id,
-                :timestamp
+            UPDATE
+                email_verification_codes
+            SET
+                confirmed = 1
+            WHERE
+                code = :code
+            AND
+                created >= :expiry_limit
+            AND (
+                :sender IS NULL
+                OR
+                account_id IN (
+                    SELECT
+                        id
+                    FROM
+                        account_states
+                    WHERE
+                        LOWER(account) = LOWER(:sender)
+                    AND
+                        account_ty_id = (
+                            SELECT
+                                id
+                            FROM
+                                account_types
+                            WHERE
+                                account_ty = 'email'
+                        )
+                )
             )
-            ",
+        ",
             named_params! {
-                ":email_id": email_id,
-                ":timestamp": unix_time() as i64,
+                ":code": code,
+                ":expiry_limit": (unix_time().saturating_sub(expiry)) as i64,
+                ":sender": sender,
             },
         )?;
 
-        Ok(())
+        Ok(changes > 0)
     }
-    pub async fn find_untracked_email_ids<'id>(
-        &self,
-        ids: &'id [EmailId],
-    ) -> Result<Vec<&'id EmailId>> {
+    pub async fn select_confirmed_email_codes(&self) -> Result<Vec<NetAccount>> {
         let con = self.con.lock().await;
+
         let mut stmt = con.prepare(
             "
-            SELECT
-                id
-            FROM
-                email_processed_ids
+            SELECT
+                net_account
+            FROM
+                email_verification_codes
+            INNER JOIN
+                account_states
+            ON
+                email_verification_codes.account_id = account_states.id
+            INNER JOIN
+                pending_judgments
+            ON
+                account_states.net_account_id = pending_judgments.id
+            WHERE
+                email_verification_codes.confirmed = 1
+        ",
+        )?;
+
+        let mut rows = stmt.query(params![])?;
+
+        let mut net_accounts = vec![];
+        while let Some(row) = rows.next()? {
+            net_accounts.push(row.get::<_, NetAccount>(0)?);
+        }
+
+        Ok(net_accounts)
+    }
+    pub async fn remove_email_code(&self, net_account: &NetAccount) -> Result<()> {
+        let con = self.con.lock().await;
+
+        con.execute_named(
+            "
+            DELETE FROM
+                email_verification_codes
             WHERE
-                email_id = :email_id
+                account_id = (
+                    SELECT
+                        id
+                    FROM
+                        account_states
+                    WHERE
+                        net_account_id = (
+                            SELECT
+                                id
+                            FROM
+                                pending_judgments
+                            WHERE
+                                net_account = :net_account
+                        )
+                    AND
+                        account_ty_id = (
+                            SELECT
+                                id
+                            FROM
+                                account_types
+                            WHERE
+                                account_ty = 'email'
+                        )
+                )
         ",
+            named_params! {
+                ":net_account": net_account,
+            },
         )?;
 
-        let mut untracked_email_ids = vec![];
-        for email_id in ids {
-            stmt.query_row_named(
-                named_params! {
-                    ":email_id": email_id
-                },
-                |row| row.get::<_, i64>(0),
-            )
-            .optional()?
-            .map(|_| ())
-            .or_else(|| {
-                untracked_email_ids.push(email_id);
-                Some(())
-            });
-        }
-
-        Ok(untracked_email_ids)
+        Ok(())
     }
     pub async fn insert_display_name(&self, account: &Account) -> Result<()> {
         let con = self.con.lock().await;
@@ -1154,6 +2550,13 @@ impl Database2 {
     }
 }
 
+fn invalid_reason(status: &AccountStatus) -> Option<&InvalidReason> {
+    match status {
+        AccountStatus::Invalid(reason) => reason.as_ref(),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1608,6 +3011,158 @@ mod tests {
             assert_eq!(res.len(), 2);
             assert!(res.contains(&alice_room_2));
             assert!(res.contains(&bob_room));
+
+            let res = db
+                .select_net_accounts_by_room_id(&alice_room_2)
+                .await
+                .unwrap();
+            assert_eq!(res, vec![alice.clone()]);
+
+            let res = db
+                .select_net_accounts_by_room_id(&alice_room_1)
+                .await
+                .unwrap();
+            assert!(res.is_empty());
+
+            // Remove RoomId.
+            db.insert_direct_room(&Account::from("@alice:matrix.org"), &alice_room_2)
+                .await
+                .unwrap();
+            db.remove_room_id(&alice_room_2).await.unwrap();
+
+            let res = db.select_room_id(&alice).await.unwrap();
+            assert!(res.is_none());
+
+            let res = db.select_direct_rooms().await.unwrap();
+            assert!(res.is_empty());
+
+            let res = db.select_room_ids().await.unwrap();
+            assert_eq!(res, vec![bob_room]);
+        });
+    }
+
+    #[test]
+    fn queue_rooms_to_leave() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let room_1 = RoomId::try_from("!ROOM1:matrix.org").unwrap();
+            let room_2 = RoomId::try_from("!ROOM2:matrix.org").unwrap();
+
+            db.insert_room_to_leave(&room_1).await.unwrap();
+            db.insert_room_to_leave(&room_2).await.unwrap();
+            // Repeated inserts are ignored.
+            db.insert_room_to_leave(&room_1).await.unwrap();
+
+            let res = db.select_rooms_to_leave().await.unwrap();
+            assert_eq!(res.len(), 2);
+            assert!(res.contains(&(room_1.clone(), 0)));
+            assert!(res.contains(&(room_2.clone(), 0)));
+
+            // Postponed rooms are not due yet.
+            db.postpone_room_to_leave(&room_1, 60).await.unwrap();
+
+            let res = db.select_rooms_to_leave().await.unwrap();
+            assert_eq!(res, vec![(room_2.clone(), 0)]);
+
+            db.remove_room_to_leave(&room_2).await.unwrap();
+
+            let res = db.select_rooms_to_leave().await.unwrap();
+            assert!(res.is_empty());
+
+            let res = db.count_rooms_to_leave().await.unwrap();
+            assert_eq!(res, 1);
+
+            // Attempts are counted.
+            db.postpone_room_to_leave(&room_1, 0).await.unwrap();
+
+            let res = db.select_rooms_to_leave().await.unwrap();
+            assert_eq!(res, vec![(room_1, 2)]);
+        });
+    }
+
+    #[test]
+    fn queue_user_lookups() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let net_account = NetAccount::from("14GcE3qBiEnAyg2sDfadT3fQhWd2Z3M59tWi1CvVV8UwxUfU");
+            let alice = Account::from("@alice:matrix.org");
+            let bob = Account::from("@bob:matrix.org");
+
+            // Attempts are counted.
+            let res = db
+                .postpone_user_lookup(&net_account, &alice, 0)
+                .await
+                .unwrap();
+            assert_eq!(res, 1);
+            let res = db
+                .postpone_user_lookup(&net_account, &alice, 0)
+                .await
+                .unwrap();
+            assert_eq!(res, 2);
+
+            // Postponed lookups are not due yet.
+            let res = db
+                .postpone_user_lookup(&net_account, &bob, 60)
+                .await
+                .unwrap();
+            assert_eq!(res, 1);
+
+            let res = db.select_user_lookups().await.unwrap();
+            assert_eq!(res, vec![(net_account.clone(), alice.clone())]);
+
+            db.remove_user_lookup(&net_account, &alice).await.unwrap();
+
+            let res = db.select_user_lookups().await.unwrap();
+            assert!(res.is_empty());
+        });
+    }
+
+    #[test]
+    fn insert_select_direct_rooms() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let alice = Account::from("@alice:matrix.org");
+            let bob = Account::from("@bob:matrix.org");
+            let eve = Account::from("@eve:matrix.org");
+
+            let alice_room_1 = RoomId::try_from("!ALICE1:matrix.org").unwrap();
+            let alice_room_2 = RoomId::try_from("!ALICE2:matrix.org").unwrap();
+            let bob_room = RoomId::try_from("!BOB:matrix.org").unwrap();
+
+            db.insert_direct_room(&alice, &alice_room_1).await.unwrap();
+            db.insert_direct_room(&bob, &bob_room).await.unwrap();
+
+            // Updated data with repeated inserts.
+            db.insert_direct_room(&alice, &alice_room_2).await.unwrap();
+
+            let res = db.select_direct_room(&alice).await.unwrap().unwrap();
+            assert_eq!(res, alice_room_2);
+
+            let res = db.select_direct_room(&eve).await.unwrap();
+            assert!(res.is_none());
+
+            let res = db.select_direct_rooms().await.unwrap();
+            assert_eq!(res.len(), 2);
+            assert!(res.contains(&(alice.clone(), alice_room_2.clone())));
+            assert!(res.contains(&(bob.clone(), bob_room.clone())));
+
+            // Direct rooms are considered to be alive.
+            let res = db.select_room_ids().await.unwrap();
+            assert_eq!(res.len(), 2);
+
+            db.remove_direct_room(&alice).await.unwrap();
+
+            let res = db.select_direct_room(&alice).await.unwrap();
+            assert!(res.is_none());
+
+            let res = db.select_room_ids().await.unwrap();
+            assert_eq!(res, vec![bob_room]);
         });
     }
 
@@ -1715,25 +3270,212 @@ mod tests {
                 .push_account(AccountType::Web, Account::from("alice.com"))
                 .unwrap();
 
-            db.insert_identity(&ident).await.unwrap();
+            db.insert_identity(&ident).await.unwrap();
+
+            // Set account status to valid
+            db.set_account_status(&alice, &AccountType::Matrix, &AccountStatus::Valid)
+                .await
+                .unwrap();
+
+            let res = db.select_account_statuses(&alice).await.unwrap();
+            assert_eq!(res.len(), 2);
+            res.contains(&(
+                AccountType::Matrix,
+                Account::from("@alice:matrix.org"),
+                AccountStatus::Valid,
+            ));
+            res.contains(&(
+                AccountType::Web,
+                Account::from("alice.com"),
+                AccountStatus::Unknown,
+            ));
+        });
+    }
+
+    #[test]
+    fn invalid_account_reasons() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let alice = NetAccount::from("14GcE3qBiEnAyg2sDfadT3fQhWd2Z3M59tWi1CvVV8UwxUfU");
+
+            let mut ident = OnChainIdentity::new(alice.clone()).unwrap();
+            ident
+                .push_account(AccountType::Matrix, Account::from("@alice:matrix.org"))
+                .unwrap();
+            ident
+                .push_account(AccountType::Twitter, Account::from("@alice"))
+                .unwrap();
+            ident
+                .push_account(AccountType::Email, Account::from("alice@example.com"))
+                .unwrap();
+
+            db.insert_identity(&ident).await.unwrap();
+
+            let res = db.select_invalid_accounts(&alice).await.unwrap();
+            assert!(res.is_empty());
+
+            db.set_account_status(
+                &alice,
+                &AccountType::Twitter,
+                &AccountStatus::Invalid(Some(InvalidReason::Suspended)),
+            )
+            .await
+            .unwrap();
+            db.set_account_status(&alice, &AccountType::Email, &AccountStatus::Invalid(None))
+                .await
+                .unwrap();
+
+            let mut res = db.select_invalid_accounts(&alice).await.unwrap();
+            res.sort_by(|(a, _, _), (b, _, _)| format!("{:?}", a).cmp(&format!("{:?}", b)));
+            assert_eq!(
+                res,
+                vec![
+                    (AccountType::Email, Account::from("alice@example.com"), None),
+                    (
+                        AccountType::Twitter,
+                        Account::from("@alice"),
+                        Some(InvalidReason::Suspended)
+                    ),
+                ]
+            );
+
+            // The reason is reset once the status changes.
+            db.set_account_status(&alice, &AccountType::Twitter, &AccountStatus::Valid)
+                .await
+                .unwrap();
+            db.set_account_status(&alice, &AccountType::Twitter, &AccountStatus::Invalid(None))
+                .await
+                .unwrap();
+
+            let res = db.select_invalid_accounts(&alice).await.unwrap();
+            assert!(res.contains(&(AccountType::Twitter, Account::from("@alice"), None)));
+
+            // The reason is part of the status.
+            db.set_account_status(
+                &alice,
+                &AccountType::Matrix,
+                &AccountStatus::Invalid(Some(InvalidReason::InviteRejected)),
+            )
+            .await
+            .unwrap();
+
+            let res = db.select_account_statuses(&alice).await.unwrap();
+            assert!(res.contains(&(
+                AccountType::Matrix,
+                Account::from("@alice:matrix.org"),
+                AccountStatus::Invalid(Some(InvalidReason::InviteRejected))
+            )));
+        });
+    }
+
+    #[test]
+    fn update_select_twitter_token() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let res = db.select_twitter_token().await.unwrap();
+            assert!(res.is_none());
+
+            let token = TwitterToken {
+                access_token: "access_1".to_string(),
+                refresh_token: "refresh_1".to_string(),
+                expires_at: 100,
+            };
+
+            db.update_twitter_token(&token).await.unwrap();
+
+            let res = db.select_twitter_token().await.unwrap();
+            assert_eq!(res, Some(token));
+
+            // The token is replaced.
+            let token = TwitterToken {
+                access_token: "access_2".to_string(),
+                refresh_token: "refresh_2".to_string(),
+                expires_at: 200,
+            };
+
+            db.update_twitter_token(&token).await.unwrap();
+
+            let res = db.select_twitter_token().await.unwrap();
+            assert_eq!(res, Some(token));
+        });
+    }
+
+    #[test]
+    fn adapter_health() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let res = db.select_unhealthy_adapters().await.unwrap();
+            assert!(res.is_empty());
+
+            db.set_adapter_unhealthy(&AccountType::Twitter, "first")
+                .await
+                .unwrap();
+            db.set_adapter_unhealthy(&AccountType::Twitter, "second")
+                .await
+                .unwrap();
+
+            let res = db.select_unhealthy_adapters().await.unwrap();
+            assert_eq!(res, vec![(AccountType::Twitter, "second".to_string())]);
+
+            db.set_adapter_healthy(&AccountType::Twitter).await.unwrap();
+
+            let res = db.select_unhealthy_adapters().await.unwrap();
+            assert!(res.is_empty());
+
+            db.set_adapter_unhealthy(&AccountType::Twitter, "third")
+                .await
+                .unwrap();
+            db.set_adapter_unhealthy(&AccountType::Matrix, "fourth")
+                .await
+                .unwrap();
+            db.clear_unhealthy_adapters().await.unwrap();
+
+            let res = db.select_unhealthy_adapters().await.unwrap();
+            assert!(res.is_empty());
+        });
+    }
+
+    #[test]
+    fn tweet_verifications() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let alice = Account::from("@alice");
+            let bob = Account::from("@bob");
+            let alice_id = TwitterId::from(1000);
+            let bob_id = TwitterId::from(2000);
+
+            let res = db.select_tweet_verifications().await.unwrap();
+            assert!(res.is_empty());
+
+            db.insert_tweet_verification(&alice, &alice_id)
+                .await
+                .unwrap();
+            db.insert_tweet_verification(&bob, &bob_id).await.unwrap();
+
+            let res = db.select_tweet_verifications().await.unwrap();
+            assert_eq!(
+                res,
+                vec![
+                    (alice.clone(), alice_id.clone(), None),
+                    (bob.clone(), bob_id.clone(), None)
+                ]
+            );
 
-            // Set account status to valid
-            db.set_account_status(&alice, &AccountType::Matrix, &AccountStatus::Valid)
+            db.update_tweet_verification(&alice, 1580705921830768647)
                 .await
                 .unwrap();
+            db.remove_tweet_verification(&bob).await.unwrap();
 
-            let res = db.select_account_statuses(&alice).await.unwrap();
-            assert_eq!(res.len(), 2);
-            res.contains(&(
-                AccountType::Matrix,
-                Account::from("@alice:matrix.org"),
-                AccountStatus::Valid,
-            ));
-            res.contains(&(
-                AccountType::Web,
-                Account::from("alice.com"),
-                AccountStatus::Unknown,
-            ));
+            let res = db.select_tweet_verifications().await.unwrap();
+            assert_eq!(res, vec![(alice, alice_id, Some(1580705921830768647))]);
         });
     }
 
@@ -1867,6 +3609,101 @@ mod tests {
 
             assert_eq!(account, bob);
             assert_eq!(init_msg, false);
+
+            // The Twitter ID of Alice now belongs to the screen name of Bob.
+            db.insert_twitter_id(&bob, &alice_id).await.unwrap();
+
+            let (account, _) = db
+                .select_account_from_twitter_id(&alice_id)
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(account, bob);
+
+            let res = db.select_twitter_id(&alice).await.unwrap();
+            assert!(res.is_none());
+
+            let res = db.select_twitter_id(&bob).await.unwrap().unwrap();
+            assert_eq!(res, alice_id);
+        });
+    }
+
+    #[test]
+    fn twitter_id_ignores_other_account_types() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let bob = Account::from("Bob");
+            let bob_id = TwitterId::from(2000);
+
+            // Eve uses the same name for another account type.
+            let mut eve_ident = OnChainIdentity::new(NetAccount::eve()).unwrap();
+            eve_ident
+                .push_account(AccountType::DisplayName, bob.clone())
+                .unwrap();
+
+            let mut bob_ident = OnChainIdentity::new(NetAccount::bob()).unwrap();
+            bob_ident
+                .push_account(AccountType::Twitter, bob.clone())
+                .unwrap();
+
+            db.insert_identity(&eve_ident).await.unwrap();
+            db.insert_identity(&bob_ident).await.unwrap();
+
+            db.insert_twitter_id(&bob, &bob_id).await.unwrap();
+            db.confirm_init_message(&bob).await.unwrap();
+
+            let res = db.select_twitter_id(&bob).await.unwrap().unwrap();
+            assert_eq!(res, bob_id);
+
+            let (account, init_msg) = db
+                .select_account_from_twitter_id(&bob_id)
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(account, bob);
+            assert_eq!(init_msg, true);
+
+            // Inserting the same pair again keeps the entry.
+            db.insert_twitter_id(&bob, &bob_id).await.unwrap();
+
+            let res = db.select_twitter_id(&bob).await.unwrap().unwrap();
+            assert_eq!(res, bob_id);
+        });
+    }
+
+    #[test]
+    fn twitter_event_tracking() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let alice_id = TwitterId::from(1000);
+            let bob_id = TwitterId::from(2000);
+            let events = [11, 22, 33];
+
+            let res = db.find_untracked_twitter_events(&events).await.unwrap();
+            assert_eq!(res, vec![11, 22, 33]);
+
+            db.track_twitter_events(&alice_id, &[11, 33]).await.unwrap();
+
+            let res = db.find_untracked_twitter_events(&events).await.unwrap();
+            assert_eq!(res, vec![22]);
+
+            // Tracking is idempotent.
+            db.track_twitter_events(&bob_id, &[22, 22]).await.unwrap();
+            db.track_twitter_events(&alice_id, &[11]).await.unwrap();
+
+            let res = db.find_untracked_twitter_events(&events).await.unwrap();
+            assert!(res.is_empty());
+
+            db.prune_twitter_events(3600).await.unwrap();
+
+            let res = db.find_untracked_twitter_events(&events).await.unwrap();
+            assert!(res.is_empty());
         });
     }
 
@@ -1882,18 +3719,288 @@ mod tests {
 
             let list = [id_1.clone(), id_2.clone(), id_3.clone()];
 
-            let res = db.find_untracked_email_ids(&list).await.unwrap();
+            let res = db.find_untracked_email_ids(&list, 1).await.unwrap();
             assert_eq!(&res, &[&id_1, &id_2, &id_3]);
 
-            db.track_email_id(&id_2).await.unwrap();
+            db.track_email_id(&id_2, 1).await.unwrap();
 
-            let res = db.find_untracked_email_ids(&list).await.unwrap();
+            let res = db.find_untracked_email_ids(&list, 1).await.unwrap();
             assert_eq!(&res, &[&id_1, &id_3]);
 
-            db.track_email_id(&id_1).await.unwrap();
+            db.track_email_id(&id_1, 1).await.unwrap();
+
+            let res = db.find_untracked_email_ids(&list, 1).await.unwrap();
+            assert_eq!(&res, &[&id_3]);
+
+            // IDs of a different UIDVALIDITY are not considered tracked.
+            let res = db.find_untracked_email_ids(&list, 2).await.unwrap();
+            assert_eq!(&res, &[&id_1, &id_2, &id_3]);
+        });
+    }
+
+    #[test]
+    fn invalidate_prune_email_ids() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let id_1 = EmailId::from(11u32);
+            let id_2 = EmailId::from(22u32);
+            let id_3 = EmailId::from(33u32);
+
+            let list = [id_1.clone(), id_2.clone(), id_3.clone()];
 
-            let res = db.find_untracked_email_ids(&list).await.unwrap();
+            db.track_email_id(&id_1, 1).await.unwrap();
+            db.track_email_id(&id_2, 1).await.unwrap();
+
+            // Same UIDVALIDITY, nothing changes.
+            db.invalidate_email_ids(1).await.unwrap();
+
+            let res = db.find_untracked_email_ids(&list, 1).await.unwrap();
             assert_eq!(&res, &[&id_3]);
+
+            // Changed UIDVALIDITY, previous IDs are removed.
+            db.invalidate_email_ids(2).await.unwrap();
+            db.track_email_id(&id_3, 2).await.unwrap();
+
+            let res = db.find_untracked_email_ids(&list, 1).await.unwrap();
+            assert_eq!(&res, &[&id_1, &id_2, &id_3]);
+
+            let res = db.find_untracked_email_ids(&list, 2).await.unwrap();
+            assert_eq!(&res, &[&id_1, &id_2]);
+
+            // Entries within the retention period are kept.
+            db.prune_email_ids(3).await.unwrap();
+
+            let res = db.find_untracked_email_ids(&list, 2).await.unwrap();
+            assert_eq!(&res, &[&id_1, &id_2]);
+
+            time::delay_for(Duration::from_secs(2)).await;
+
+            db.prune_email_ids(1).await.unwrap();
+
+            let res = db.find_untracked_email_ids(&list, 2).await.unwrap();
+            assert_eq!(&res, &[&id_1, &id_2, &id_3]);
+        });
+    }
+
+    #[test]
+    fn normalize_stored_accounts() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let path = db_path();
+            let db = Database2::new(&path).unwrap();
+
+            let mut ident = OnChainIdentity::new(NetAccount::alice()).unwrap();
+            ident
+                .push_account(AccountType::Matrix, Account::from("Alice:Matrix.org"))
+                .unwrap();
+            ident
+                .push_account(AccountType::Twitter, Account::from("Alice_01"))
+                .unwrap();
+            ident
+                .push_account(AccountType::Email, Account::from("Alice@example.com"))
+                .unwrap();
+
+            db.insert_identity(&ident).await.unwrap();
+
+            // Accounts are normalized when opening the database.
+            let db = Database2::new(&path).unwrap();
+
+            let mut res = db
+                .select_account_statuses(&NetAccount::alice())
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(account_ty, account, _)| (account_ty, account))
+                .collect::<Vec<(AccountType, Account)>>();
+            res.sort_by(|(_, a), (_, b)| a.as_str().cmp(b.as_str()));
+
+            assert_eq!(
+                res,
+                vec![
+                    (AccountType::Matrix, Account::from("@Alice:matrix.org")),
+                    (AccountType::Twitter, Account::from("@alice_01")),
+                    (AccountType::Email, Account::from("Alice@example.com")),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn adopt_unknown_uid_validity() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let id_1 = EmailId::from(11u32);
+            let id_2 = EmailId::from(22u32);
+
+            let list = [id_1.clone(), id_2.clone()];
+
+            // Tracked by an earlier version, without UIDVALIDITY.
+            db.track_email_id(&id_1, 0).await.unwrap();
+
+            db.invalidate_email_ids(5).await.unwrap();
+
+            let res = db.find_untracked_email_ids(&list, 5).await.unwrap();
+            assert_eq!(&res, &[&id_2]);
+
+            // Once adopted, changes of the UIDVALIDITY apply as usual.
+            db.invalidate_email_ids(6).await.unwrap();
+
+            let res = db.find_untracked_email_ids(&list, 6).await.unwrap();
+            assert_eq!(&res, &[&id_1, &id_2]);
+        });
+    }
+
+    #[test]
+    fn sent_email_tracking() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            let alice = Account::from("alice@example.com");
+            let bob = Account::from("bob@example.com");
+
+            let ids = vec!["1234@example.com".to_string()];
+
+            let res = db.is_sent_email(&ids, &alice).await.unwrap();
+            assert!(!res);
+
+            db.track_sent_email("1234@example.com", &alice)
+                .await
+                .unwrap();
+
+            let res = db.is_sent_email(&ids, &alice).await.unwrap();
+            assert!(res);
+
+            // Addresses are compared case-insensitively.
+            let res = db
+                .is_sent_email(&ids, &Account::from("Alice@example.com"))
+                .await
+                .unwrap();
+            assert!(res);
+
+            // Sent to a different address.
+            let res = db.is_sent_email(&ids, &bob).await.unwrap();
+            assert!(!res);
+
+            let res = db
+                .is_sent_email(&["5678@example.com".to_string()], &alice)
+                .await
+                .unwrap();
+            assert!(!res);
+
+            // Entries within the retention period are kept.
+            db.prune_sent_emails(3).await.unwrap();
+
+            let res = db.is_sent_email(&ids, &alice).await.unwrap();
+            assert!(res);
+
+            time::delay_for(Duration::from_secs(2)).await;
+
+            db.prune_sent_emails(1).await.unwrap();
+
+            let res = db.is_sent_email(&ids, &alice).await.unwrap();
+            assert!(!res);
+        });
+    }
+
+    #[test]
+    fn insert_confirm_email_codes() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = Database2::new(&db_path()).unwrap();
+
+            // Prepare addresses.
+            let alice = NetAccount::from("14GcE3qBiEnAyg2sDfadT3fQhWd2Z3M59tWi1CvVV8UwxUfU");
+            let bob = NetAccount::from("163AnENMFr6k4UWBGdHG9dTWgrDmnJgmh3HBBZuVWhUTTU5C");
+
+            // Create and insert identities into storage.
+            let mut alice_ident = OnChainIdentity::new(alice.clone()).unwrap();
+            alice_ident
+                .push_account(AccountType::Email, Account::from("alice@example.com"))
+                .unwrap();
+
+            let mut bob_ident = OnChainIdentity::new(bob.clone()).unwrap();
+            bob_ident
+                .push_account(AccountType::Email, Account::from("bob@example.com"))
+                .unwrap();
+
+            db.insert_identity_batch(&[&alice_ident, &bob_ident])
+                .await
+                .unwrap();
+
+            let alice_code = Challenge::gen_random();
+            let bob_code = Challenge::gen_random();
+
+            db.insert_email_code(&alice, &alice_code).await.unwrap();
+            db.insert_email_code(&bob, &bob_code).await.unwrap();
+
+            let res = db.select_confirmed_email_codes().await.unwrap();
+            assert!(res.is_empty());
+
+            // Unknown code.
+            let res = db.confirm_email_code("invalid", 3600, None).await.unwrap();
+            assert_eq!(res, false);
+
+            // Code of a different address.
+            let res = db
+                .confirm_email_code(
+                    alice_code.as_str(),
+                    3600,
+                    Some(&Account::from("bob@example.com")),
+                )
+                .await
+                .unwrap();
+            assert_eq!(res, false);
+
+            let res = db.select_confirmed_email_codes().await.unwrap();
+            assert!(res.is_empty());
+
+            let res = db
+                .confirm_email_code(
+                    alice_code.as_str(),
+                    3600,
+                    Some(&Account::from("Alice@example.com")),
+                )
+                .await
+                .unwrap();
+            assert_eq!(res, true);
+
+            let res = db.select_confirmed_email_codes().await.unwrap();
+            assert_eq!(res, vec![alice.clone()]);
+
+            // Codes can only be used once.
+            db.remove_email_code(&alice).await.unwrap();
+
+            let res = db
+                .confirm_email_code(alice_code.as_str(), 3600, None)
+                .await
+                .unwrap();
+            assert_eq!(res, false);
+
+            let res = db.select_confirmed_email_codes().await.unwrap();
+            assert!(res.is_empty());
+
+            // Expired code.
+            time::delay_for(Duration::from_secs(2)).await;
+
+            let res = db
+                .confirm_email_code(bob_code.as_str(), 1, None)
+                .await
+                .unwrap();
+            assert_eq!(res, false);
+
+            let res = db
+                .confirm_email_code(bob_code.as_str(), 3600, None)
+                .await
+                .unwrap();
+            assert_eq!(res, true);
+
+            let res = db.select_confirmed_email_codes().await.unwrap();
+            assert_eq!(res, vec![bob]);
         });
     }
 
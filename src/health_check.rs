@@ -1,9 +1,21 @@
+use crate::adapters::email::EMAIL_CODE_EXPIRY;
+use crate::db::Database2;
 use crate::primitives::Result;
 use actix_web::http::StatusCode;
-use actix_web::{get, rt, App, HttpServer, Responder};
+use actix_web::{get, post, rt, web, App, HttpResponse, HttpServer, Responder};
 
-/// Currently, the health check endpoint just returns a "200 OK" response. In
-/// the future, this service might be improved for more advanced reporting.
+/// The health check endpoint always returns a "200 OK" response while the
+/// service is running. It is used as the liveness probe, so a single
+/// unhealthy adapter must not result in a restart of all the other ones.
+///
+/// The status endpoint returns "503 Service Unavailable" if any adapter
+/// reported itself as unhealthy, e.g. because the remote service could not be
+/// reached on startup.
+///
+/// The same service serves the verification links sent by the Email adapter
+/// (see `EmailVerificationMode::Code`). Opening the link only shows a
+/// confirmation button, so links opened automatically (e.g. by mail scanners)
+/// do not confirm the address.
 pub struct HealthCheck {}
 
 #[get("/healthcheck")]
@@ -11,13 +23,81 @@ async fn endpoint() -> impl Responder {
     "OK".with_status(StatusCode::OK)
 }
 
+#[get("/status")]
+async fn status(db: web::Data<Database2>) -> impl Responder {
+    match db.select_unhealthy_adapters().await {
+        Ok(unhealthy) if unhealthy.is_empty() => "OK".to_string().with_status(StatusCode::OK),
+        Ok(unhealthy) => unhealthy
+            .iter()
+            .map(|(account_ty, reason)| format!("{:?}: {}", account_ty, reason))
+            .collect::<Vec<String>>()
+            .join("\n")
+            .with_status(StatusCode::SERVICE_UNAVAILABLE),
+        Err(err) => {
+            error!("Failed to check adapter health: {}", err);
+            "Internal error"
+                .to_string()
+                .with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[get("/verify/email/{code}")]
+async fn verify_email_page(code: web::Path<String>) -> impl Responder {
+    // Codes are generated by `Challenge::gen_random`, this also prevents
+    // injecting markup into the page.
+    if !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return HttpResponse::NotFound().body("The verification link is invalid.");
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(
+            "<!DOCTYPE html>\n\
+            <html>\n\
+            <body>\n\
+            <p>Please confirm the verification of your email address.</p>\n\
+            <form method=\"post\">\n\
+            <button type=\"submit\">Confirm</button>\n\
+            </form>\n\
+            </body>\n\
+            </html>\n",
+        )
+}
+
+#[post("/verify/email/{code}")]
+async fn verify_email(db: web::Data<Database2>, code: web::Path<String>) -> impl Responder {
+    // The Email adapter picks up confirmed codes and updates the challenge
+    // status accordingly.
+    match db
+        .confirm_email_code(code.as_str(), EMAIL_CODE_EXPIRY, None)
+        .await
+    {
+        Ok(true) => "Your email address has been verified.".with_status(StatusCode::OK),
+        Ok(false) => {
+            "The verification link is invalid or has expired.".with_status(StatusCode::NOT_FOUND)
+        }
+        Err(err) => {
+            error!("Failed to confirm email code: {}", err);
+            "Internal error, please try again later.".with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 impl HealthCheck {
-    pub fn start() -> Result<()> {
+    pub fn start(db: Database2) -> Result<()> {
         let mut sys = rt::System::new("health check service");
 
-        let server = HttpServer::new(|| App::new().service(endpoint))
-            .bind("0.0.0.0:8080")?
-            .run();
+        let server = HttpServer::new(move || {
+            App::new()
+                .data(db.clone())
+                .service(endpoint)
+                .service(status)
+                .service(verify_email_page)
+                .service(verify_email)
+        })
+        .bind("0.0.0.0:8080")?
+        .run();
 
         sys.block_on(server)?;
 
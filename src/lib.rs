@@ -8,10 +8,13 @@ extern crate serde;
 extern crate failure;
 
 use adapters::{
-    DisplayNameHandler, EmailHandler, EmailTransport, MatrixHandler, MatrixTransport,
+    DisplayNameHandler, EmailHandler, EmailTransport, MatrixHandler, MatrixTransport, Twitter,
     TwitterHandler, TwitterTransport,
 };
-pub use adapters::{MatrixClient, SmtpImapClientBuilder, TwitterBuilder};
+pub use adapters::{
+    EmailVerificationMode, MatrixClient, SmtpImapClientBuilder, TwitterBuilder, TwitterClient,
+    TwitterV2Builder,
+};
 use comms::{CommsMain, CommsVerifier};
 use connector::{Connector, ConnectorInitTransports};
 pub use connector::{
@@ -29,6 +32,7 @@ use std::io::prelude::*;
 use std::process::exit;
 #[cfg(test)]
 use std::sync::Arc;
+pub use templates::Templates;
 #[cfg(test)]
 use tests::mocks::{ConnectorMocker, ConnectorReaderMocker, ConnectorWriterMocker, EventManager2};
 use tokio::time::{self, Duration};
@@ -39,7 +43,9 @@ mod connector;
 mod db;
 mod health_check;
 mod manager;
+mod message;
 mod primitives;
+mod templates;
 #[cfg(test)]
 mod tests;
 mod verifier;
@@ -59,16 +65,112 @@ pub struct Config {
     pub matrix_password: String,
     //
     pub twitter_screen_name: String,
+    // Not required for the API v2.
+    #[serde(default)]
     pub twitter_api_key: String,
+    #[serde(default)]
     pub twitter_api_secret: String,
+    #[serde(default)]
     pub twitter_token: String,
+    #[serde(default)]
     pub twitter_token_secret: String,
+    // Optional: use the Twitter API v2 with OAuth 2.0 user context tokens
+    // instead of the API v1.1 with the OAuth 1.0a credentials above. If those
+    // credentials are specified, the API v1.1 is used while no access token
+    // can be obtained.
+    #[serde(default)]
+    pub twitter_api_v2: bool,
+    #[serde(default)]
+    pub twitter_client_id: Option<String>,
+    #[serde(default)]
+    pub twitter_client_secret: Option<String>,
+    #[serde(default)]
+    pub twitter_refresh_token: Option<String>,
     //
     pub email_server: String,
     pub imap_server: String,
     pub email_inbox: String,
     pub email_user: String,
     pub email_password: String,
+    // Optional: verify email addresses with a one-time code or link instead
+    // of a signature.
+    #[serde(default)]
+    pub email_code_verification: bool,
+    #[serde(default)]
+    pub email_code_base_url: Option<String>,
+    // Optional: domain of the generated Message-IDs. Defaults to the domain of
+    // `email_user`.
+    #[serde(default)]
+    pub email_message_id_domain: Option<String>,
+    // Optional: directory of custom message templates, see `Templates`.
+    #[serde(default)]
+    pub template_dir: Option<String>,
+    #[serde(default)]
+    pub template_language: Option<String>,
+}
+
+impl Config {
+    pub fn twitter_client(&self, db: Database2) -> Result<TwitterClient> {
+        let screen_name = Account::from(self.twitter_screen_name.as_str());
+
+        if self.twitter_api_v2 {
+            let mut builder = TwitterV2Builder::new().db(db).screen_name(screen_name);
+
+            if let Some(id) = &self.twitter_client_id {
+                builder = builder.client_id(id.clone());
+            }
+            if let Some(secret) = &self.twitter_client_secret {
+                builder = builder.client_secret(secret.clone());
+            }
+            if let Some(token) = &self.twitter_refresh_token {
+                builder = builder.refresh_token(token.clone());
+            }
+
+            let client = TwitterClient::from(builder.build()?);
+
+            // The API v1.1 is used as a fallback if its credentials are
+            // specified, too.
+            if self.twitter_api_key.is_empty() {
+                Ok(client)
+            } else {
+                Ok(client.with_fallback(self.twitter_v1(screen_name)?))
+            }
+        } else {
+            Ok(self.twitter_v1(screen_name)?.into())
+        }
+    }
+    fn twitter_v1(&self, screen_name: Account) -> Result<Twitter> {
+        TwitterBuilder::new()
+            .screen_name(screen_name)
+            .consumer_key(self.twitter_api_key.clone())
+            .consumer_secret(self.twitter_api_secret.clone())
+            .sig_method("HMAC-SHA1".to_string())
+            .token(self.twitter_token.clone())
+            .token_secret(self.twitter_token_secret.clone())
+            .version(1.0)
+            .build()
+    }
+    pub fn email_verification_mode(&self) -> EmailVerificationMode {
+        if self.email_code_verification {
+            EmailVerificationMode::Code {
+                base_url: self.email_code_base_url.clone(),
+            }
+        } else {
+            EmailVerificationMode::Signature
+        }
+    }
+    pub fn templates(&self) -> Result<Templates> {
+        if let Some(dir) = &self.template_dir {
+            Templates::load(
+                dir,
+                self.template_language
+                    .as_deref()
+                    .unwrap_or(templates::DEFAULT_LANGUAGE),
+            )
+        } else {
+            Ok(Templates::default())
+        }
+    }
 }
 
 fn open_config() -> Result<Config> {
@@ -135,12 +237,16 @@ pub async fn run<
     matrix_transport: M,
     twitter_transport: T,
     email_transport: E,
+    email_mode: EmailVerificationMode,
+    templates: Templates,
 ) -> Result<()> {
     let (_, c_connector) = run_adapters(
         db2.clone(),
         matrix_transport,
         twitter_transport,
         email_transport,
+        email_mode,
+        templates,
     )
     .await?;
 
@@ -197,6 +303,8 @@ pub async fn test_run<
         matrix_transport,
         twitter_transport,
         email_transport,
+        EmailVerificationMode::Signature,
+        Templates::default(),
     )
     .await?;
 
@@ -234,6 +342,8 @@ async fn run_adapters<
     mut matrix_transport: M,
     twitter_transport: T,
     email_transport: E,
+    email_mode: EmailVerificationMode,
+    templates: Templates,
 ) -> Result<(CommsMain, CommsVerifier)> {
     info!("Setting up manager");
     let mut manager = IdentityManager::new(db2.clone())?;
@@ -267,18 +377,22 @@ async fn run_adapters<
     info!("Starting Matrix task");
     let l_db = db2.clone();
     let l_c_matrix = c_matrix.clone();
+    let l_templates = templates.clone();
     tokio::spawn(async move {
-        matrix_transport.run_emitter(l_db.clone(), c_emitter).await;
+        matrix_transport
+            .run_emitter(l_db.clone(), c_emitter, l_templates.clone())
+            .await;
 
-        MatrixHandler::new(l_db, l_c_matrix, matrix_transport)
+        MatrixHandler::new(l_db, l_c_matrix, matrix_transport, l_templates)
             .start()
             .await;
     });
 
     info!("Starting Twitter task");
     let l_db = db2.clone();
+    let l_templates = templates.clone();
     tokio::spawn(async move {
-        TwitterHandler::new(l_db, c_twitter)
+        TwitterHandler::new(l_db, c_twitter, l_templates)
             .start(twitter_transport)
             .await;
     });
@@ -286,7 +400,7 @@ async fn run_adapters<
     info!("Starting Email task");
     let l_db = db2.clone();
     tokio::spawn(async move {
-        EmailHandler::new(l_db, c_email)
+        EmailHandler::new(l_db, c_email, email_mode, templates)
             .start(email_transport)
             .await;
     });
@@ -1,3 +1,4 @@
+use crate::adapters::{normalize_matrix_id, normalize_twitter_handle};
 use crate::comms::{generate_comms, CommsMain, CommsMessage, CommsVerifier};
 use crate::db::Database2;
 use crate::primitives::{
@@ -111,8 +112,9 @@ pub enum AccountStatus {
     Unknown,
     #[serde(rename = "valid")]
     Valid,
+    /// The reason is presented to the user, if known.
     #[serde(rename = "invalid")]
-    Invalid,
+    Invalid(Option<InvalidReason>),
     #[serde(rename = "notified")]
     Notified,
 }
@@ -126,7 +128,8 @@ impl ToSql for AccountStatus {
         match self {
             Unknown => Ok(Borrowed(Text(b"unknown"))),
             Valid => Ok(Borrowed(Text(b"valid"))),
-            Invalid => Ok(Borrowed(Text(b"invalid"))),
+            // The reason is stored separately.
+            Invalid(_) => Ok(Borrowed(Text(b"invalid"))),
             Notified => Ok(Borrowed(Text(b"notified"))),
         }
     }
@@ -138,7 +141,7 @@ impl FromSql for AccountStatus {
             ValueRef::Text(val) => match val {
                 b"unknown" => Ok(AccountStatus::Unknown),
                 b"valid" => Ok(AccountStatus::Valid),
-                b"invalid" => Ok(AccountStatus::Invalid),
+                b"invalid" => Ok(AccountStatus::Invalid(None)),
                 b"notified" => Ok(AccountStatus::Notified),
                 _ => Err(FromSqlError::InvalidType),
             },
@@ -147,6 +150,60 @@ impl FromSql for AccountStatus {
     }
 }
 
+/// Why an account was marked as `AccountStatus::Invalid`.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidReason {
+    /// The account identifier could not be parsed.
+    InvalidFormat,
+    /// The account does not exist (anymore).
+    NotFound,
+    Suspended,
+    DirectMessagesClosed,
+    InviteRejected,
+    MailBounced,
+    HomeserverUnreachable,
+    DisplayNameViolation,
+}
+
+impl ToSql for InvalidReason {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        use InvalidReason::*;
+        use ToSqlOutput::*;
+        use ValueRef::*;
+
+        match self {
+            InvalidFormat => Ok(Borrowed(Text(b"invalid_format"))),
+            NotFound => Ok(Borrowed(Text(b"not_found"))),
+            Suspended => Ok(Borrowed(Text(b"suspended"))),
+            DirectMessagesClosed => Ok(Borrowed(Text(b"direct_messages_closed"))),
+            InviteRejected => Ok(Borrowed(Text(b"invite_rejected"))),
+            MailBounced => Ok(Borrowed(Text(b"mail_bounced"))),
+            HomeserverUnreachable => Ok(Borrowed(Text(b"homeserver_unreachable"))),
+            DisplayNameViolation => Ok(Borrowed(Text(b"display_name_violation"))),
+        }
+    }
+}
+
+impl FromSql for InvalidReason {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(val) => match val {
+                b"invalid_format" => Ok(InvalidReason::InvalidFormat),
+                b"not_found" => Ok(InvalidReason::NotFound),
+                b"suspended" => Ok(InvalidReason::Suspended),
+                b"direct_messages_closed" => Ok(InvalidReason::DirectMessagesClosed),
+                b"invite_rejected" => Ok(InvalidReason::InviteRejected),
+                b"mail_bounced" => Ok(InvalidReason::MailBounced),
+                b"homeserver_unreachable" => Ok(InvalidReason::HomeserverUnreachable),
+                b"display_name_violation" => Ok(InvalidReason::DisplayNameViolation),
+                _ => Err(FromSqlError::InvalidType),
+            },
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
 pub struct IdentityManager {
     db2: Database2,
     comms: CommsTable,
@@ -202,6 +259,9 @@ impl IdentityManager {
                 NotifyStatusChange { net_account } => {
                     self.handle_status_change(net_account).await?
                 }
+                CancelJudgementRequest { net_account } => {
+                    self.handle_cancellation(net_account).await?
+                }
                 MessageAcknowledged => {}
                 ExistingDisplayNames { accounts } => {
                     for account in &accounts {
@@ -236,12 +296,55 @@ impl IdentityManager {
 
         Ok(())
     }
+    async fn handle_cancellation(&self, net_account: NetAccount) -> Result<()> {
+        info!("Cancelling verification for: {}", net_account.as_str());
+
+        // Unlike with timed out identities, no judgement is given. The
+        // cancellation is requested by the user and only drops the local
+        // state.
+        self.get_comms(&AccountType::Matrix)?
+            .leave_matrix_room(net_account.clone());
+
+        // Leave enough time for the Matrix client to close the room.
+        time::delay_for(Duration::from_secs(3)).await;
+
+        self.db2.remove_identity(&net_account).await?;
+
+        Ok(())
+    }
     async fn handle_new_judgment_request(&mut self, mut ident: OnChainIdentity) -> Result<()> {
         debug!(
             "Handling new judgment request for account: {}",
             ident.net_account().as_str()
         );
 
+        // Canonicalize the account identifiers, so the same account in a
+        // different notation is recognized. Matrix IDs and Twitter handles
+        // which cannot be parsed are marked invalid right away instead of
+        // trying to contact them.
+        let mut has_invalid = false;
+        for state in ident.accounts.iter_mut() {
+            let normalized = match state.account_ty {
+                AccountType::Matrix => normalize_matrix_id(&state.account),
+                AccountType::Twitter => normalize_twitter_handle(&state.account),
+                _ => continue,
+            };
+
+            if let Some(account) = normalized {
+                state.account = account;
+            } else {
+                warn!(
+                    "Identity {} specifies an invalid {:?} account: {}",
+                    ident.network_address.address().as_str(),
+                    state.account_ty,
+                    state.account.as_str()
+                );
+
+                state.account_status = AccountStatus::Invalid(Some(InvalidReason::InvalidFormat));
+                has_invalid = true;
+            }
+        }
+
         // Check the current, associated addresses of the identity, if any.
         let accounts = self.db2.select_addresses(&ident.net_account()).await?;
         let mut to_delete = vec![];
@@ -280,6 +383,11 @@ impl IdentityManager {
         self.db2.insert_identity(&ident).await?;
 
         for state in ident.account_states() {
+            // The reason is stored together with the identity.
+            if let AccountStatus::Invalid(_) = state.account_status {
+                continue;
+            }
+
             if state.account_ty == AccountType::Twitter {
                 self.db2.reset_init_message(&state.account).await?;
             }
@@ -290,6 +398,12 @@ impl IdentityManager {
             })?;
         }
 
+        // Inform the user about invalid accounts through another channel.
+        if has_invalid {
+            self.handle_status_change(ident.net_account().clone())
+                .await?;
+        }
+
         Ok(())
     }
     async fn handle_status_change(&mut self, net_account: NetAccount) -> Result<()> {
@@ -351,23 +465,11 @@ impl IdentityManager {
             None
         }
 
-        /// Find invalid accounts.
-        fn find_invalid<'a>(
-            account_statuses: &[(AccountType, Account, AccountStatus)],
-        ) -> Vec<(AccountType, Account)> {
-            account_statuses
-                .iter()
-                .cloned()
-                .filter(|(_, _, status)| status == &AccountStatus::Invalid)
-                .map(|(account_ty, account, _)| (account_ty, account))
-                .collect::<Vec<(AccountType, Account)>>()
-        }
-
         let account_statuses = self.db2.select_account_statuses(&net_account).await?;
 
         // If invalid accounts were found, attempt to contact the user in order
         // to inform that person about the current state of invalid accounts.
-        let invalid_accounts = find_invalid(&account_statuses);
+        let invalid_accounts = self.db2.select_invalid_accounts(&net_account).await?;
         if !invalid_accounts.is_empty() {
             if let Some(to_notify) = find_valid(&account_statuses) {
                 self.get_comms(to_notify).map(|comms| {
@@ -0,0 +1,130 @@
+use crate::templates::RenderedMessage;
+
+/// A message with a plain text body and an optional HTML formatted body
+/// (`org.matrix.custom.html`). Adapters which do not support formatting just
+/// use the plain text body.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FormattedMessage {
+    pub plain: String,
+    pub html: Option<String>,
+}
+
+impl From<String> for FormattedMessage {
+    fn from(plain: String) -> Self {
+        FormattedMessage {
+            plain: plain,
+            html: None,
+        }
+    }
+}
+
+impl From<&str> for FormattedMessage {
+    fn from(plain: &str) -> Self {
+        FormattedMessage::from(plain.to_string())
+    }
+}
+
+impl From<RenderedMessage> for FormattedMessage {
+    fn from(rendered: RenderedMessage) -> Self {
+        FormattedMessage {
+            plain: rendered.text,
+            html: rendered.html,
+        }
+    }
+}
+
+impl From<FormattedMessage> for String {
+    fn from(message: FormattedMessage) -> Self {
+        message.plain
+    }
+}
+
+/// Builds the plain text and the HTML body of a message simultaneously.
+pub struct MessageBuilder {
+    plain: String,
+    html: String,
+    // Code blocks are already displayed on their own line, so the line break
+    // following a code block is skipped in the HTML body.
+    after_block: bool,
+}
+
+impl MessageBuilder {
+    pub fn new() -> Self {
+        MessageBuilder {
+            plain: String::new(),
+            html: String::new(),
+            after_block: false,
+        }
+    }
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        self.plain.push_str(text);
+
+        let html_text = if self.after_block {
+            text.strip_prefix('\n').unwrap_or(text)
+        } else {
+            text
+        };
+
+        self.html
+            .push_str(&escape_html(html_text).replace('\n', "<br>"));
+        self.after_block = false;
+        self
+    }
+    pub fn bold(&mut self, text: &str) -> &mut Self {
+        self.plain.push_str(text);
+        self.html
+            .push_str(&format!("<strong>{}</strong>", escape_html(text)));
+        self.after_block = false;
+        self
+    }
+    /// Adds a code block, e.g. for challenges and signatures, which can be
+    /// easily copied.
+    pub fn code_block(&mut self, code: &str) -> &mut Self {
+        // Same as for the line break after the block.
+        if self.html.ends_with("<br>") {
+            self.html.truncate(self.html.len() - "<br>".len());
+        }
+
+        self.plain.push_str(code);
+        self.html
+            .push_str(&format!("<pre><code>{}</code></pre>", escape_html(code)));
+        self.after_block = true;
+        self
+    }
+    pub fn build(&mut self) -> FormattedMessage {
+        FormattedMessage {
+            plain: self.plain.clone(),
+            html: Some(self.html.clone()),
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_formatted_message() {
+        let message = MessageBuilder::new()
+            .bold("Status:")
+            .text(" <pending>\n- Challenge:\n")
+            .code_block("abcd")
+            .text("\nDone")
+            .build();
+
+        assert_eq!(message.plain, "Status: <pending>\n- Challenge:\nabcd\nDone");
+        assert_eq!(
+            message.html.unwrap(),
+            "<strong>Status:</strong> &lt;pending&gt;<br>- Challenge:\
+            <pre><code>abcd</code></pre>Done"
+        );
+    }
+}
@@ -0,0 +1,404 @@
+use crate::primitives::{AccountType, Challenge, NetworkAddress, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Fallback language if a template does not exist in the configured language.
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+const CHALLENGES_BEGIN: &str = "{:CHALLENGES}";
+const CHALLENGES_END: &str = "{:END_CHALLENGES}";
+const ADDRESS: &str = "{:ADDRESS}";
+const PAYLOAD: &str = "{:PAYLOAD}";
+const CODE: &str = "{:CODE}";
+const LINK: &str = "{:LINK}";
+
+#[derive(Debug, Fail)]
+pub enum TemplateError {
+    #[fail(display = "failed to read template {}: {}", _0, _1)]
+    Read(String, failure::Error),
+    #[fail(display = "template {} has an unclosed challenges block", _0)]
+    UnclosedBlock(String),
+}
+
+/// The different kind of messages which can be customized.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum TemplateKind {
+    /// The initial message which contains the challenges.
+    Instructions,
+    /// The initial message which contains the one-time code, only used by
+    /// Email (see `EmailVerificationMode::Code`).
+    Code,
+}
+
+impl TemplateKind {
+    fn file_stem(&self) -> &'static str {
+        match self {
+            TemplateKind::Instructions => "instructions",
+            TemplateKind::Code => "code",
+        }
+    }
+}
+
+/// A rendered message. The `subject` and `html` parts are optional and only
+/// used by adapters which support them (Email).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RenderedMessage {
+    pub subject: Option<String>,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TemplateSet {
+    subject: Option<String>,
+    text: String,
+    html: Option<String>,
+}
+
+/// Templates loaded from disk, organized as:
+///
+/// ```text
+/// <dir>/<adapter>/<language>/<kind>.txt       (required)
+/// <dir>/<adapter>/<language>/<kind>.html      (optional)
+/// <dir>/<adapter>/<language>/<kind>.subject   (optional)
+/// ```
+///
+/// where `<adapter>` is one of `email`, `matrix` or `twitter` and `<kind>`
+/// one of `instructions` or `code` (Email only). If no template is available
+/// for a given adapter, the adapter falls back to its built-in messages.
+/// Matrix uses the `.html` template as the formatted body, Twitter only uses
+/// the `.txt` template.
+///
+/// In `instructions` templates, the `{:ADDRESS}` and `{:PAYLOAD}`
+/// placeholders are replaced with the address and the challenge
+/// respectively. Text wrapped in a `{:CHALLENGES}` ... `{:END_CHALLENGES}`
+/// block is repeated for each challenge. If the template has no such block,
+/// the entire template is repeated.
+///
+/// In `code` templates, the `{:CODE}` and `{:LINK}` placeholders are replaced
+/// with the one-time code and the verification link respectively. The link is
+/// empty if no `email_code_base_url` is configured.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    sets: HashMap<(AccountType, TemplateKind), TemplateSet>,
+}
+
+impl Templates {
+    /// Loads the templates of the given language, falling back to
+    /// `DEFAULT_LANGUAGE` for missing templates.
+    pub fn load<P: AsRef<Path>>(dir: P, language: &str) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut sets = HashMap::new();
+
+        for (account_ty, adapter, kinds) in &[
+            (
+                AccountType::Email,
+                "email",
+                &[TemplateKind::Instructions, TemplateKind::Code][..],
+            ),
+            (
+                AccountType::Matrix,
+                "matrix",
+                &[TemplateKind::Instructions][..],
+            ),
+            (
+                AccountType::Twitter,
+                "twitter",
+                &[TemplateKind::Instructions][..],
+            ),
+        ] {
+            for kind in kinds.iter() {
+                let mut set = None;
+                for lang in &[language, DEFAULT_LANGUAGE] {
+                    set = Self::load_set(&dir.join(adapter).join(lang), kind)?;
+                    if set.is_some() {
+                        break;
+                    }
+                }
+
+                if let Some(set) = set {
+                    debug!("Loaded {} template for {}", kind.file_stem(), adapter);
+                    sets.insert((account_ty.clone(), *kind), set);
+                }
+            }
+        }
+
+        Ok(Templates { sets: sets })
+    }
+    fn load_set(dir: &Path, kind: &TemplateKind) -> Result<Option<TemplateSet>> {
+        fn read(path: &Path) -> Result<Option<String>> {
+            match fs::read_to_string(path) {
+                Ok(content) => Ok(Some(content)),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(TemplateError::Read(path.display().to_string(), err.into()).into()),
+            }
+        }
+
+        let stem = kind.file_stem();
+
+        let text = if let Some(text) = read(&dir.join(format!("{}.txt", stem)))? {
+            text
+        } else {
+            return Ok(None);
+        };
+
+        let set = TemplateSet {
+            subject: read(&dir.join(format!("{}.subject", stem)))?
+                .map(|subject| subject.trim().to_string()),
+            text: text,
+            html: read(&dir.join(format!("{}.html", stem)))?,
+        };
+
+        // Detect malformed templates on startup rather than on usage.
+        for content in [Some(&set.text), set.html.as_ref()].iter().flatten() {
+            if content.contains(CHALLENGES_BEGIN) && !content.contains(CHALLENGES_END) {
+                return Err(
+                    TemplateError::UnclosedBlock(dir.join(stem).display().to_string()).into(),
+                );
+            }
+        }
+
+        Ok(Some(set))
+    }
+    /// Renders the `Instructions` template for the given adapter. Returns
+    /// `None` if no template was loaded.
+    pub fn render(
+        &self,
+        account_ty: &AccountType,
+        kind: TemplateKind,
+        challenges: &[(NetworkAddress, Challenge)],
+    ) -> Option<RenderedMessage> {
+        self.sets
+            .get(&(account_ty.clone(), kind))
+            .map(|set| RenderedMessage {
+                subject: set.subject.clone(),
+                text: render_content(&set.text, challenges),
+                html: set
+                    .html
+                    .as_ref()
+                    .map(|html| render_content(html, challenges)),
+            })
+    }
+    /// Renders the `Code` template for the given adapter. Returns `None` if no
+    /// template was loaded.
+    pub fn render_code(
+        &self,
+        account_ty: &AccountType,
+        code: &Challenge,
+        link: Option<&str>,
+    ) -> Option<RenderedMessage> {
+        let fill = |content: &str| {
+            content
+                .replace(CODE, code.as_str())
+                .replace(LINK, link.unwrap_or(""))
+        };
+
+        self.sets
+            .get(&(account_ty.clone(), TemplateKind::Code))
+            .map(|set| RenderedMessage {
+                subject: set.subject.clone(),
+                text: fill(&set.text),
+                html: set.html.as_ref().map(|html| fill(html)),
+            })
+    }
+}
+
+fn render_content(content: &str, challenges: &[(NetworkAddress, Challenge)]) -> String {
+    fn fill(block: &str, challenges: &[(NetworkAddress, Challenge)]) -> String {
+        challenges
+            .iter()
+            .map(|(network_address, challenge)| {
+                block
+                    .replace(ADDRESS, network_address.address().as_str())
+                    .replace(PAYLOAD, challenge.as_str())
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    if let Some(start) = content.find(CHALLENGES_BEGIN) {
+        if let Some(end) = content[start..].find(CHALLENGES_END).map(|end| start + end) {
+            let block = &content[start + CHALLENGES_BEGIN.len()..end];
+
+            return format!(
+                "{}{}{}",
+                &content[..start],
+                fill(block, challenges),
+                &content[end + CHALLENGES_END.len()..]
+            );
+        }
+    }
+
+    fill(content, challenges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::NetAccount;
+    use std::convert::TryFrom;
+
+    // Generate a random template directory.
+    fn template_dir() -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("templates_{}", Challenge::gen_random().as_str()));
+        fs::create_dir_all(dir.join("email").join("en")).unwrap();
+        fs::create_dir_all(dir.join("email").join("de")).unwrap();
+        fs::create_dir_all(dir.join("matrix").join("en")).unwrap();
+        dir
+    }
+
+    fn challenges() -> Vec<(NetworkAddress, Challenge)> {
+        vec![
+            (
+                NetworkAddress::try_from(NetAccount::alice()).unwrap(),
+                Challenge("aaaa".to_string()),
+            ),
+            (
+                NetworkAddress::try_from(NetAccount::bob()).unwrap(),
+                Challenge("bbbb".to_string()),
+            ),
+        ]
+    }
+
+    #[test]
+    fn render_templates() {
+        let dir = template_dir();
+
+        fs::write(
+            dir.join("email/en/instructions.txt"),
+            "Hello\n{:CHALLENGES}{:ADDRESS}: {:PAYLOAD}{:END_CHALLENGES}\nBye",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("email/en/instructions.html"),
+            "<p>{:ADDRESS}={:PAYLOAD}</p>",
+        )
+        .unwrap();
+        fs::write(dir.join("email/en/instructions.subject"), "Subject\n").unwrap();
+        fs::write(dir.join("email/de/instructions.txt"), "Hallo {:PAYLOAD}").unwrap();
+
+        let challenges = challenges();
+        let alice = NetAccount::alice();
+        let bob = NetAccount::bob();
+
+        let templates = Templates::load(&dir, "en").unwrap();
+        let res = templates
+            .render(&AccountType::Email, TemplateKind::Instructions, &challenges)
+            .unwrap();
+
+        assert_eq!(res.subject.unwrap(), "Subject");
+        assert_eq!(
+            res.text,
+            format!(
+                "Hello\n{}: aaaa\n{}: bbbb\nBye",
+                alice.as_str(),
+                bob.as_str()
+            )
+        );
+        assert_eq!(
+            res.html.unwrap(),
+            format!(
+                "<p>{}=aaaa</p>\n<p>{}=bbbb</p>",
+                alice.as_str(),
+                bob.as_str()
+            )
+        );
+
+        // No templates for Matrix.
+        let res = templates.render(
+            &AccountType::Matrix,
+            TemplateKind::Instructions,
+            &challenges,
+        );
+        assert!(res.is_none());
+
+        // Language specific template.
+        let templates = Templates::load(&dir, "de").unwrap();
+        let res = templates
+            .render(
+                &AccountType::Email,
+                TemplateKind::Instructions,
+                &challenges[..1],
+            )
+            .unwrap();
+
+        assert!(res.subject.is_none());
+        assert_eq!(res.text, "Hallo aaaa");
+        assert!(res.html.is_none());
+
+        // Fallback to the default language.
+        let templates = Templates::load(&dir, "fr").unwrap();
+        let res = templates
+            .render(
+                &AccountType::Email,
+                TemplateKind::Instructions,
+                &challenges[..1],
+            )
+            .unwrap();
+
+        assert_eq!(res.subject.unwrap(), "Subject");
+    }
+
+    #[test]
+    fn render_code_templates() {
+        let dir = template_dir();
+
+        fs::write(
+            dir.join("email/en/code.txt"),
+            "Code: {:CODE}\nLink: {:LINK}",
+        )
+        .unwrap();
+        // Code templates are only used by Email.
+        fs::write(dir.join("matrix/en/code.txt"), "Code: {:CODE}").unwrap();
+
+        let code = Challenge("aaaa".to_string());
+
+        let templates = Templates::load(&dir, "en").unwrap();
+        let res = templates
+            .render_code(
+                &AccountType::Email,
+                &code,
+                Some("https://example.com/verify/email/aaaa"),
+            )
+            .unwrap();
+
+        assert!(res.subject.is_none());
+        assert_eq!(
+            res.text,
+            "Code: aaaa\nLink: https://example.com/verify/email/aaaa"
+        );
+        assert!(res.html.is_none());
+
+        let res = templates
+            .render_code(&AccountType::Email, &code, None)
+            .unwrap();
+        assert_eq!(res.text, "Code: aaaa\nLink: ");
+
+        let res = templates.render_code(&AccountType::Matrix, &code, None);
+        assert!(res.is_none());
+
+        // The instructions template is not used for codes.
+        let res = templates.render(
+            &AccountType::Email,
+            TemplateKind::Instructions,
+            &challenges(),
+        );
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn unclosed_challenges_block() {
+        let dir = template_dir();
+
+        fs::write(
+            dir.join("email/en/instructions.txt"),
+            "{:CHALLENGES}{:ADDRESS}",
+        )
+        .unwrap();
+
+        assert!(Templates::load(&dir, "en").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use super::db_path;
+use super::mocks::*;
+use crate::adapters::MatrixHandler;
+use crate::comms::CommsVerifier;
+use crate::manager::{AccountStatus, InvalidReason, OnChainIdentity};
+use crate::primitives::{Account, AccountType, NetAccount};
+use crate::templates::Templates;
+use crate::Database2;
+use matrix_sdk::events::room::member::MemberEventContent;
+use matrix_sdk::events::SyncStateEvent;
+use matrix_sdk::identifiers::{RoomId, UserId};
+use matrix_sdk::locks::RwLock;
+use matrix_sdk::{EventEmitter, Room, SyncRoom};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+fn member_event(
+    user_id: &UserId,
+    prev_membership: Option<&str>,
+    membership: &str,
+) -> SyncStateEvent<MemberEventContent> {
+    let mut event = serde_json::json!({
+        "type": "m.room.member",
+        "event_id": "$event:matrix.org",
+        "sender": user_id.as_str(),
+        "state_key": user_id.as_str(),
+        "origin_server_ts": 1,
+        "content": {
+            "membership": membership,
+        },
+    });
+
+    if let Some(prev_membership) = prev_membership {
+        event["prev_content"] = serde_json::json!({
+            "membership": prev_membership,
+        });
+    }
+
+    serde_json::from_value(event).unwrap()
+}
+
+fn room() -> SyncRoom {
+    let room_id = RoomId::try_from("!alice:matrix.org").unwrap();
+    let my_user_id = UserId::try_from("@registrar:matrix.org").unwrap();
+
+    SyncRoom::Joined(Arc::new(RwLock::new(Room::new(&room_id, &my_user_id))))
+}
+
+async fn setup() -> (Database2, Arc<EventManager2>, MatrixHandler, UserId) {
+    let db = Database2::new(&db_path()).unwrap();
+    let manager = Arc::new(EventManager2::new());
+    let (_, matrix_child) = manager.child();
+
+    let my_user_id = UserId::try_from("@registrar:matrix.org").unwrap();
+    let alice = UserId::try_from("@alice:matrix.org").unwrap();
+    let room_id = RoomId::try_from("!alice:matrix.org").unwrap();
+
+    let mut ident = OnChainIdentity::new(NetAccount::alice()).unwrap();
+    ident
+        .push_account(AccountType::Matrix, Account::from(alice.as_str()))
+        .unwrap();
+
+    db.insert_identity(&ident).await.unwrap();
+    db.insert_room_id(&NetAccount::alice(), &room_id)
+        .await
+        .unwrap();
+
+    let handler = MatrixHandler::new(
+        db.clone(),
+        CommsVerifier::new(),
+        MatrixMocker::new(matrix_child, my_user_id.clone()),
+        Templates::default(),
+    );
+
+    (db, manager, handler, alice)
+}
+
+async fn status(db: &Database2) -> AccountStatus {
+    db.select_account_statuses(&NetAccount::alice())
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|(account_ty, _, _)| account_ty == &AccountType::Matrix)
+        .map(|(_, _, status)| status)
+        .unwrap()
+}
+
+#[test]
+fn matrix_invite_rejected() {
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (db, _, handler, alice) = setup().await;
+
+        handler
+            .on_room_member(room(), &member_event(&alice, Some("invite"), "leave"))
+            .await;
+
+        assert_eq!(
+            status(&db).await,
+            AccountStatus::Invalid(Some(InvalidReason::InviteRejected))
+        );
+
+        // The room is queued to be left.
+        let rooms = db.select_rooms_to_leave().await.unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert!(db
+            .select_room_id(&NetAccount::alice())
+            .await
+            .unwrap()
+            .is_none());
+    });
+}
+
+#[test]
+fn matrix_leave_and_rejoin() {
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (db, manager, handler, alice) = setup().await;
+        let room_id = RoomId::try_from("!alice:matrix.org").unwrap();
+
+        // Joining the room marks the account as valid.
+        handler
+            .on_room_member(room(), &member_event(&alice, Some("invite"), "join"))
+            .await;
+
+        assert_eq!(status(&db).await, AccountStatus::Valid);
+
+        // The verification is still pending, so the user is invited again.
+        handler
+            .on_room_member(room(), &member_event(&alice, Some("join"), "leave"))
+            .await;
+
+        let events = manager.events().await;
+        assert_eq!(
+            events.last().unwrap(),
+            &Event::Matrix(MatrixEvent::InviteUser {
+                room_id: room_id.clone(),
+                user_id: alice.clone(),
+            })
+        );
+
+        let rooms = db.select_rooms_to_leave().await.unwrap();
+        assert!(rooms.is_empty());
+
+        // Rejoining keeps the room.
+        handler
+            .on_room_member(room(), &member_event(&alice, Some("invite"), "join"))
+            .await;
+
+        assert_eq!(status(&db).await, AccountStatus::Valid);
+        assert_eq!(
+            db.select_room_id(&NetAccount::alice()).await.unwrap(),
+            Some(room_id)
+        );
+    });
+}
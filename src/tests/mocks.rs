@@ -1,14 +1,18 @@
 use crate::adapters::email;
-use crate::adapters::twitter::{self, TwitterError, TwitterId};
-use crate::adapters::{EmailTransport, EventExtract, MatrixTransport, TwitterTransport};
+use crate::adapters::twitter::{self, RateLimit, TwitterError, TwitterId};
+use crate::adapters::{
+    EmailTransport, EventExtract, MatrixTransport, TwitterTransport, UserLookup,
+};
 use crate::comms::CommsVerifier;
 use crate::connector::{
     ConnectorInitTransports, ConnectorReaderTransport, ConnectorWriterTransport, EventType, Message,
 };
-use crate::primitives::{unix_time, Result};
+use crate::message::FormattedMessage;
+use crate::primitives::{unix_time, Challenge, Result};
+use crate::templates::Templates;
 use crate::{Account, Database2};
 use matrix_sdk::api::r0::room::create_room::{Request, Response};
-use matrix_sdk::identifiers::{RoomId, UserId};
+use matrix_sdk::identifiers::{EventId, RoomId, UserId};
 use std::convert::TryFrom;
 use std::result::Result as StdResult;
 use std::sync::Arc;
@@ -27,6 +31,7 @@ pub enum MatrixEvent {
     SendMessage { room_id: RoomId, message: String },
     CreateRoom { to_invite: UserId },
     LeaveRoom { room_id: RoomId },
+    InviteUser { room_id: RoomId, user_id: UserId },
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -56,6 +61,18 @@ pub enum TwitterEvent {
         id: TwitterId,
         message: String,
     },
+    SendMention {
+        account: Account,
+        message: String,
+    },
+    RequestTweets {
+        twitter_id: TwitterId,
+        since_id: Option<u64>,
+    },
+    LookupUser {
+        account: Account,
+        user: twitter::TwitterUser,
+    },
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -191,7 +208,6 @@ impl ConnectorReaderTransport for ConnectorReaderMocker {
         } else {
             Ok(None)
         }
-
     }
 }
 
@@ -224,7 +240,7 @@ impl DummyTransport {
 
 #[async_trait]
 impl MatrixTransport for DummyTransport {
-    async fn send_message(&self, _room_id: &RoomId, _message: String) -> Result<()> {
+    async fn send_message(&self, _room_id: &RoomId, _message: FormattedMessage) -> Result<()> {
         unimplemented!()
     }
     async fn create_room<'a>(&'a self, _request: Request<'a>) -> Result<Response> {
@@ -233,18 +249,43 @@ impl MatrixTransport for DummyTransport {
     async fn leave_room(&self, _room_id: &RoomId) -> Result<()> {
         unimplemented!()
     }
+    async fn joined_members(&self, _room_id: &RoomId) -> Result<Vec<UserId>> {
+        unimplemented!()
+    }
+    async fn invite_user(&self, _room_id: &RoomId, _user_id: &UserId) -> Result<()> {
+        unimplemented!()
+    }
+    async fn direct_rooms(&self) -> Result<Vec<(Account, RoomId)>> {
+        unimplemented!()
+    }
+    async fn set_direct_rooms(&self, _rooms: &[(Account, RoomId)]) -> Result<()> {
+        unimplemented!()
+    }
+    async fn read_receipt(&self, _room_id: &RoomId, _event_id: &EventId) -> Result<()> {
+        unimplemented!()
+    }
+    async fn typing(&self, _room_id: &RoomId, _typing: bool) -> Result<()> {
+        unimplemented!()
+    }
+    async fn lookup_user(&self, _user_id: &UserId) -> Result<UserLookup> {
+        unimplemented!()
+    }
     async fn user_id(&self) -> Result<UserId> {
         unimplemented!()
     }
-    async fn run_emitter(&mut self, _db: Database2, _comms: CommsVerifier) {}
+    async fn run_emitter(&mut self, _db: Database2, _comms: CommsVerifier, _templates: Templates) {}
 }
 
 #[async_trait]
 impl EmailTransport for DummyTransport {
-    async fn request_messages(&self) -> Result<Vec<email::ReceivedMessageContext>> {
-        Ok(vec![])
+    async fn request_messages(&self) -> Result<(Vec<email::ReceivedMessageContext>, u32)> {
+        Ok((vec![], 0))
     }
-    async fn send_message(&self, _account: &Account, _msg: String) -> Result<()> {
+    async fn send_message(
+        &self,
+        _account: &Account,
+        _msg: email::OutgoingMessage,
+    ) -> Result<String> {
         unimplemented!()
     }
 }
@@ -255,8 +296,8 @@ impl TwitterTransport for DummyTransport {
         &self,
         _exclude: &TwitterId,
         _watermark: u64,
-    ) -> Result<(Vec<twitter::ReceivedMessageContext>, u64)> {
-        Ok((vec![], 0))
+    ) -> Result<(Vec<twitter::ReceivedMessageContext>, u64, Option<RateLimit>)> {
+        Ok((vec![], 0, None))
     }
     async fn lookup_twitter_id(
         &self,
@@ -265,9 +306,26 @@ impl TwitterTransport for DummyTransport {
     ) -> Result<Vec<(Account, TwitterId)>> {
         Ok(vec![(Account::from(""), TwitterId::from(0))])
     }
+    async fn lookup_user(&self, _account: &Account) -> Result<twitter::TwitterUser> {
+        unimplemented!()
+    }
     async fn send_message(&self, _id: &TwitterId, _message: String) -> StdResult<(), TwitterError> {
         unimplemented!()
     }
+    async fn send_mention(
+        &self,
+        _account: &Account,
+        _message: String,
+    ) -> StdResult<(), TwitterError> {
+        unimplemented!()
+    }
+    async fn request_tweets(
+        &self,
+        _twitter_id: &TwitterId,
+        _since_id: Option<u64>,
+    ) -> Result<Vec<twitter::Tweet>> {
+        unimplemented!()
+    }
     fn my_screen_name(&self) -> &Account {
         &self.screen_name
     }
@@ -275,11 +333,11 @@ impl TwitterTransport for DummyTransport {
 
 #[async_trait]
 impl MatrixTransport for MatrixMocker {
-    async fn send_message(&self, room_id: &RoomId, message: String) -> Result<()> {
+    async fn send_message(&self, room_id: &RoomId, message: FormattedMessage) -> Result<()> {
         self.child
             .push_event(Event::Matrix(MatrixEvent::SendMessage {
                 room_id: room_id.clone(),
-                message: message,
+                message: message.plain,
             }))
             .await;
 
@@ -305,14 +363,43 @@ impl MatrixTransport for MatrixMocker {
 
         Ok(())
     }
+    async fn joined_members(&self, _room_id: &RoomId) -> Result<Vec<UserId>> {
+        Ok(vec![self.user_id.clone()])
+    }
+    async fn invite_user(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+        self.child
+            .push_event(Event::Matrix(MatrixEvent::InviteUser {
+                room_id: room_id.clone(),
+                user_id: user_id.clone(),
+            }))
+            .await;
+
+        Ok(())
+    }
+    async fn direct_rooms(&self) -> Result<Vec<(Account, RoomId)>> {
+        Ok(vec![])
+    }
+    async fn set_direct_rooms(&self, _rooms: &[(Account, RoomId)]) -> Result<()> {
+        Ok(())
+    }
+    async fn read_receipt(&self, _room_id: &RoomId, _event_id: &EventId) -> Result<()> {
+        Ok(())
+    }
+    async fn typing(&self, _room_id: &RoomId, _typing: bool) -> Result<()> {
+        Ok(())
+    }
+    async fn lookup_user(&self, _user_id: &UserId) -> Result<UserLookup> {
+        Ok(UserLookup::Exists)
+    }
     async fn user_id(&self) -> Result<UserId> {
         Ok(self.user_id.clone())
     }
-    async fn run_emitter(&mut self, _db: Database2, _comms: CommsVerifier) {}
+    async fn run_emitter(&mut self, _db: Database2, _comms: CommsVerifier, _templates: Templates) {}
 }
 
 pub struct MatrixEventMock {
     user_id: UserId,
+    event_id: EventId,
     message: Result<String>,
 }
 
@@ -320,6 +407,9 @@ impl EventExtract for MatrixEventMock {
     fn sender(&self) -> &UserId {
         &self.user_id
     }
+    fn event_id(&self) -> &EventId {
+        &self.event_id
+    }
     fn message(&self) -> Result<String> {
         // Work around ownership violations.
         if let Ok(message) = &self.message {
@@ -342,7 +432,7 @@ impl EmailMocker {
 
 #[async_trait]
 impl EmailTransport for EmailMocker {
-    async fn request_messages(&self) -> Result<Vec<email::ReceivedMessageContext>> {
+    async fn request_messages(&self) -> Result<(Vec<email::ReceivedMessageContext>, u32)> {
         let messages = self.child.messages().await;
 
         self.child
@@ -351,17 +441,17 @@ impl EmailTransport for EmailMocker {
             }))
             .await;
 
-        Ok(messages)
+        Ok((messages, 0))
     }
-    async fn send_message(&self, account: &Account, msg: String) -> Result<()> {
+    async fn send_message(&self, account: &Account, msg: email::OutgoingMessage) -> Result<String> {
         self.child
             .push_event(Event::Email(EmailEvent::SendMessage {
                 account: account.clone(),
-                message: msg,
+                message: msg.text,
             }))
             .await;
 
-        Ok(())
+        Ok(format!("{}@localhost", Challenge::gen_random().as_str()))
     }
 }
 
@@ -369,6 +459,8 @@ pub struct TwitterMocker {
     child: EventChild<twitter::ReceivedMessageContext>,
     index_book: Vec<(Account, TwitterId)>,
     screen_name: Account,
+    closed_dms: Vec<TwitterId>,
+    deleted_users: Vec<TwitterId>,
 }
 
 impl TwitterMocker {
@@ -381,8 +473,18 @@ impl TwitterMocker {
             child: child,
             index_book: index_book,
             screen_name: screen_name,
+            closed_dms: vec![],
+            deleted_users: vec![],
         }
     }
+    /// Direct messages to the user are rejected.
+    pub fn close_direct_messages(&mut self, twitter_id: TwitterId) {
+        self.closed_dms.push(twitter_id);
+    }
+    /// The user no longer exists.
+    pub fn delete_user(&mut self, twitter_id: TwitterId) {
+        self.deleted_users.push(twitter_id);
+    }
 }
 
 #[async_trait]
@@ -391,7 +493,7 @@ impl TwitterTransport for TwitterMocker {
         &self,
         exclude: &TwitterId,
         watermark: u64,
-    ) -> Result<(Vec<twitter::ReceivedMessageContext>, u64)> {
+    ) -> Result<(Vec<twitter::ReceivedMessageContext>, u64, Option<RateLimit>)> {
         let messages = self.child.messages().await;
 
         let mut new_watermark = 0;
@@ -414,7 +516,7 @@ impl TwitterTransport for TwitterMocker {
             }))
             .await;
 
-        Ok((messages, new_watermark))
+        Ok((messages, new_watermark, None))
     }
     async fn lookup_twitter_id(
         &self,
@@ -425,25 +527,17 @@ impl TwitterTransport for TwitterMocker {
 
         if let Some(twitter_ids) = twitter_ids {
             for twitter_id in twitter_ids {
-                let pair = self
-                    .index_book
-                    .iter()
-                    .find(|(_, id)| id == *twitter_id)
-                    .unwrap();
-
-                lookups.push(pair.clone());
+                if let Some(pair) = self.index_book.iter().find(|(_, id)| id == *twitter_id) {
+                    lookups.push(pair.clone());
+                }
             }
         }
 
         if let Some(accounts) = accounts {
             for account in accounts {
-                let pair = self
-                    .index_book
-                    .iter()
-                    .find(|(acc, _)| acc == *account)
-                    .unwrap();
-
-                lookups.push(pair.clone());
+                if let Some(pair) = self.index_book.iter().find(|(acc, _)| acc == *account) {
+                    lookups.push(pair.clone());
+                }
             }
         }
 
@@ -456,9 +550,34 @@ impl TwitterTransport for TwitterMocker {
             }))
             .await;
 
+        if lookups.is_empty() {
+            return Err(TwitterError::UserNotFound.into());
+        }
+
         Ok(lookups)
     }
+    async fn lookup_user(&self, account: &Account) -> Result<twitter::TwitterUser> {
+        let user = self
+            .index_book
+            .iter()
+            .find(|(acc, _)| acc == account)
+            .map(|(_, id)| twitter::TwitterUser::Active(id.clone()))
+            .unwrap_or(twitter::TwitterUser::NotFound);
+
+        self.child
+            .push_event(Event::Twitter(TwitterEvent::LookupUser {
+                account: account.clone(),
+                user: user.clone(),
+            }))
+            .await;
+
+        Ok(user)
+    }
     async fn send_message(&self, id: &TwitterId, message: String) -> StdResult<(), TwitterError> {
+        if self.closed_dms.contains(id) {
+            return Err(TwitterError::DirectMessagesClosed);
+        }
+
         self.child
             .push_event(Event::Twitter(TwitterEvent::SendMessage {
                 id: id.clone(),
@@ -468,6 +587,45 @@ impl TwitterTransport for TwitterMocker {
 
         Ok(())
     }
+    async fn send_mention(
+        &self,
+        account: &Account,
+        message: String,
+    ) -> StdResult<(), TwitterError> {
+        // Tweets are limited to 280 characters, including the mention.
+        if account.as_str().chars().count() + 1 + message.chars().count() > 280 {
+            return Err(TwitterError::Api(String::from(
+                "Tweet needs to be a bit shorter",
+            )));
+        }
+
+        self.child
+            .push_event(Event::Twitter(TwitterEvent::SendMention {
+                account: account.clone(),
+                message: message,
+            }))
+            .await;
+
+        Ok(())
+    }
+    async fn request_tweets(
+        &self,
+        twitter_id: &TwitterId,
+        since_id: Option<u64>,
+    ) -> Result<Vec<twitter::Tweet>> {
+        self.child
+            .push_event(Event::Twitter(TwitterEvent::RequestTweets {
+                twitter_id: twitter_id.clone(),
+                since_id: since_id,
+            }))
+            .await;
+
+        if self.deleted_users.contains(twitter_id) {
+            return Err(TwitterError::UserNotFound.into());
+        }
+
+        Ok(vec![])
+    }
     fn my_screen_name(&self) -> &Account {
         &self.screen_name
     }
@@ -502,11 +660,11 @@ mod tests {
             mocker.create_room(request).await.unwrap();
 
             mocker
-                .send_message(&room_id, String::from("First message out"))
+                .send_message(&room_id, String::from("First message out").into())
                 .await
                 .unwrap();
             mocker
-                .send_message(&room_id, String::from("Second message out"))
+                .send_message(&room_id, String::from("Second message out").into())
                 .await
                 .unwrap();
 
@@ -561,30 +719,40 @@ mod tests {
                 id: EmailId::from(11u64),
                 sender: alice.clone(),
                 body: String::from("from alice one"),
+                message_id: None,
+                references: None,
+                subject: None,
+                failed_recipients: vec![],
+                bounced_message_ids: vec![],
             };
 
             let bob_message = email::ReceivedMessageContext {
                 id: EmailId::from(33u64),
                 sender: bob.clone(),
                 body: String::from("from bob one"),
+                message_id: None,
+                references: None,
+                subject: None,
+                failed_recipients: vec![],
+                bounced_message_ids: vec![],
             };
 
             // Init mocker and create events.
             let mocker = EmailMocker::new(email_child);
 
-            let res = mocker.request_messages().await.unwrap();
+            let (res, _) = mocker.request_messages().await.unwrap();
             assert_eq!(res, vec![]);
 
             mocker
-                .send_message(&alice, String::from("alice one"))
+                .send_message(&alice, String::from("alice one").into())
                 .await
                 .unwrap();
             mocker
-                .send_message(&alice, String::from("alice two"))
+                .send_message(&alice, String::from("alice two").into())
                 .await
                 .unwrap();
             mocker
-                .send_message(&bob, String::from("bob one"))
+                .send_message(&bob, String::from("bob one").into())
                 .await
                 .unwrap();
 
@@ -592,7 +760,7 @@ mod tests {
             sender.send_message(alice_message.clone()).await;
             sender.send_message(bob_message.clone()).await;
 
-            let res = mocker.request_messages().await.unwrap();
+            let (res, _) = mocker.request_messages().await.unwrap();
             assert_eq!(res.len(), 2);
             assert!(res.contains(&alice_message));
             assert!(res.contains(&bob_message));
@@ -660,24 +828,28 @@ mod tests {
             ];
 
             let alice_message1 = twitter::ReceivedMessageContext {
+                id: 1,
                 sender: alice_id.clone(),
                 message: String::from("from alice one"),
                 created: 22,
             };
 
             let alice_message2 = twitter::ReceivedMessageContext {
+                id: 2,
                 sender: alice_id.clone(),
                 message: String::from("from alice one"),
                 created: 33,
             };
 
             let my_message = twitter::ReceivedMessageContext {
+                id: 3,
                 sender: my_id.clone(),
                 message: String::from("from me one"),
                 created: 44,
             };
 
             let bob_message = twitter::ReceivedMessageContext {
+                id: 4,
                 sender: bob_id.clone(),
                 message: String::from("from bob one"),
                 created: 55,
@@ -722,7 +894,7 @@ mod tests {
             sender.send_message(my_message.clone()).await;
             sender.send_message(bob_message.clone()).await;
 
-            let (res, watermark) = mocker.request_messages(&my_id, 30).await.unwrap();
+            let (res, watermark, _) = mocker.request_messages(&my_id, 30).await.unwrap();
             assert_eq!(res.len(), 2);
             assert_eq!(watermark, 55);
             assert!(res.contains(&alice_message2));
@@ -817,8 +989,12 @@ mod tests {
                 .await
                 .unwrap();
 
-            injector.send_message(String::from("First message in")).await;
-            injector.send_message(String::from("Second message in")).await;
+            injector
+                .send_message(String::from("First message in"))
+                .await;
+            injector
+                .send_message(String::from("Second message in"))
+                .await;
 
             let res = reader.read().await.unwrap().unwrap();
             assert_eq!(res, String::from("First message in"));
@@ -1,6 +1,7 @@
 use crate::primitives::Challenge;
 use tokio::time::{self, Duration};
 
+mod matrix_membership;
 pub mod mocks;
 mod verify_matrix;
 
@@ -1,5 +1,7 @@
 use crate::adapters::VIOLATIONS_CAP;
 use crate::comms::CommsVerifier;
+use crate::manager::InvalidReason;
+use crate::message::{FormattedMessage, MessageBuilder};
 use crate::primitives::{
     Account, AccountType, Challenge, ChallengeStatus, NetworkAddress, Result, Signature,
 };
@@ -62,13 +64,13 @@ impl<'a> Verifier2<'a> {
             .map(|(account_address, _)| *account_address)
             .collect()
     }
-    pub fn init_message_builder(&self, send_context: bool) -> String {
-        let mut message = String::new();
+    pub fn init_message_builder(&self, send_context: bool) -> FormattedMessage {
+        let mut message = MessageBuilder::new();
 
         if send_context {
-            message.push_str(
-                "\
-                [!!] NEVER EXPOSE YOUR PRIVATE KEYS TO ANYONE [!!]\n\n\
+            message.bold("[!!] NEVER EXPOSE YOUR PRIVATE KEYS TO ANYONE [!!]");
+            message.text(
+                "\n\n\
                 This contact address was discovered in the Polkadot on-chain naming system and \
                 the issuer has requested the Web3 Registrar service to judge this account.\
                 If you did not issue this request then just ignore this message.\n\n\
@@ -77,53 +79,65 @@ impl<'a> Verifier2<'a> {
         }
 
         if self.challenges.len() > 1 {
-            message.push_str("Please sign each challenge with the corresponding address:\n");
+            message.text("Please sign each challenge with the corresponding address:\n");
         } else {
-            message.push_str("Please sign the challenge with the corresponding address:\n");
+            message.text("Please sign the challenge with the corresponding address:\n");
         }
 
         for (network_address, challenge) in self.challenges {
-            message.push_str("\n- Address:\n");
-            message.push_str(network_address.address().as_str());
-            message.push_str("\n- Challenge:\n");
-            message.push_str(challenge.as_str());
+            message
+                .text("\n- Address:\n")
+                .code_block(network_address.address().as_str())
+                .text("\n- Challenge:\n")
+                .code_block(challenge.as_str());
         }
 
-        message.push_str("\n\nRefer to the Polkadot Wiki guide https://wiki.polkadot.network/");
+        message.text("\n\nRefer to the Polkadot Wiki guide https://wiki.polkadot.network/");
 
-        message
+        message.build()
     }
-    pub fn response_message_builder(&self) -> String {
-        let mut message = String::new();
+    pub fn response_message_builder(&self) -> FormattedMessage {
+        let mut message = MessageBuilder::new();
 
         if self.valid.is_empty() {
-            message.push_str("The signature is invalid. Refer to the Polkadot Wiki guide.");
-            return message;
+            message
+                .bold("The signature is invalid.")
+                .text(" Refer to the Polkadot Wiki guide.");
+            return message.build();
         } else if self.valid.len() == 1 {
-            message.push_str("The following address has been verified:\n")
+            message
+                .bold("The following address has been verified:")
+                .text("\n");
         } else {
-            message.push_str("The following addresses have been verified:\n")
+            message
+                .bold("The following addresses have been verified:")
+                .text("\n");
         }
 
         for (network_address, challenge) in &self.valid {
-            message.push_str("\n- Address:\n");
-            message.push_str(network_address.address().as_str());
-            message.push_str("\n- Challenge:\n");
-            message.push_str(challenge.as_str());
+            message
+                .text("\n- Address:\n")
+                .code_block(network_address.address().as_str())
+                .text("\n- Challenge:\n")
+                .code_block(challenge.as_str());
         }
 
         if !self.invalid.is_empty() {
-            message.push_str("\n\nPending/Unconfirmed address(-es) for this account:\n");
+            message
+                .text("\n\n")
+                .bold("Pending/Unconfirmed address(-es) for this account:")
+                .text("\n");
 
             for (network_address, challenge) in &self.invalid {
-                message.push_str("\n- Address:\n");
-                message.push_str(network_address.address().as_str());
-                message.push_str("\n- Challenge:\n");
-                message.push_str(challenge.as_str());
+                message
+                    .text("\n- Address:\n")
+                    .code_block(network_address.address().as_str())
+                    .text("\n- Challenge:\n")
+                    .code_block(challenge.as_str());
             }
         }
 
-        message
+        message.build()
     }
 }
 
@@ -169,17 +183,19 @@ pub async fn verification_handler<'a>(
 }
 
 pub fn invalid_accounts_message(
-    accounts: &[(AccountType, Account)],
+    accounts: &[(AccountType, Account, Option<InvalidReason>)],
     violations: Option<Vec<Account>>,
-) -> String {
-    let mut message = String::new();
+) -> FormattedMessage {
+    let mut message = MessageBuilder::new();
 
-    message.push_str("Please note that the following information is invalid:\n\n");
+    message
+        .bold("Please note that the following information is invalid:")
+        .text("\n\n");
 
-    for (account_ty, account) in accounts {
+    for (account_ty, account, reason) in accounts {
         if account_ty == &AccountType::DisplayName {
             if let Some(violations) = violations.as_ref() {
-                message.push_str(&format!(
+                message.text(&format!(
                     "* \"{}\" (Display Name) is too similar to {}existing display {}:\n",
                     account.as_str(),
                     {
@@ -199,28 +215,77 @@ pub fn invalid_accounts_message(
                 ));
 
                 for violation in violations {
-                    message.push_str(&format!("  * \"{}\"\n", violation.as_str()));
+                    message.text(&format!("  * \"{}\"\n", violation.as_str()));
                 }
 
                 if violations.len() == VIOLATIONS_CAP {
-                    message.push_str("  * etc.\n");
+                    message.text("  * etc.\n");
                 }
 
                 continue;
             }
         }
 
-        message.push_str(&format!(
+        if let Some(reason) = reason {
+            message.text(&format!(
+                "* \"{}\" ({}) {}\n",
+                account.as_str(),
+                account_ty.to_string(),
+                invalid_reason_explanation(account_ty, reason)
+            ));
+
+            continue;
+        }
+
+        message.text(&format!(
             "* \"{}\" ({}), could not be reached\n",
             account.as_str(),
             account_ty.to_string()
         ));
     }
 
-    message.push_str(
+    message.text(
         "\nPlease update the on-chain identity data. No new \
         `requestJudgement` extrinsic must be issued after the update.",
     );
 
-    message
+    message.build()
+}
+
+/// Explains to the user why the account is invalid and what needs to be
+/// fixed.
+pub(crate) fn invalid_reason_explanation(
+    account_ty: &AccountType,
+    reason: &InvalidReason,
+) -> &'static str {
+    use InvalidReason::*;
+
+    match (account_ty, reason) {
+        (AccountType::Matrix, InvalidFormat) => {
+            "is not a valid Matrix ID, expected a format like \"@alice:matrix.org\""
+        }
+        (AccountType::Twitter, InvalidFormat) => {
+            "is not a valid Twitter handle, expected a format like \"@alice\""
+        }
+        (_, InvalidFormat) => "has an invalid format",
+        (_, NotFound) => "does not exist, please check the spelling",
+        (_, Suspended) => "is suspended, please specify a different account",
+        (_, DirectMessagesClosed) => {
+            "does not accept direct messages, please allow direct messages from everyone \
+            in the privacy settings"
+        }
+        (_, InviteRejected) => {
+            "rejected the invite to the verification room, please accept the invite after \
+            updating the identity"
+        }
+        (_, MailBounced) => {
+            "could not receive emails, please check the address and whether the mailbox \
+            accepts emails"
+        }
+        (_, HomeserverUnreachable) => {
+            "could not be reached, the homeserver of the Matrix ID is offline or does not \
+            exist"
+        }
+        (_, DisplayNameViolation) => "is too similar to an existing display name",
+    }
 }